@@ -1,7 +1,6 @@
 #![feature(test)]
 
 extern crate test;
-use core::mem::MaybeUninit;
 use test::Bencher;
 
 use imagequant::*;
@@ -22,7 +21,7 @@ fn histogram(b: &mut Bencher) {
 #[bench]
 fn remap_ord(b: &mut Bencher) {
     let img = lodepng::decode32_file("/Users/kornel/Desktop/canvas.png").unwrap();
-    let mut buf = vec![MaybeUninit::uninit(); img.width * img.height];
+    let mut buf = vec![0u8; img.width * img.height];
     let mut liq = Attributes::new();
     liq.set_speed(10).unwrap();
     let mut img = liq
@@ -45,7 +44,7 @@ fn kmeans(b: &mut Bencher) {
 #[bench]
 fn remap_floyd(b: &mut Bencher) {
     let img = lodepng::decode32_file("/Users/kornel/Desktop/canvas.png").unwrap();
-    let mut buf = vec![MaybeUninit::uninit(); img.width * img.height];
+    let mut buf = vec![0u8; img.width * img.height];
     let mut liq = Attributes::new();
     liq.set_speed(10).unwrap();
     let mut img = liq
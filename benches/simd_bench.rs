@@ -167,12 +167,166 @@ fn bench_blur_simd_vs_scalar(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_palette_algorithm(c: &mut Criterion) {
+    let mut group = c.benchmark_group("palette_algorithm");
+
+    let width = 512;
+    let height = 512;
+    // needs many distinct colors, otherwise the palette is taken from the histogram directly
+    let pixels: Vec<_> = (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            RGBA::new((x / 2) as u8, (y / 2) as u8, ((x ^ y) / 2) as u8, 255)
+        })
+        .collect();
+
+    for (name, algorithm) in [
+        ("mediancut", PaletteAlgorithm::MedianCut),
+        ("wu", PaletteAlgorithm::Wu),
+        ("octree", PaletteAlgorithm::Octree),
+    ] {
+        for colors in [16, 64, 256] {
+            group.bench_with_input(BenchmarkId::new(name, colors), &colors, |b, &colors| {
+                b.iter(|| {
+                    let mut attr = Attributes::new();
+                    attr.set_max_colors(colors).unwrap();
+                    attr.set_palette_algorithm(algorithm);
+                    let mut img = attr
+                        .new_image(black_box(&pixels[..]), width, height, 0.0)
+                        .unwrap();
+                    attr.quantize(black_box(&mut img)).unwrap()
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_octree(c: &mut Criterion) {
+    let mut group = c.benchmark_group("octree_1080p");
+    group.sample_size(20);
+
+    let width = 1920;
+    let height = 1080;
+    let pixels: Vec<_> = (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            RGBA::new((x / 8) as u8, (y / 5) as u8, ((x ^ y) / 8) as u8, 255)
+        })
+        .collect();
+
+    let mut attr = Attributes::new();
+    attr.set_palette_algorithm(PaletteAlgorithm::Octree);
+    group.throughput(Throughput::Elements((width * height) as u64));
+
+    // borrowed, so that copying the pixels isn't measured
+    group.bench_function("quantize", |b| {
+        b.iter(|| {
+            let mut img = attr
+                .new_image_borrowed(black_box(&pixels), width, height, 0.0)
+                .unwrap();
+            attr.quantize(black_box(&mut img)).unwrap()
+        })
+    });
+
+    let mut img = attr
+        .new_image_borrowed(&pixels, width, height, 0.0)
+        .unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    group.bench_function("remap", |b| {
+        b.iter(|| res.remapped(black_box(&mut img)).unwrap())
+    });
+
+    group.finish();
+}
+
+fn bench_importance_map(c: &mut Criterion) {
+    let mut group = c.benchmark_group("importance_map");
+
+    let width = 512;
+    let height = 512;
+    let pixels = generate_test_image(width, height);
+    group.throughput(Throughput::Elements((width * height) as u64));
+
+    for (name, saliency) in [("contrast_maps", false), ("saliency", true)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut attr = Attributes::new();
+                attr.set_saliency(saliency);
+                let mut img = attr
+                    .new_image(black_box(&pixels[..]), width, height, 0.0)
+                    .unwrap();
+                let mut hist = Histogram::new(&attr);
+                hist.add_image(&attr, black_box(&mut img)).unwrap();
+                hist
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_palette_order(c: &mut Criterion) {
+    let mut group = c.benchmark_group("palette_order");
+
+    let width = 512;
+    let height = 512;
+    let pixels: Vec<_> = (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            RGBA::new((x / 2) as u8, (y / 2) as u8, ((x ^ y) / 2) as u8, 255)
+        })
+        .collect();
+    let mut attr = Attributes::new();
+    attr.set_speed(8).unwrap();
+    let mut img = attr.new_image(&pixels[..], width, height, 0.0).unwrap();
+    let res = attr.quantize(&mut img).unwrap();
+    let (_, indices) = res.clone().remapped(&mut img).unwrap();
+
+    for (name, order) in [
+        ("popularity", PaletteOrder::Popularity),
+        ("luminance", PaletteOrder::Luminance),
+        ("hue", PaletteOrder::Hue),
+        ("nearest_neighbor", PaletteOrder::NearestNeighbor),
+        ("index_entropy", PaletteOrder::IndexEntropy),
+    ] {
+        // criterion measures only time, so the size of the compressed indices is printed.
+        // `palette_order_compressed_size` in `src/lib.rs` asserts the sizes.
+        let mut reordered = indices.clone();
+        res.clone()
+            .reorder_palette(order, &indices)
+            .unwrap()
+            .apply(&mut reordered);
+        let png =
+            lodepng::encode_memory(&reordered, width, height, lodepng::ColorType::GREY, 8).unwrap();
+        eprintln!(
+            "palette_order/{name}: {} bytes of PNG-compressed indices",
+            png.len()
+        );
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                res.clone()
+                    .reorder_palette(order, black_box(&indices))
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_quantize,
     bench_quantize_speed_levels,
     bench_remap,
     bench_histogram,
-    bench_blur_simd_vs_scalar
+    bench_blur_simd_vs_scalar,
+    bench_palette_algorithm,
+    bench_octree,
+    bench_importance_map,
+    bench_palette_order
 );
 criterion_main!(benches);
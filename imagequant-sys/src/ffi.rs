@@ -8,6 +8,7 @@
 #![allow(clippy::items_after_statements)]
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cast_possible_wrap)]
+#![allow(clippy::type_complexity)]
 
 #[cfg(all(not(feature = "std"), feature = "no_std"))]
 extern crate alloc as std;
@@ -106,7 +107,7 @@ macro_rules! bad_object {
         let bork = if cfg!(miri) {
             false
         } else {
-            unsafe { liq_received_invalid_pointer((obj as *const _ as *const u8)) }
+            unsafe { liq_received_invalid_pointer(obj as *const _ as *const u8) }
         };
         (bork || (($obj).magic_header != $tag))
     }};
@@ -0,0 +1,207 @@
+//! Classification of images by their kind of content, for choosing settings automatically.
+//!
+//! It's based on statistics that the library computes anyway: number of colors, the maps of noise and edges, and use of alpha.
+use crate::attr::{Attributes, ContentMode};
+use crate::error::Error;
+use crate::hist::{U32Hasher, DOMINANT_COLOR_DIVISOR};
+use crate::image::{Image, TEXT_EDGE_THRESHOLD};
+use crate::pal::{unpremultiply, RGBA};
+use crate::rows::temp_buf;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use hashbrown::HashMap;
+
+#[cfg(not(all(not(feature = "std"), feature = "no_std")))]
+use std::collections::HashMap;
+
+/// Colors beyond this many aren't tracked, since such images are photo-like anyway
+const MAX_TRACKED_COLORS: usize = 1 << 16;
+/// Longer side of images that are small enough to be icons
+const MAX_ICON_SIZE: usize = 256;
+/// Importance map values of areas without noise
+const FLAT_IMPORTANCE: u8 = 240;
+
+/// Kind of image content, detected by [`Attributes::auto_tune`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ContentProfile {
+    /// Natural images with noise and smooth gradients
+    Photo,
+    /// Drawings and renders with mostly flat or smooth areas
+    Illustration,
+    /// Screenshots, UI and text, dominated by a few flat colors with sharp edges
+    Screenshot,
+    /// Small images with transparency, like icons and sprites
+    Icon,
+}
+
+/// Statistics of an image, and settings chosen for it by [`Attributes::auto_tune`]
+///
+/// Fractions are of the number of pixels in the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ImageAnalysis {
+    /// Detected kind of the image
+    pub profile: ContentProfile,
+    /// Chosen for [`Attributes::set_speed`]
+    pub speed: u8,
+    /// Chosen for [`Attributes::set_min_posterization`]
+    pub min_posterization: u8,
+    /// Chosen for [`Attributes::set_content_mode`]
+    pub content_mode: ContentMode,
+    /// Recommended for [`QuantizationResult::set_dithering_level`](crate::QuantizationResult::set_dithering_level).
+    /// It's not a setting of [`Attributes`], so it has to be applied after quantization.
+    pub dithering_level: f32,
+    /// Number of distinct RGBA colors, counted up to 65536
+    pub unique_colors: usize,
+    /// Pixels covered by colors that each cover at least 2% of the image
+    pub dominant_colors: f32,
+    /// Pixels in areas without noise
+    pub flat: f32,
+    /// Pixels on sharp edges between flat areas
+    pub sharp_edges: f32,
+    /// Fully transparent pixels
+    pub transparent: f32,
+    /// Semi-transparent pixels
+    pub translucent: f32,
+}
+
+impl ImageAnalysis {
+    pub(crate) fn new(image: &mut Image<'_>) -> Result<Self, Error> {
+        let width = image.width();
+        let height = image.height();
+        let pixels = (width * height) as f32;
+
+        let mut colors = HashMap::<u32, u32, _>::with_hasher(U32Hasher(0));
+        let mut transparent = 0usize;
+        let mut translucent = 0usize;
+        // bits set in any channel, to see if the image has been posterized already
+        let mut used_bits = 0u8;
+        {
+            let premultiplied = image.px.premultiplied;
+            let rows = image.px.rgba_rows_iter()?;
+            let mut temp_row = temp_buf(width)?;
+            for row in 0..height {
+                for &px in rows.row_rgba(&mut temp_row, row) {
+                    let px = if premultiplied { unpremultiply(px) } else { px };
+                    let px = match px.a {
+                        0 => {
+                            transparent += 1;
+                            // all transparent colors look the same
+                            RGBA::new(0, 0, 0, 0)
+                        }
+                        255 => px,
+                        _ => {
+                            translucent += 1;
+                            px
+                        }
+                    };
+                    used_bits |= px.r | px.g | px.b;
+                    let key = u32::from_ne_bytes([px.r, px.g, px.b, px.a]);
+                    if let Some(count) = colors.get_mut(&key) {
+                        *count += 1;
+                    } else if colors.len() < MAX_TRACKED_COLORS {
+                        colors.insert(key, 1);
+                    }
+                }
+            }
+        }
+        let total = (width * height) as u64;
+        let dominant: u64 = colors
+            .values()
+            .map(|&c| u64::from(c))
+            .filter(|&c| c * DOMINANT_COLOR_DIVISOR >= total)
+            .sum();
+
+        let (flat, sharp_edges) = contrast_stats(image)?;
+
+        let mut analysis = Self {
+            profile: ContentProfile::Photo,
+            speed: 4,
+            min_posterization: 0,
+            content_mode: ContentMode::Photo,
+            dithering_level: 1.,
+            unique_colors: colors.len(),
+            dominant_colors: dominant as f32 / pixels,
+            flat: flat as f32 / pixels,
+            sharp_edges: sharp_edges as f32 / pixels,
+            transparent: transparent as f32 / pixels,
+            translucent: translucent as f32 / pixels,
+        };
+        analysis.profile = analysis.classify(width.max(height));
+        analysis.choose_settings();
+        // low bits that are always 0 mean the source had fewer bits per channel
+        if !image.px.is_high_depth() && analysis.unique_colors > 16 && used_bits != 0 {
+            analysis.min_posterization = (used_bits.trailing_zeros() as u8).min(4);
+        }
+        Ok(analysis)
+    }
+
+    fn classify(&self, longer_side: usize) -> ContentProfile {
+        if longer_side <= MAX_ICON_SIZE && self.transparent + self.translucent > 0.05 {
+            ContentProfile::Icon
+        } else if self.dominant_colors >= 0.5 && self.sharp_edges > 0. {
+            ContentProfile::Screenshot
+        } else if self.flat >= 0.6 {
+            ContentProfile::Illustration
+        } else {
+            ContentProfile::Photo
+        }
+    }
+
+    fn choose_settings(&mut self) {
+        (self.speed, self.content_mode, self.dithering_level) = match self.profile {
+            ContentProfile::Photo => (4, ContentMode::Photo, 1.),
+            // dithering is visible in flat areas, and slower speeds avoid banding of gradients
+            ContentProfile::Illustration => (3, ContentMode::Photo, 0.5),
+            ContentProfile::Screenshot => (4, ContentMode::Text, 0.5),
+            // they're small, so the slowest speed is still fast
+            ContentProfile::Icon => (1, ContentMode::Photo, 1.),
+        };
+    }
+
+    /// Applies the chosen settings, except the dithering level
+    pub(crate) fn apply(&self, attr: &mut Attributes) -> Result<(), Error> {
+        attr.set_speed(self.speed.into())?;
+        attr.set_min_posterization(self.min_posterization)?;
+        attr.set_content_mode(self.content_mode);
+        attr.verbose_print(format!(
+            "  auto-tuned for {:?}: speed {}, posterization {}, dithering {}",
+            self.profile, self.speed, self.min_posterization, self.dithering_level
+        ));
+        Ok(())
+    }
+}
+
+/// Counts flat pixels and pixels on sharp edges. The image's own maps are left as they were.
+fn contrast_stats(image: &mut Image<'_>) -> Result<(usize, usize), Error> {
+    let importance_map = image.importance_map.take();
+    let edges = image.edges.take();
+    let result = image.contrast_maps().map(|()| {
+        match (image.importance_map.as_deref(), image.edges.as_deref()) {
+            (Some(noise), Some(edges)) => {
+                noise
+                    .iter()
+                    .zip(edges)
+                    .fold((0, 0), |(flat, sharp), (&noise, &edge)| {
+                        // noise is eroded, so thin edges between flat areas don't count as noise
+                        (
+                            flat + usize::from(noise >= FLAT_IMPORTANCE),
+                            sharp
+                                + usize::from(
+                                    noise >= FLAT_IMPORTANCE && edge < TEXT_EDGE_THRESHOLD,
+                                ),
+                        )
+                    })
+            }
+            // too small or too large for the maps
+            _ => (0, 0),
+        }
+    });
+    image.importance_map = importance_map;
+    image.edges = edges;
+    result
+}
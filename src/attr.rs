@@ -1,7 +1,8 @@
+use crate::analysis::ImageAnalysis;
 use crate::error::Error;
 use crate::hist::Histogram;
 use crate::image::Image;
-use crate::pal::{PalLen, MAX_COLORS, RGBA};
+use crate::pal::{ChannelLevels, PalLen, MAX_COLORS, RGBA};
 use crate::quant::{mse_to_quality, quality_to_mse, QuantizationResult};
 use crate::remap::DitherMapMode;
 use std::sync::Arc;
@@ -27,8 +28,19 @@ pub struct Attributes {
     pub(crate) max_histogram_entries: u32,
     min_posterization_output: u8,
     min_posterization_input: u8,
+    pub(crate) channel_levels: Option<ChannelLevels>,
+    pub(crate) candidate_colors: Vec<RGBA>,
     pub(crate) last_index_transparent: bool,
     pub(crate) use_contrast_maps: bool,
+    pub(crate) grayscale: bool,
+    pub(crate) saliency: bool,
+    pub(crate) medoids: bool,
+    /// Fraction of pixels counted in the histogram, and seed for choosing them
+    pub(crate) pixel_sampling: (f32, u64),
+    pub(crate) content_mode: ContentMode,
+    pub(crate) palette_algorithm: PaletteAlgorithm,
+    pub(crate) palette_order: PaletteOrder,
+    pub(crate) histogram_reduction: HistogramReduction,
     pub(crate) single_threaded_dithering: bool,
     pub(crate) use_dither_map: DitherMapMode,
     speed: u8,
@@ -57,9 +69,19 @@ impl Attributes {
             max_histogram_entries: 0,
             min_posterization_output: 0,
             min_posterization_input: 0,
+            channel_levels: None,
+            candidate_colors: Vec::new(),
             kmeans_iterations: 0,
             feedback_loop_trials: 0,
             use_contrast_maps: false,
+            grayscale: false,
+            saliency: false,
+            medoids: false,
+            pixel_sampling: (1., 0),
+            content_mode: ContentMode::Photo,
+            palette_algorithm: PaletteAlgorithm::MedianCut,
+            palette_order: PaletteOrder::Popularity,
+            histogram_reduction: HistogramReduction::Posterize,
             use_dither_map: DitherMapMode::None,
             single_threaded_dithering: false,
             speed: 0,
@@ -96,11 +118,36 @@ impl Attributes {
 
     /// Generate palette for the image
     pub fn quantize(&self, image: &mut Image<'_>) -> Result<QuantizationResult, Error> {
+        if self.palette_algorithm == PaletteAlgorithm::Octree
+            && !self.grayscale
+            && self.candidate_colors.is_empty()
+            && !self.medoids
+            && self.content_mode == ContentMode::Photo
+            && !self.saliency
+            && self.pixel_sampling.0 >= 1.
+            && image.importance_map.is_none()
+            && image.regions.is_empty()
+        {
+            return QuantizationResult::from_octree(self, image);
+        }
         let mut hist = Histogram::new(self);
         hist.add_image(self, image)?;
         hist.quantize_internal(self, false)
     }
 
+    /// Chooses speed, posterization and content mode for the kind of the image (photo, illustration, screenshot or icon).
+    ///
+    /// The image is classified from its number of colors, noise and edges, and use of alpha.
+    /// Returns the statistics and the chosen settings, including a recommended dithering level,
+    /// which needs to be set via [`QuantizationResult::set_dithering_level`] after quantization.
+    ///
+    /// It overwrites values set via [`Attributes::set_speed`], [`Attributes::set_min_posterization`] and [`Attributes::set_content_mode`].
+    pub fn auto_tune(&mut self, image: &mut Image<'_>) -> Result<ImageAnalysis, Error> {
+        let analysis = ImageAnalysis::new(image)?;
+        analysis.apply(self)?;
+        Ok(analysis)
+    }
+
     /// It's better to use `set_quality()`
     #[inline]
     pub fn set_max_colors(&mut self, colors: u32) -> Result<(), Error> {
@@ -188,6 +235,64 @@ impl Attributes {
         self.min_posterization_output
     }
 
+    /// Bits of precision of each channel of the palette colors, e.g. `(5, 6, 5, 8)` for RGB565 or `(4, 4, 4, 1)` for 12-bit color with 1-bit alpha.
+    ///
+    /// Values are expanded to 8 bits by repeating their high bits, like hardware does. Use `(8, 8, 8, 8)` to remove the restriction.
+    /// It replaces values set with [`Attributes::set_output_channel_values`].
+    pub fn set_output_bit_depths(&mut self, r: u8, g: u8, b: u8, a: u8) -> Result<(), Error> {
+        if [r, g, b, a].iter().any(|bits| !(1..=8).contains(bits)) {
+            return Err(Error::ValueOutOfRange);
+        }
+        if [r, g, b, a] == [8; 4] {
+            self.channel_levels = None;
+            return Ok(());
+        }
+        let [r, g, b, a] =
+            [r, g, b, a].map(|bits| ChannelLevels::bit_depth_values(bits).collect::<Vec<_>>());
+        self.set_output_channel_values(&r, &g, &b, &a)
+    }
+
+    /// Lists of values that each channel of the palette colors can have, e.g. multiples of 51 for the web-safe palette.
+    ///
+    /// Palette colors are snapped to the closest allowed values, and K-Means measures the error of the snapped colors.
+    /// Each list must have at least one value. Colors of premultiplied output can still be lowered to not exceed alpha.
+    pub fn set_output_channel_values(
+        &mut self,
+        r: &[u8],
+        g: &[u8],
+        b: &[u8],
+        a: &[u8],
+    ) -> Result<(), Error> {
+        self.channel_levels = Some(ChannelLevels::new([r, g, b, a]).ok_or(Error::ValueOutOfRange)?);
+        Ok(())
+    }
+
+    /// Values allowed in each channel (R, G, B, A), or `None` if the output isn't restricted.
+    #[must_use]
+    pub fn output_channel_values(&self) -> Option<[Vec<u8>; 4]> {
+        let levels = self.channel_levels.as_ref()?;
+        Some([0, 1, 2, 3].map(|channel| levels.values(channel).collect()))
+    }
+
+    /// Pool of colors that the palette is selected from, e.g. colors approved for a brand. Empty list removes the restriction.
+    ///
+    /// Up to `max_colors` of them are chosen to fit the image best, instead of inventing new colors.
+    /// Colors are chosen as given (fixed colors are added to them), but like all palette colors they're still rounded to
+    /// the output bit depth ([`Attributes::set_min_posterization`]) and allowed channel values ([`Attributes::set_output_channel_values`]).
+    pub fn set_candidate_colors(&mut self, colors: &[RGBA]) -> Result<(), Error> {
+        let mut candidate_colors = Vec::new();
+        candidate_colors.try_reserve_exact(colors.len())?;
+        candidate_colors.extend_from_slice(colors);
+        self.candidate_colors = candidate_colors;
+        Ok(())
+    }
+
+    /// Colors set with [`Attributes::set_candidate_colors`]. Empty if the palette isn't restricted to them.
+    #[must_use]
+    pub fn candidate_colors(&self) -> &[RGBA] {
+        &self.candidate_colors
+    }
+
     /// Return currently set speed/quality trade-off setting
     #[inline(always)]
     #[must_use]
@@ -293,6 +398,135 @@ impl Attributes {
         self.last_index_transparent = is_last;
     }
 
+    /// Quantize to a palette of pure grays (R=G=B), for grayscale images like X-rays and scanned documents.
+    ///
+    /// Colors are converted to luma, and if all of them have the same alpha, the palette is optimal for the given number of colors.
+    /// Fixed colors are kept as they are.
+    ///
+    /// It must be set before images or histograms are created.
+    #[inline(always)]
+    pub fn set_grayscale(&mut self, grayscale: bool) {
+        self.grayscale = grayscale;
+    }
+
+    /// Getter for the value set in [`Attributes::set_grayscale`]
+    #[inline(always)]
+    #[must_use]
+    pub fn grayscale(&self) -> bool {
+        self.grayscale
+    }
+
+    /// Give more palette precision to the parts of the image that stand out (e.g. the subject of a product photo), and less to the background.
+    ///
+    /// Saliency is estimated from color contrast on a downscaled copy of the image, and combined with the map of noise and edges.
+    /// It doesn't affect images with a map set via [`Image::set_importance_map`], nor [`Histogram::add_rows`]. The default is `false`.
+    #[inline(always)]
+    pub fn set_saliency(&mut self, enabled: bool) {
+        self.saliency = enabled;
+    }
+
+    /// Getter for the value set in [`Attributes::set_saliency`]
+    #[inline(always)]
+    #[must_use]
+    pub fn saliency(&self) -> bool {
+        self.saliency
+    }
+
+    /// Use only colors that exist in the image, e.g. for pixel art and logos.
+    ///
+    /// After each k-means iteration, palette colors are replaced with the most representative image color (medoid) of the colors mapped to them,
+    /// and they aren't adjusted during remapping. Output bit depths are still applied to them. The default is `false`.
+    #[inline(always)]
+    pub fn set_medoids(&mut self, enabled: bool) {
+        self.medoids = enabled;
+    }
+
+    /// Getter for the value set in [`Attributes::set_medoids`]
+    #[inline(always)]
+    #[must_use]
+    pub fn medoids(&self) -> bool {
+        self.medoids
+    }
+
+    /// Builds the histogram from only a `rate` fraction of pixels (0-1), e.g. for fast previews of very large photos.
+    ///
+    /// One pixel is picked at random from every `1/rate` pixels, so samples are spread evenly over the image, and their weights are
+    /// multiplied to compensate. The same `seed` picks the same pixels. [`QuantizationResult::pixel_sampling_rate`] reports the rate achieved.
+    ///
+    /// The default is `1.`, which counts every pixel.
+    pub fn set_pixel_sampling(&mut self, rate: f32, seed: u64) -> Result<(), Error> {
+        if !(rate > 0. && rate <= 1.) {
+            return Err(Error::ValueOutOfRange);
+        }
+        self.pixel_sampling = (rate, seed);
+        Ok(())
+    }
+
+    /// Reads values set with [`Attributes::set_pixel_sampling`]
+    #[inline(always)]
+    #[must_use]
+    pub fn pixel_sampling(&self) -> (f32, u64) {
+        self.pixel_sampling
+    }
+
+    /// Kind of images being quantized. The default is [`ContentMode::Photo`].
+    ///
+    /// [`ContentMode::Text`] keeps anti-aliased text and UI crisp. It must be set before images or histograms are created.
+    #[inline(always)]
+    pub fn set_content_mode(&mut self, mode: ContentMode) {
+        self.content_mode = mode;
+    }
+
+    /// Getter for the value set in [`Attributes::set_content_mode`]
+    #[inline(always)]
+    #[must_use]
+    pub fn content_mode(&self) -> ContentMode {
+        self.content_mode
+    }
+
+    /// Method used for choosing palette colors. The default is [`PaletteAlgorithm::MedianCut`].
+    #[inline(always)]
+    pub fn set_palette_algorithm(&mut self, algorithm: PaletteAlgorithm) {
+        self.palette_algorithm = algorithm;
+    }
+
+    /// Getter for the value set in [`Attributes::set_palette_algorithm`]
+    #[inline(always)]
+    #[must_use]
+    pub fn palette_algorithm(&self) -> PaletteAlgorithm {
+        self.palette_algorithm
+    }
+
+    /// Order of entries in the palette. The default is [`PaletteOrder::Popularity`].
+    ///
+    /// Transparent entries are still placed first (or last with [`Attributes::set_last_index_transparent`]).
+    /// The palette can also be reordered after remapping with [`QuantizationResult::reorder_palette`].
+    #[inline(always)]
+    pub fn set_palette_order(&mut self, order: PaletteOrder) {
+        self.palette_order = order;
+    }
+
+    /// Getter for the value set in [`Attributes::set_palette_order`]
+    #[inline(always)]
+    #[must_use]
+    pub fn palette_order(&self) -> PaletteOrder {
+        self.palette_order
+    }
+
+    /// How colors are merged when an image has too many unique colors for the histogram.
+    /// The default is [`HistogramReduction::Posterize`]. Faster speeds allow fewer colors, see [`Attributes::set_speed`].
+    #[inline(always)]
+    pub fn set_histogram_reduction(&mut self, reduction: HistogramReduction) {
+        self.histogram_reduction = reduction;
+    }
+
+    /// Getter for the value set in [`Attributes::set_histogram_reduction`]
+    #[inline(always)]
+    #[must_use]
+    pub fn histogram_reduction(&self) -> HistogramReduction {
+        self.histogram_reduction
+    }
+
     // true == abort
     #[inline]
     #[must_use]
@@ -417,6 +651,102 @@ pub enum ControlFlow {
     Break = 0,
 }
 
+/// See [`Attributes::set_palette_algorithm`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PaletteAlgorithm {
+    /// Median cut refined with k-means. Works well for all kinds of images.
+    #[default]
+    MedianCut,
+    /// If the colors lie along a line in the color space (like gradients), finds the best possible palette
+    /// for them using dynamic programming, which avoids banding that the heuristics may leave.
+    ///
+    /// Falls back to [`PaletteAlgorithm::MedianCut`] for other images.
+    Optimal,
+    /// Xiaolin Wu's variance-minimizing quantizer, refined with k-means.
+    /// It's fast, and may be a better starting point for photos at low numbers of colors.
+    Wu,
+    /// Agglomerative clustering: starts from all colors of the histogram, and merges the pair of colors
+    /// that adds the least error until few enough remain, then refines them with k-means.
+    /// It's slower, but often better for small palettes, like 16-64 color icons.
+    Agglomerative,
+    /// Octree quantizer for low-latency previews: [`Attributes::quantize`] counts 8-bit pixels in a tree in one pass,
+    /// skipping the histogram, importance maps and k-means, and remapping without dithering looks up colors in the same tree.
+    ///
+    /// Dithering is off by default. Quality is estimated from the tree, so it's approximate. Grayscale, candidate colors, medoids, content modes
+    /// other than [`ContentMode::Photo`], saliency, pixel sampling, importance maps and regions need the histogram, so with them it only replaces median cut.
+    ///
+    /// On a single core, a 1920×1080 image takes about 8-10ms to quantize and 6ms to remap (`octree_1080p` in `benches/simd_bench.rs`),
+    /// so quantizing and remapping together take about 15ms, not under 10ms. Both are split across threads.
+    Octree,
+    /// NeuQuant, a neural network quantizer common in GIF tools, refined with k-means.
+    /// [`Attributes::set_speed`] sets its sampling factor: speed 1 trains on the most samples, speed 10 on the fewest.
+    NeuQuant,
+}
+
+/// See [`Attributes::set_palette_order`] and [`QuantizationResult::reorder_palette`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PaletteOrder {
+    /// Most used colors first
+    #[default]
+    Popularity,
+    /// Darkest to brightest
+    Luminance,
+    /// Grays first, then by hue around the color wheel
+    Hue,
+    /// A path from the darkest color, where every next color is the one closest to the previous.
+    /// Similar colors get nearby indices, which helps PNG filters and palette textures.
+    NearestNeighbor,
+    /// Colors that are often next to each other in the remapped image get consecutive indices.
+    /// This order is kept only if it gives lower entropy of differences between indices of neighboring pixels
+    /// (what PNG's Sub filter compresses) than [`PaletteOrder::NearestNeighbor`] and [`PaletteOrder::Popularity`], otherwise the better of these is used.
+    ///
+    /// It needs a remapped image, so it works only in [`QuantizationResult::reorder_palette`]. Elsewhere it's the same as [`PaletteOrder::Popularity`].
+    IndexEntropy,
+}
+
+/// See [`Attributes::set_histogram_reduction`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum HistogramReduction {
+    /// Ignores the lowest bit of every channel of all colors, which is fast, but loses precision of the whole image,
+    /// including smooth gradients and rare colors.
+    #[default]
+    Posterize,
+    /// Merges similar colors only where there are many of them, starting from the most crowded parts of the color space,
+    /// and keeps colors in sparse parts exact. Merged colors are replaced by the most common of them, so the histogram has only colors of the image.
+    ///
+    /// It applies to 8-bit images. High-bit-depth images are already posterized gradually, a bit at a time.
+    Adaptive,
+}
+
+/// See [`Attributes::set_content_mode`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ContentMode {
+    /// Photos and other natural images, where noise hides dithering
+    #[default]
+    Photo,
+    /// Screenshots, UI, text and line art:
+    ///
+    /// * thin high-contrast structures and their surroundings are not dithered, which would fringe them,
+    /// * colors of large flat areas (like backgrounds and text) are kept exactly, as if they were fixed colors,
+    /// * anti-aliased edges aren't treated as noise, so that their ramps get enough colors.
+    Text,
+    /// Pixel art and sprites with up to 1024 distinct colors:
+    ///
+    /// * colors are kept exactly, and if there are too many of them, the least important ones are merged into the colors closest to them,
+    /// * dithering is off by default, so that hard pixel edges are kept.
+    ///
+    /// Images with more colors are quantized like [`ContentMode::Photo`].
+    PixelArt,
+    /// Use [`ContentMode::Text`] for images dominated by a few flat colors, and [`ContentMode::Photo`] otherwise.
+    ///
+    /// The decision is made from the histogram, so it doesn't change weighting of the anti-aliased edges.
+    Auto,
+}
+
 #[test]
 fn counters() {
     let mut a = Attributes::new();
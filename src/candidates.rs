@@ -0,0 +1,248 @@
+//! Palettes made only of colors from a pool given by the user, e.g. the best 64 of 1000 colors approved for a brand.
+//!
+//! It's k-medoids: histogram colors are assigned to the closest selected candidate,
+//! and then each selected candidate is swapped for a nearby one that has a lower error for the colors assigned to it.
+use crate::attr::Attributes;
+use crate::error::Error;
+use crate::hist::HistogramInternal;
+use crate::mediancut::mediancut;
+use crate::nearest::Nearest;
+use crate::pal::{f_pixel, gamma_lut, PalF, PalLen, PalPop, ARGBF};
+use crate::quant::quality_to_mse;
+use crate::OrdFloat;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Rounds of assigning colors and swapping candidates
+const ITERATIONS: usize = 10;
+/// Candidates closest to the average of colors of an entry that are tried as its replacement
+const SWAP_CANDIDATES: usize = 8;
+
+/// Selects up to `max_colors` colors from [`Attributes::set_candidate_colors`], plus the fixed colors.
+///
+/// Returns the palette and its error, like `find_best_palette`.
+pub(crate) fn candidate_palette(
+    attr: &Attributes,
+    hist: &mut HistogramInternal,
+    gamma: f64,
+    target_mse: f64,
+) -> Result<(PalF, Option<f64>), Error> {
+    let lut = gamma_lut(gamma);
+    let mut candidates = Vec::new();
+    candidates.try_reserve_exact(attr.candidate_colors.len())?;
+    candidates.extend(
+        attr.candidate_colors
+            .iter()
+            .map(|&c| f_pixel::from_rgba(&lut, c))
+            .filter(|c| !hist.fixed_colors.contains(c)),
+    );
+    let fixed_colors = hist.fixed_colors.len().min(attr.max_colors.into());
+    let max_selected = usize::from(attr.max_colors) - fixed_colors;
+    if max_selected == 0 {
+        // the fixed colors take up the whole palette
+        let palette = palette(&candidates, &[], &hist.fixed_colors[..fixed_colors]);
+        let (clusters, error) = assign(hist, &palette)?;
+        return Ok((used_entries(palette, &clusters, 0), Some(error)));
+    }
+
+    let mut selected: Vec<usize> = if candidates.len() <= max_selected {
+        (0..candidates.len()).collect()
+    } else {
+        initial_selection(hist, &candidates, max_selected as PalLen, target_mse)?
+    };
+    selected.truncate(max_selected);
+
+    let mut changed = true;
+    let mut iteration = 0;
+    let (palette, error) = loop {
+        let palette = palette(&candidates, &selected, &hist.fixed_colors[..fixed_colors]);
+        let (clusters, error) = assign(hist, &palette)?;
+        if !changed || iteration >= ITERATIONS || attr.progress(0.) {
+            break (used_entries(palette, &clusters, selected.len()), error);
+        }
+        changed = swap(hist, &candidates, &mut selected, &clusters);
+        iteration += 1;
+    };
+    Ok((palette, Some(error)))
+}
+
+/// Candidates closest to colors of a mediancut palette
+fn initial_selection(
+    hist: &mut HistogramInternal,
+    candidates: &[f_pixel],
+    max_selected: PalLen,
+    target_mse: f64,
+) -> Result<Vec<usize>, Error> {
+    let seeds = mediancut(
+        hist,
+        max_selected,
+        target_mse,
+        target_mse.max(quality_to_mse(51)) * 1.2,
+    )?;
+    let mut is_selected: Vec<bool> = candidates.iter().map(|_| false).collect();
+    let mut selected = Vec::new();
+    selected.try_reserve_exact(seeds.len())?;
+    for seed in seeds.as_slice() {
+        let closest = candidates
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !is_selected[i])
+            .min_by_key(|&(_, c)| OrdFloat::new(c.diff(seed)));
+        if let Some((i, _)) = closest {
+            is_selected[i] = true;
+            selected.push(i);
+        }
+    }
+    Ok(selected)
+}
+
+/// Selected candidates followed by the fixed colors
+fn palette(candidates: &[f_pixel], selected: &[usize], fixed_colors: &[f_pixel]) -> PalF {
+    let mut palette = PalF::new();
+    for &i in selected {
+        palette.push(candidates[i], PalPop::new(0.));
+    }
+    for &c in fixed_colors {
+        palette.push(c, PalPop::new(0.).to_fixed());
+    }
+    palette
+}
+
+/// Colors assigned to one palette entry
+#[derive(Clone, Default)]
+struct Cluster {
+    items: Vec<u32>,
+    weight: f64,
+    sum: ARGBF,
+}
+
+/// Assigns histogram colors to their closest palette entries (also setting their likely palette index).
+///
+/// Returns colors of each entry, and the error of the palette.
+fn assign(hist: &mut HistogramInternal, palette: &PalF) -> Result<(Vec<Cluster>, f64), Error> {
+    let mut clusters = Vec::new();
+    clusters.try_reserve_exact(palette.len())?;
+    clusters.resize(palette.len(), Cluster::default());
+    let n = Nearest::new(palette)?;
+    let mut error = 0.;
+    for (i, item) in hist.items.iter_mut().enumerate() {
+        let (matched, diff) = n.search(&item.color, item.likely_palette_index());
+        item.tmp = matched.into();
+        error += f64::from(diff * item.perceptual_weight);
+        let cluster = &mut clusters[usize::from(matched)];
+        cluster.items.try_reserve(1)?;
+        cluster.items.push(i as u32);
+        cluster.weight += f64::from(item.perceptual_weight);
+        cluster.sum += item.color.0 * item.perceptual_weight;
+    }
+    Ok((clusters, error / hist.total_perceptual_weight.max(1e-10)))
+}
+
+/// Replaces each selected candidate with a nearby one that has a lower error for its colors. Returns `false` if nothing has changed.
+fn swap(
+    hist: &HistogramInternal,
+    candidates: &[f_pixel],
+    selected: &mut [usize],
+    clusters: &[Cluster],
+) -> bool {
+    let mut is_selected: Vec<bool> = candidates.iter().map(|_| false).collect();
+    for &i in selected.iter() {
+        is_selected[i] = true;
+    }
+    let cluster_error = |cluster: &Cluster, color: &f_pixel| -> f64 {
+        cluster
+            .items
+            .iter()
+            .map(|&i| {
+                let item = &hist.items[i as usize];
+                f64::from(item.color.diff(color) * item.perceptual_weight)
+            })
+            .sum()
+    };
+
+    let mut changed = false;
+    for (current, cluster) in selected.iter_mut().zip(clusters) {
+        if cluster.weight <= 0. {
+            continue;
+        }
+        let mean = f_pixel(cluster.sum * (1. / cluster.weight) as f32);
+        let mut nearby: Vec<usize> = (0..candidates.len()).filter(|&i| !is_selected[i]).collect();
+        if nearby.len() > SWAP_CANDIDATES {
+            nearby.select_nth_unstable_by_key(SWAP_CANDIDATES, |&i| {
+                OrdFloat::new(candidates[i].diff(&mean))
+            });
+            nearby.truncate(SWAP_CANDIDATES);
+        }
+
+        let mut best = (*current, cluster_error(cluster, &candidates[*current]));
+        for i in nearby {
+            let error = cluster_error(cluster, &candidates[i]);
+            if error < best.1 {
+                best = (i, error);
+            }
+        }
+        if best.0 != *current {
+            is_selected[*current] = false;
+            is_selected[best.0] = true;
+            *current = best.0;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Removes candidates that no color has been assigned to, and sets popularity of the entries
+fn used_entries(palette: PalF, clusters: &[Cluster], selected_len: usize) -> PalF {
+    let mut used = PalF::new();
+    for (i, ((&color, pop), cluster)) in palette
+        .as_slice()
+        .iter()
+        .zip(palette.pop_as_slice())
+        .zip(clusters)
+        .enumerate()
+    {
+        let is_fixed = i >= selected_len;
+        if is_fixed || cluster.weight > 0. {
+            let new_pop = PalPop::new(cluster.weight as f32);
+            used.push(
+                color,
+                if pop.is_fixed() {
+                    new_pop.to_fixed()
+                } else {
+                    new_pop
+                },
+            );
+        }
+    }
+    if used.len() == 0 {
+        if let Some(&color) = palette.as_slice().first() {
+            used.push(color, PalPop::new(0.));
+        }
+    }
+    used
+}
+
+#[test]
+fn fixed_colors_fill_palette() {
+    use crate::RGBA;
+    let px: Vec<_> = (0..32 * 32)
+        .map(|n| RGBA::new(n as u8, (n / 32) as u8, 128, 255))
+        .collect();
+    let fixed = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255)];
+
+    let mut attr = crate::new();
+    attr.set_max_colors(2).unwrap();
+    attr.set_candidate_colors(&[RGBA::new(255, 0, 0, 255), RGBA::new(0, 0, 255, 255)])
+        .unwrap();
+    let mut img = attr.new_image_borrowed(&px, 32, 32, 0.).unwrap();
+    for c in fixed {
+        img.add_fixed_color(c).unwrap();
+    }
+    let mut res = attr.quantize(&mut img).unwrap();
+    let pal = res.palette_vec();
+    assert_eq!(pal.len(), 2);
+    for c in fixed {
+        assert!(pal.contains(&c), "{pal:?}");
+    }
+}
@@ -0,0 +1,250 @@
+//! Editing of the palette after quantization: reordering, merging and removing entries.
+//!
+//! The float palette, the final RGBA palette and the palette of the last remapping are all edited together.
+//! Indices of already remapped images can be updated with the returned [`PaletteMapping`].
+use crate::error::Error;
+use crate::pal::{
+    standard_mse_to_internal_mse, PalF, PalIndexRemap, PalLen, PalPop, Palette, MAX_COLORS,
+};
+use crate::quant::QuantizationResult;
+use crate::OrdFloat;
+use core::cmp::Reverse;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// How palette indices have changed after editing the palette of a [`QuantizationResult`]
+///
+/// Every old index has a new one. Entries that have been merged or removed map to the entry that replaced them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaletteMapping {
+    new_indices: Vec<PalLen>,
+}
+
+impl PaletteMapping {
+    /// New index of the palette entry that was at `old_index`, or `None` if the index was out of range
+    #[inline]
+    #[must_use]
+    pub fn new_index(&self, old_index: usize) -> Option<usize> {
+        self.new_indices.get(old_index).map(|&i| i.into())
+    }
+
+    /// Updates indices of an image remapped before the edit. Out-of-range indices are left as they were.
+    pub fn apply(&self, indices: &mut [PalIndexRemap]) {
+        for i in indices {
+            if let Some(&new) = self.new_indices.get(usize::from(*i)) {
+                *i = new as PalIndexRemap;
+            }
+        }
+    }
+
+    /// Old index → new index
+    #[inline]
+    #[must_use]
+    pub fn as_slice(&self) -> &[PalLen] {
+        &self.new_indices
+    }
+}
+
+impl QuantizationResult {
+    /// Keep the palette entry at the `index` in place, e.g. for colors reserved by a game engine.
+    ///
+    /// Locked entries don't move when the palette is sorted, and aren't removed or merged into other entries.
+    /// Their color is also kept exactly when remapping (unlocking doesn't undo that).
+    pub fn set_palette_index_locked(&mut self, index: usize, locked: bool) -> Result<(), Error> {
+        let len = self.palette.len();
+        if index >= len {
+            return Err(Error::ValueOutOfRange);
+        }
+        self.locked
+            .get_or_insert_with(|| Box::new([false; MAX_COLORS]))[index] = locked;
+        if locked {
+            if let Some((_, pop)) = self.palette.iter_mut().nth(index) {
+                *pop = pop.to_fixed();
+            }
+        }
+        Ok(())
+    }
+
+    /// Getter for the value set in [`Self::set_palette_index_locked`]
+    #[must_use]
+    pub fn is_palette_index_locked(&self, index: usize) -> bool {
+        index < self.palette.len() && self.locked.as_ref().map_or(false, |l| l[index])
+    }
+
+    /// Sorts palette entries by the key computed from their final RGBA colors (e.g. luminance or hue), except the locked ones.
+    ///
+    /// The sort is stable.
+    pub fn sort_palette_by_key<K: Ord>(
+        &mut self,
+        mut key: impl FnMut(crate::RGBA) -> K,
+    ) -> Result<PaletteMapping, Error> {
+        let len = self.palette.len();
+        let mut unlocked: Vec<usize> = Vec::new();
+        unlocked.try_reserve_exact(len)?;
+        unlocked.extend((0..len).filter(|&i| !self.is_palette_index_locked(i)));
+        let palette = self.palette();
+        unlocked.sort_by_cached_key(|&i| key(palette[i]));
+
+        let mut unlocked = unlocked.into_iter();
+        let new_to_old: Vec<usize> = (0..len)
+            .map(|i| {
+                if self.is_palette_index_locked(i) {
+                    i
+                } else {
+                    unlocked.next().unwrap_or(i)
+                }
+            })
+            .collect();
+        self.rebuild_palette(&new_to_old, &[])
+    }
+
+    /// Merges palette entries that are within `max_difference` of each other, in the same units as [`Self::quantization_error`].
+    ///
+    /// Out of similar entries, the most popular one is kept as it is. Locked entries and fixed colors aren't merged into others.
+    /// Quality estimates aren't updated.
+    pub fn merge_similar_palette_entries(
+        &mut self,
+        max_difference: f64,
+    ) -> Result<PaletteMapping, Error> {
+        if max_difference.is_nan() || max_difference < 0. {
+            return Err(Error::ValueOutOfRange);
+        }
+        let max_difference = standard_mse_to_internal_mse(max_difference) as f32;
+        let colors = self.palette.as_slice();
+        let pops = self.palette.pop_as_slice();
+        let is_kept = |i: usize| self.is_palette_index_locked(i) || pops[i].is_fixed();
+
+        let mut by_popularity: Vec<usize> = (0..colors.len()).collect();
+        by_popularity.sort_by_key(|&i| (!is_kept(i), Reverse(OrdFloat::new(pops[i].popularity()))));
+
+        // each entry is merged into the most popular similar one
+        let mut merged_into: Vec<Option<usize>> = colors.iter().map(|_| None).collect();
+        for (n, &i) in by_popularity.iter().enumerate() {
+            if merged_into[i].is_some() {
+                continue;
+            }
+            merged_into[i] = Some(i);
+            for &j in &by_popularity[n + 1..] {
+                if merged_into[j].is_none()
+                    && !is_kept(j)
+                    && colors[i].diff(&colors[j]) <= max_difference
+                {
+                    merged_into[j] = Some(i);
+                }
+            }
+        }
+        let merged_into: Vec<usize> = merged_into
+            .into_iter()
+            .enumerate()
+            .map(|(i, m)| m.unwrap_or(i))
+            .collect();
+        self.remove_palette_entries(&merged_into)
+    }
+
+    /// Removes palette entries that aren't used in the `indices` of a remapped image. Locked entries and fixed colors are kept.
+    ///
+    /// Entries that were removed are mapped to the closest remaining color, so the mapping can be applied to other images too.
+    pub fn remove_unused_palette_entries(
+        &mut self,
+        indices: &[PalIndexRemap],
+    ) -> Result<PaletteMapping, Error> {
+        let len = self.palette.len();
+        let mut used: Vec<bool> = (0..len).map(|_| false).collect();
+        for &i in indices {
+            if let Some(u) = used.get_mut(usize::from(i)) {
+                *u = true;
+            }
+        }
+        for (i, u) in used.iter_mut().enumerate() {
+            *u |= self.is_palette_index_locked(i) || self.palette.pop_as_slice()[i].is_fixed();
+        }
+        if !used.iter().any(|&u| u) {
+            // the palette can't be empty
+            used[0] = true;
+        }
+
+        let colors = self.palette.as_slice();
+        let replacements: Vec<usize> = (0..len)
+            .map(|i| {
+                if used[i] {
+                    return i;
+                }
+                (0..len)
+                    .filter(|&j| used[j])
+                    .min_by_key(|&j| OrdFloat::new(colors[i].diff(&colors[j])))
+                    .unwrap_or(i)
+            })
+            .collect();
+        self.remove_palette_entries(&replacements)
+    }
+
+    /// Entries for which `replacements[i] != i` are removed, and their indices are mapped to the replacement
+    fn remove_palette_entries(&mut self, replacements: &[usize]) -> Result<PaletteMapping, Error> {
+        let new_to_old: Vec<usize> = replacements
+            .iter()
+            .enumerate()
+            .filter(|&(i, &r)| i == r)
+            .map(|(i, _)| i)
+            .collect();
+        self.rebuild_palette(&new_to_old, replacements)
+    }
+
+    /// Puts the entry `new_to_old[i]` at index `i` in all palettes. Entries not in `new_to_old` are replaced by `replacements`.
+    pub(crate) fn rebuild_palette(
+        &mut self,
+        new_to_old: &[usize],
+        replacements: &[usize],
+    ) -> Result<PaletteMapping, Error> {
+        let len = self.palette.len();
+        let mut new_indices = Vec::new();
+        new_indices.try_reserve_exact(len)?;
+        new_indices.resize(len, 0 as PalLen);
+        for (new, &old) in new_to_old.iter().enumerate() {
+            new_indices[old] = new as PalLen;
+        }
+        for (old, &r) in replacements.iter().enumerate() {
+            new_indices[old] = new_indices[r];
+        }
+
+        // popularity of removed entries goes to their replacements
+        let mut pops: Vec<PalPop> = self.palette.pop_as_slice().to_vec();
+        for (old, &r) in replacements.iter().enumerate() {
+            if r != old {
+                let merged = PalPop::new(pops[r].popularity() + pops[old].popularity());
+                pops[r] = if pops[r].is_fixed() {
+                    merged.to_fixed()
+                } else {
+                    merged
+                };
+            }
+        }
+        let mut palette = PalF::new();
+        for &old in new_to_old {
+            palette.push(self.palette.as_slice()[old], pops[old]);
+        }
+        self.palette = palette;
+
+        let reorder = |int_palette: &mut Palette| {
+            if int_palette.count > 0 {
+                let old = int_palette.entries;
+                for (entry, &o) in int_palette.entries.iter_mut().zip(new_to_old) {
+                    *entry = old[o];
+                }
+                int_palette.count = new_to_old.len() as _;
+            }
+        };
+        reorder(&mut self.int_palette);
+        if let Some(remapped) = self.remapped.as_deref_mut() {
+            reorder(&mut remapped.int_palette);
+        }
+        if let Some(locked) = self.locked.as_deref_mut() {
+            let old = *locked;
+            *locked = [false; MAX_COLORS];
+            for (l, &o) in locked.iter_mut().zip(new_to_old) {
+                *l = old[o];
+            }
+        }
+        Ok(PaletteMapping { new_indices })
+    }
+}
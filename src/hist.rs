@@ -1,9 +1,16 @@
+use crate::attr::{ContentMode, HistogramReduction};
 use crate::error::*;
 use crate::image::Image;
-use crate::pal::{f_pixel, gamma_lut, PalIndex, ARGBF, MAX_COLORS, RGBA};
+use crate::merge::PIXEL_ART_MAX_COLORS;
+use crate::pal::{
+    f_pixel, gamma_exponent, gamma_lut, to_gray, to_gray16, unpremultiply, unpremultiply16,
+    PalIndex, PalLen, ARGBF, MAX_COLORS, RGBA, RGBA16,
+};
 use crate::quant::QuantizationResult;
 use crate::rows::{temp_buf, DynamicRows};
+use crate::stream::{self, RowSource};
 use crate::Attributes;
+use core::cmp::Reverse;
 use core::hash::Hash;
 use core::{fmt, hash, mem};
 
@@ -37,10 +44,89 @@ pub struct Histogram {
 
     /// The key is the RGBA cast to u32
     /// The value is a (boosted) count or 0 if it's a fixed color
-    hashmap: HashMap<u32, (u32, RGBA), U32Hasher>,
+    pub(crate) hashmap: HashMap<u32, (u32, RGBA), U32Hasher>,
+
+    /// Same as `hashmap`, but for high-bit-depth images. The key is RGBA16 cast to u64.
+    hashmap16: HashMap<u64, (u32, RGBA16), U32Hasher>,
 
     posterize_bits: u8,
+    /// Number of low bits ignored in `hashmap16` keys. Grows to fit the colors into `max_histogram_entries`
+    posterize_bits16: u8,
     max_histogram_entries: u32,
+    /// Colors are converted to luma when added
+    grayscale: bool,
+    /// `None` if every pixel is counted
+    sampler: Option<Box<PixelSampler>>,
+    /// What to do when `hashmap` has more than `max_histogram_entries`
+    reduction: HistogramReduction,
+}
+
+/// Stratified sampling: picks one pixel at random from every run of `1/rate` pixels
+struct PixelSampler {
+    /// Pixels per sample
+    step: f64,
+    /// Multiplies weights of the sampled pixels
+    weight: f32,
+    /// Run of pixels of the next sample, from the start of the image
+    stratum: u64,
+    /// Index of the next sampled pixel in the image
+    next: u64,
+    /// Continues across images, so that bands of streamed images don't repeat the same pattern
+    rng: u64,
+    /// Pixels of all images added, and how many of them were counted
+    total_pixels: u64,
+    sampled_pixels: u64,
+}
+
+impl PixelSampler {
+    fn new((rate, seed): (f32, u64)) -> Option<Box<Self>> {
+        (rate < 1.).then(|| {
+            Box::new(Self {
+                step: 1. / f64::from(rate),
+                weight: 1. / rate,
+                stratum: 0,
+                next: 0,
+                rng: seed,
+                total_pixels: 0,
+                sampled_pixels: 0,
+            })
+        })
+    }
+
+    /// splitmix64, in 0..1
+    fn random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn start_image(&mut self, pixels: usize) {
+        self.total_pixels += pixels as u64;
+        self.stratum = 0;
+        // at least one pixel of small images
+        self.next = (self.random() * self.step.min(pixels as f64)) as u64;
+    }
+
+    /// Next sampled column of the row that starts at pixel `row_start`
+    #[inline]
+    fn next_in_row(&mut self, row_start: u64, width: usize) -> Option<usize> {
+        let col = self.next.checked_sub(row_start)?;
+        if col >= width as u64 {
+            return None;
+        }
+        self.stratum += 1;
+        self.next = ((self.stratum as f64 + self.random()) * self.step) as u64;
+        self.sampled_pixels += 1;
+        Some(col as usize)
+    }
+
+    #[inline]
+    fn boost(&self, boost: u8) -> u32 {
+        (f32::from(boost) * self.weight + 0.5) as u32
+    }
 }
 
 pub(crate) type FixedColorsSet = HashSet<HashColor, U32Hasher>;
@@ -91,7 +177,16 @@ impl Histogram {
             max_histogram_entries: attr.max_histogram_entries,
             fixed_colors: HashSet::with_hasher(U32Hasher(0)),
             hashmap: HashMap::with_hasher(U32Hasher(0)),
+            hashmap16: HashMap::with_hasher(U32Hasher(0)),
+            // posterization is set in 8-bit units, so it also drops all the extra precision of 16-bit colors
+            posterize_bits16: match attr.posterize_bits() {
+                0 => 0,
+                bits => bits + 8,
+            },
             gamma: None,
+            grayscale: attr.grayscale,
+            sampler: PixelSampler::new(attr.pixel_sampling),
+            reduction: attr.histogram_reduction,
         }
     }
 
@@ -101,27 +196,41 @@ impl Histogram {
     /// this function will fail with `LIQ_BUFFER_TOO_SMALL`.
     #[inline(never)]
     pub fn add_image(&mut self, attr: &Attributes, image: &mut Image) -> Result<(), Error> {
+        if image.importance_map.is_none() {
+            if attr.use_contrast_maps {
+                image.contrast_maps()?;
+                if attr.content_mode == ContentMode::Text {
+                    image.raise_importance_of_edges();
+                }
+            }
+            if attr.saliency {
+                image.apply_saliency()?;
+            }
+        }
+        self.add_image_with_importance_map(attr, image)
+    }
+
+    /// `add_image()` without making the importance map from the image, for bands of [`Self::add_rows`] that get it from a larger window
+    pub(crate) fn add_image_with_importance_map(
+        &mut self,
+        attr: &Attributes,
+        image: &mut Image,
+    ) -> Result<(), Error> {
         let width = image.width();
         let height = image.height();
-        if image.importance_map.is_none() && attr.use_contrast_maps {
-            image.contrast_maps()?;
-        }
+        image.apply_importance_regions()?;
 
         self.gamma = image.gamma();
 
         if !image.fixed_colors.is_empty() {
             self.fixed_colors
-                .extend(
-                    image
-                        .fixed_colors
-                        .iter()
-                        .copied()
-                        .enumerate()
-                        .map(|(idx, rgba)| HashColor {
-                            rgba,
-                            index: idx as _,
-                        }),
-                );
+                .extend(image.fixed_colors.iter().copied().enumerate().map(
+                    |(idx, (rgba, pinned))| HashColor {
+                        rgba,
+                        index: idx as _,
+                        pinned,
+                    },
+                ));
         }
 
         if attr.progress(f32::from(attr.progress_stage1) * 0.40) {
@@ -133,13 +242,40 @@ impl Histogram {
         let estimated_colors = (surface_area
             / (posterize_bits as usize + if surface_area > 512 * 512 { 7 } else { 5 }))
         .min(250_000);
-        self.reserve(estimated_colors);
-
-        self.add_pixel_rows(&image.px, image.importance_map.as_deref(), posterize_bits)?;
+        if let Some(sampler) = &mut self.sampler {
+            sampler.start_image(surface_area);
+        }
+        if image.px.is_high_depth() {
+            self.add_pixel_rows16(&image.px, image.importance_map.as_deref())?;
+        } else {
+            let sampled = self.sampler.as_ref().map_or(estimated_colors, |s| {
+                (estimated_colors as f64 / s.step) as usize
+            });
+            self.reserve(sampled);
+            self.add_pixel_rows(&image.px, image.importance_map.as_deref(), posterize_bits)?;
+        }
 
         Ok(())
     }
 
+    /// Alternative to `add_image()` for images too large to keep in memory. Pixels are read from the `source` in bands of rows, top to bottom.
+    ///
+    /// Memory use is proportional to the `width`, not the area of the image. Use [`QuantizationResult::remap_rows`] to remap the image the same way.
+    ///
+    /// Use `0.` for gamma if the image is sRGB (most images are).
+    ///
+    /// [`Attributes::set_saliency`] is not supported, because it needs the whole image at once.
+    pub fn add_rows(
+        &mut self,
+        attr: &Attributes,
+        source: &mut impl RowSource,
+        width: usize,
+        height: usize,
+        gamma: f64,
+    ) -> Result<(), Error> {
+        stream::add_rows(self, attr, source, width, height, gamma)
+    }
+
     /// Alternative to `add_image()`. Intead of counting colors in an image, it directly takes an array of colors and their counts.
     ///
     /// This function is only useful if you already have a histogram of the image from another source.
@@ -184,6 +320,49 @@ impl Histogram {
         self.fixed_colors.insert(HashColor {
             rgba,
             index: idx as _,
+            pinned: None,
+        });
+
+        Ok(())
+    }
+
+    /// Like [`Histogram::add_fixed_color`], but the color is also guaranteed to be at the given `index` of the palette.
+    /// The rest of the palette is sorted around the pinned colors.
+    ///
+    /// If the palette has fewer colors than `index`, the color is placed as if it wasn't pinned. It takes precedence over [`Attributes::set_last_index_transparent`].
+    pub fn add_fixed_color_at(
+        &mut self,
+        rgba: RGBA,
+        gamma: f64,
+        index: usize,
+    ) -> Result<(), Error> {
+        if index >= MAX_COLORS
+            || self
+                .fixed_colors
+                .iter()
+                .any(|c| c.pinned == Some(index as PalLen) && c.rgba != rgba)
+        {
+            return Err(ValueOutOfRange);
+        }
+        if self.fixed_colors.len() >= MAX_COLORS {
+            return Err(Unsupported);
+        }
+
+        if self.gamma.is_none() && gamma > 0. {
+            self.gamma = Some(gamma);
+        }
+
+        // the color may have been added already without the index
+        let idx = self
+            .fixed_colors
+            .iter()
+            .find(|c| c.rgba == rgba)
+            .map_or(self.fixed_colors.len(), |c| c.index.into());
+        self.fixed_colors.retain(|c| c.rgba != rgba);
+        self.fixed_colors.insert(HashColor {
+            rgba,
+            index: idx as _,
+            pinned: Some(index as PalLen),
         });
 
         Ok(())
@@ -204,7 +383,7 @@ impl Histogram {
         attr: &Attributes,
         freeze_result_colors: bool,
     ) -> Result<QuantizationResult, Error> {
-        if self.hashmap.is_empty() && self.fixed_colors.is_empty() {
+        if self.hashmap.is_empty() && self.hashmap16.is_empty() && self.fixed_colors.is_empty() {
             return Err(Unsupported);
         }
 
@@ -215,15 +394,55 @@ impl Histogram {
             return Err(Aborted);
         }
 
+        let content_mode = match attr.content_mode {
+            ContentMode::Auto if self.is_dominated_by_flat_colors() => ContentMode::Text,
+            ContentMode::Auto => ContentMode::Photo,
+            ContentMode::PixelArt
+                if self.hashmap.len() + self.hashmap16.len() > PIXEL_ART_MAX_COLORS =>
+            {
+                ContentMode::Photo
+            }
+            mode => mode,
+        };
+        let dominant_colors = if content_mode == ContentMode::Text {
+            self.dominant_fixed_colors(attr.max_colors)?
+        } else {
+            Vec::new()
+        };
+
         let gamma = self.gamma.unwrap_or(0.45455);
-        let hist = self.finalize_builder(gamma).map_err(|_| OutOfMemory)?;
+        let hist = self
+            .finalize_builder_with(gamma, &dominant_colors)
+            .map_err(|_| OutOfMemory)?;
 
         attr.verbose_print(format!(
             "  made histogram...{} colors found",
             hist.items.len()
         ));
+        let pixel_sampling_rate = self.pixel_sampling_rate();
+        if self.sampler.is_some() {
+            attr.verbose_print(format!(
+                "  sampled {:.1}% of pixels",
+                100. * pixel_sampling_rate
+            ));
+        }
+
+        QuantizationResult::new(attr, hist, freeze_result_colors, gamma, content_mode).map(
+            |mut res| {
+                res.pixel_sampling_rate = pixel_sampling_rate;
+                res
+            },
+        )
+    }
 
-        QuantizationResult::new(attr, hist, freeze_result_colors, gamma)
+    /// Fraction of pixels of the added images that were counted, 1 if all were
+    fn pixel_sampling_rate(&self) -> f32 {
+        match &self.sampler {
+            Some(s) if s.total_pixels > 0 => {
+                (s.sampled_pixels as f64 / s.total_pixels as f64) as f32
+            }
+            _ => 1.,
+        }
     }
 
     #[inline(always)]
@@ -231,6 +450,7 @@ impl Histogram {
         if boost == 0 {
             return;
         }
+        let rgba = if self.grayscale { to_gray(rgba) } else { rgba };
 
         let px_int = if rgba.a != 0 {
             self.posterize_mask() & rgba_to_u32(rgba)
@@ -297,20 +517,261 @@ impl Histogram {
                 .next()
                 .map(move |m| &m[..width])
                 .unwrap_or(&[]);
-            for (col, px) in pixels_row.iter().copied().enumerate() {
-                let boost = importance_map.get(col).copied().unwrap_or(255);
-                self.add_color(px, boost.into());
+            // Histogram keys are straight-alpha colors, so that they can be shared with fixed colors and other images.
+            // That's a trade-off: rounding to 8-bit straight alpha shifts colors by up to half a step (at most half a step
+            // of the premultiplied value too), unlike the remapping, which converts premultiplied pixels directly.
+            let premultiplied = image.premultiplied;
+            if let Some(mut sampler) = self.sampler.take() {
+                while let Some(col) = sampler.next_in_row((row * width) as u64, width) {
+                    let boost = importance_map.get(col).copied().unwrap_or(255);
+                    let px = pixels_row[col];
+                    let px = if premultiplied { unpremultiply(px) } else { px };
+                    self.add_color(px, sampler.boost(boost));
+                }
+                self.sampler = Some(sampler);
+            } else {
+                for (col, px) in pixels_row.iter().copied().enumerate() {
+                    let boost = importance_map.get(col).copied().unwrap_or(255);
+                    let px = if premultiplied { unpremultiply(px) } else { px };
+                    self.add_color(px, boost.into());
+                }
+            }
+            // merging is precise enough to do it while adding, which keeps memory use bounded
+            if self.reduction == HistogramReduction::Adaptive
+                && self.hashmap.len() > 2 * self.max_histogram_entries as usize
+            {
+                self.merge_dense_colors(self.max_histogram_entries as usize)?;
             }
         }
         self.init_posterize_bits(posterize_bits);
 
-        if self.hashmap.len() > self.max_histogram_entries as usize && self.posterize_bits < 3 {
-            self.init_posterize_bits(self.posterize_bits + 1);
+        let max_entries = self.max_histogram_entries as usize;
+        if self.hashmap.len() > max_entries {
+            match self.reduction {
+                HistogramReduction::Adaptive => self.merge_dense_colors(max_entries)?,
+                HistogramReduction::Posterize if self.posterize_bits < 3 => {
+                    self.init_posterize_bits(self.posterize_bits + 1);
+                }
+                HistogramReduction::Posterize => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges colors that share a cell of a grid into the most common of them, until there are at most `max_entries` colors.
+    /// Merged colors are always colors of the image, so medoids can still use them.
+    ///
+    /// Cells with the most colors are merged first, starting from the finest grid (ignoring 1 bit of every channel),
+    /// so colors in sparse parts of the color space stay exact.
+    fn merge_dense_colors(&mut self, max_entries: usize) -> Result<(), Error> {
+        let mut keys = Vec::new();
+        keys.try_reserve_exact(self.hashmap.len())?;
+        let mut cells = Vec::new();
+        for bits in 1..8 {
+            if self.hashmap.len() <= max_entries {
+                break;
+            }
+            let channel_mask = 255 << bits;
+            let cell_mask = u32::from_ne_bytes([channel_mask; 4]);
+            keys.clear();
+            // transparent colors are all in the same entry already
+            keys.extend(
+                self.hashmap
+                    .keys()
+                    .filter(|&&k| k != 0)
+                    .map(|&k| (k & cell_mask, k)),
+            );
+            keys.sort_unstable();
+
+            cells.clear();
+            let mut start = 0;
+            for end in 1..=keys.len() {
+                if end == keys.len() || keys[end].0 != keys[start].0 {
+                    if end - start > 1 {
+                        cells.push(start..end);
+                    }
+                    start = end;
+                }
+            }
+            // stable, so that cells of the same size are merged in the same order every time
+            cells.sort_by_key(|cell| Reverse(cell.len()));
+
+            for cell in cells.drain(..) {
+                if self.hashmap.len() <= max_entries {
+                    break;
+                }
+                let mut heaviest = None;
+                let mut count = 0u32;
+                for &(_, key) in &keys[cell] {
+                    if let Some((boost, rgba)) = self.hashmap.remove(&key) {
+                        if heaviest.map_or(true, |(_, max_boost, _)| boost > max_boost) {
+                            heaviest = Some((key, boost, rgba));
+                        }
+                        count = count.saturating_add(boost);
+                    }
+                }
+                if let Some((key, _, rgba)) = heaviest {
+                    self.hashmap.insert(key, (count, rgba));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn add_color16(&mut self, rgba: RGBA16, boost: u32) {
+        if boost == 0 {
+            return;
+        }
+        let rgba = if self.grayscale {
+            to_gray16(rgba)
+        } else {
+            rgba
+        };
+
+        let px_int = if rgba.a != 0 {
+            let channel_mask = u64::from(0xFFFF_u16 << self.posterize_bits16);
+            rgba16_to_u64(rgba) & (channel_mask * 0x0001_0001_0001_0001)
+        } else {
+            0
+        };
+
+        self.hashmap16
+            .entry(px_int)
+            .and_modify(move |e| e.0 = e.0.saturating_add(boost))
+            .or_insert((boost, rgba));
+    }
+
+    /// Like `init_posterize_bits`, but the 16-bit histogram can afford to drop precision gradually
+    fn init_posterize_bits16(&mut self, posterize_bits: u8) {
+        if self.posterize_bits16 >= posterize_bits {
+            return;
+        }
+        self.posterize_bits16 = posterize_bits;
+        let channel_mask = u64::from(0xFFFF_u16 << posterize_bits) * 0x0001_0001_0001_0001;
+
+        let mut new_hashmap =
+            HashMap::with_capacity_and_hasher(self.hashmap16.len() / 2, U32Hasher(0));
+        for (k, v) in self.hashmap16.drain() {
+            new_hashmap
+                .entry(k & channel_mask)
+                .and_modify(|e: &mut (u32, RGBA16)| e.0 = e.0.saturating_add(v.0))
+                .or_insert(v);
+        }
+        self.hashmap16 = new_hashmap;
+    }
+
+    pub(crate) fn add_pixel_rows16(
+        &mut self,
+        image: &DynamicRows<'_, '_>,
+        importance_map: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let width = image.width as usize;
+        let height = image.height as usize;
+        // the same limit as for 8-bit pixels with max posterization
+        let max_posterize_bits16 = 8 + 3;
+
+        let mut importance_map = importance_map.unwrap_or(&[]).chunks_exact(width).fuse();
+        let mut temp_row = temp_buf(width)?;
+        for row in 0..height {
+            let pixels_row = image.row_rgba16(&mut temp_row, row);
+            let importance_map = importance_map.next().unwrap_or(&[]);
+            let unpremultiply = |px| {
+                if image.premultiplied {
+                    unpremultiply16(px)
+                } else {
+                    px
+                }
+            };
+            if let Some(mut sampler) = self.sampler.take() {
+                while let Some(col) = sampler.next_in_row((row * width) as u64, width) {
+                    let boost = importance_map.get(col).copied().unwrap_or(255);
+                    self.add_color16(unpremultiply(pixels_row[col]), sampler.boost(boost));
+                }
+                self.sampler = Some(sampler);
+            } else {
+                for (col, px) in pixels_row.iter().copied().enumerate() {
+                    let boost = importance_map.get(col).copied().unwrap_or(255);
+                    self.add_color16(unpremultiply(px), boost.into());
+                }
+            }
+            // every pixel may be unique, so the precision has to be reduced while adding
+            while self.hashmap16.len() > self.max_histogram_entries as usize
+                && self.posterize_bits16 < max_posterize_bits16
+            {
+                self.init_posterize_bits16(self.posterize_bits16 + 1);
+            }
         }
         Ok(())
     }
 
+    /// Weights and colors of both 8-bit and 16-bit pixels
+    fn weighted_colors(&self) -> impl Iterator<Item = (u32, TempColor)> + '_ {
+        let colors = self.hashmap.values().map(|&(w, c)| (w, TempColor::Rgba(c)));
+        let colors16 = self
+            .hashmap16
+            .values()
+            .map(|&(w, c)| (w, TempColor::Rgba16(c)));
+        colors.chain(colors16)
+    }
+
+    fn total_weight(&self) -> u64 {
+        self.weighted_colors().map(|(w, _)| u64::from(w)).sum()
+    }
+
+    /// Colors that cover a large part of the image, most common first
+    fn dominant_colors(&self) -> Result<Vec<(u32, TempColor)>, Error> {
+        let total = self.total_weight();
+        let mut colors = Vec::new();
+        colors.try_reserve(32)?;
+        colors.extend(
+            self.weighted_colors()
+                .filter(|&(w, _)| u64::from(w) * DOMINANT_COLOR_DIVISOR >= total && w > 0),
+        );
+        colors.sort_unstable_by_key(|&(w, _)| core::cmp::Reverse(w));
+        Ok(colors)
+    }
+
+    /// Screenshots and text are mostly flat areas in a few colors, while photos have no color covering much of the image
+    fn is_dominated_by_flat_colors(&self) -> bool {
+        let total = self.total_weight();
+        let dominant = self
+            .dominant_colors()
+            .map_or(0, |c| c.iter().map(|&(w, _)| u64::from(w)).sum::<u64>());
+        total > 0 && dominant * 2 >= total
+    }
+
+    /// Dominant colors to use as fixed colors, so that flat areas keep their exact color. They may take up to half of the palette.
+    ///
+    /// They're fixed only for one palette, and aren't added to `fixed_colors`, so that the histogram can be quantized again in another mode.
+    fn dominant_fixed_colors(&self, max_colors: PalLen) -> Result<Vec<TempColor>, Error> {
+        let limit = (max_colors as usize / 2)
+            .max(1)
+            .min(MAX_COLORS.saturating_sub(self.fixed_colors.len()));
+        let mut colors = self.dominant_colors()?;
+        colors.retain(|&(_, color)| {
+            !self
+                .fixed_colors
+                .iter()
+                .any(|c| color == TempColor::Rgba(c.rgba))
+        });
+        Ok(colors
+            .into_iter()
+            .take(limit)
+            .map(|(_, color)| color)
+            .collect())
+    }
+
     pub(crate) fn finalize_builder(&mut self, gamma: f64) -> Result<HistogramInternal, Error> {
+        self.finalize_builder_with(gamma, &[])
+    }
+
+    /// `extra_fixed_colors` must be colors from the histogram. They're treated like fixed colors added after `fixed_colors`.
+    fn finalize_builder_with(
+        &mut self,
+        gamma: f64,
+        extra_fixed_colors: &[TempColor],
+    ) -> Result<HistogramInternal, Error> {
         debug_assert!(gamma > 0.);
 
         // Fixed colors will be put into normal hashmap, but with very high weight,
@@ -322,7 +783,7 @@ impl Histogram {
         }
 
         let mut temp = Vec::new();
-        temp.try_reserve_exact(self.hashmap.len())?;
+        temp.try_reserve_exact(self.hashmap.len() + self.hashmap16.len())?;
 
         let mut counts = [0; LIQ_MAXCLUSTER];
         temp.extend(self.hashmap.values().map(|&(boost, color)| {
@@ -333,7 +794,31 @@ impl Histogram {
             counts[cluster_index as usize] += 1;
 
             // fixed colors result in weight == 0.
-            let weight = boost as f32;
+            let color = TempColor::Rgba(color);
+            let weight = if extra_fixed_colors.contains(&color) {
+                0.
+            } else {
+                boost as f32
+            };
+            TempHistItem {
+                color,
+                weight,
+                cluster_index,
+            }
+        }));
+        temp.extend(self.hashmap16.values().map(|&(boost, color)| {
+            let cluster_index = (((color.r >> 15) << 3)
+                | ((color.g >> 15) << 2)
+                | ((color.b >> 15) << 1)
+                | (color.a >> 15)) as u8;
+            counts[cluster_index as usize] += 1;
+
+            let color = TempColor::Rgba16(color);
+            let weight = if extra_fixed_colors.contains(&color) {
+                0.
+            } else {
+                boost as f32
+            };
             TempHistItem {
                 color,
                 weight,
@@ -378,6 +863,7 @@ impl Histogram {
             ((0.1 / 255.) * temp.iter().map(|t| f64::from(t.weight)).sum::<f64>()) as f32;
 
         let lut = gamma_lut(gamma);
+        let exp = gamma_exponent(gamma);
         let mut total_perceptual_weight = 0.;
         for temp_item in temp {
             let cluster = &mut clusters[temp_item.cluster_index as usize];
@@ -392,16 +878,22 @@ impl Histogram {
             };
             total_perceptual_weight += f64::from(weight);
 
-            items[next_index].color = f_pixel::from_rgba(&lut, temp_item.color);
+            items[next_index].color = temp_item.color.to_f_pixel(&lut, exp);
             items[next_index].perceptual_weight = weight;
             items[next_index].adjusted_weight = weight;
         }
 
         let mut fixed_colors: Vec<_> = self.fixed_colors.iter().collect();
         fixed_colors.sort_by_key(|c| c.index); // original order
+        let pinned_colors = fixed_colors
+            .iter()
+            .filter_map(|c| Some((f_pixel::from_rgba(&lut, c.rgba), c.pinned?)))
+            .collect();
         let fixed_colors = fixed_colors
             .iter()
-            .map(|c| f_pixel::from_rgba(&lut, c.rgba))
+            .map(|c| TempColor::Rgba(c.rgba))
+            .chain(extra_fixed_colors.iter().copied())
+            .map(|color| color.to_f_pixel(&lut, exp))
             .collect();
 
         Ok(HistogramInternal {
@@ -409,30 +901,107 @@ impl Histogram {
             total_perceptual_weight,
             clusters,
             fixed_colors,
+            pinned_colors,
         })
     }
 }
 
 #[derive(Copy, Clone)]
 struct TempHistItem {
-    color: RGBA,
+    color: TempColor,
     weight: f32,
     cluster_index: u8,
 }
 
+#[derive(Copy, Clone, PartialEq)]
+enum TempColor {
+    Rgba(RGBA),
+    Rgba16(RGBA16),
+}
+
+impl TempColor {
+    #[inline]
+    fn to_f_pixel(self, lut: &[f32; 256], exp: f32) -> f_pixel {
+        match self {
+            Self::Rgba(color) => f_pixel::from_rgba(lut, color),
+            Self::Rgba16(color) => f_pixel::from_rgba16(exp, color, false),
+        }
+    }
+}
+
 #[inline(always)]
 fn rgba_to_u32(rgba: RGBA) -> u32 {
     rgb::bytemuck::cast(rgba)
 }
 
+#[inline(always)]
+fn rgba16_to_u64(rgba: RGBA16) -> u64 {
+    rgb::bytemuck::cast(rgba)
+}
+
 /// Clusters form initial boxes for quantization, to ensure extreme colors are better represented
 pub const LIQ_MAXCLUSTER: usize = 16;
 
+/// Colors covering at least 1/50th of the image are dominant in [`ContentMode::Text`]
+pub(crate) const DOMINANT_COLOR_DIVISOR: u64 = 50;
+
 pub(crate) struct HistogramInternal {
     pub items: Box<[HistItem]>,
     pub total_perceptual_weight: f64,
     pub clusters: [Cluster; LIQ_MAXCLUSTER],
     pub fixed_colors: Box<[f_pixel]>,
+    /// Fixed colors that must be at the given palette index
+    pub pinned_colors: Box<[(f_pixel, PalLen)]>,
+}
+
+#[cfg(test)]
+impl HistogramInternal {
+    /// Histogram of colors with their counts, for testing palette algorithms
+    pub(crate) fn from_colors(colors: &[(RGBA, u32)]) -> Self {
+        let entries: Vec<_> = colors
+            .iter()
+            .map(|&(color, count)| HistogramEntry { color, count })
+            .collect();
+        let mut hist = Histogram::new(&Attributes::new());
+        hist.add_colors(&entries, 0.45455).unwrap();
+        hist.finalize_builder(0.45455).unwrap()
+    }
+}
+
+#[test]
+fn dominant_colors_16bit() {
+    let (width, height) = (100, 100);
+    let background = RGBA16::new(64250, 64250, 62965, 65535);
+    let brand = RGBA16::new(9509, 25443, 60395, 65535);
+    let px: Vec<_> = (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            match y {
+                0..=14 => brand,
+                15..=59 => background,
+                _ => RGBA16::new((x * 600) as u16, (y * 300) as u16, 20000, 65535),
+            }
+        })
+        .collect();
+
+    let attr = Attributes::new();
+    let mut img = crate::Image::new_rgba16(&attr, &px[..], width, height, 0.).unwrap();
+    let mut hist = Histogram::new(&attr);
+    hist.add_image(&attr, &mut img).unwrap();
+    assert!(hist.hashmap.is_empty());
+    assert!(hist.is_dominated_by_flat_colors());
+
+    let dominant = hist.dominant_fixed_colors(8).unwrap();
+    let expected = [TempColor::Rgba16(background), TempColor::Rgba16(brand)];
+    assert!(dominant == expected);
+
+    let lut = gamma_lut(0.45455);
+    let exp = gamma_exponent(0.45455);
+    let fixed = hist
+        .finalize_builder_with(0.45455, &dominant)
+        .unwrap()
+        .fixed_colors;
+    assert_eq!(*fixed, expected.map(|c| c.to_f_pixel(&lut, exp)));
 }
 
 // Pre-grouped colors
@@ -473,8 +1042,10 @@ impl hash::Hasher for U32Hasher {
     fn write_u16(&mut self, _i: u16) {
         unimplemented!()
     }
-    fn write_u64(&mut self, _i: u64) {
-        unimplemented!()
+    /// For 16-bit colors. Folds to 32 bits, because `finish()` multiplies anyway.
+    #[inline(always)]
+    fn write_u64(&mut self, i: u64) {
+        self.0 = (i as u32) ^ ((i >> 32) as u32).rotate_left(16);
     }
     fn write_u128(&mut self, _i: u128) {
         unimplemented!()
@@ -507,6 +1078,8 @@ impl hash::Hasher for U32Hasher {
 pub(crate) struct HashColor {
     pub rgba: RGBA,
     pub index: PalIndex,
+    /// Palette index from `add_fixed_color_at`
+    pub pinned: Option<PalLen>,
 }
 
 #[allow(clippy::derived_hash_with_manual_eq)]
@@ -518,6 +1091,4 @@ impl Hash for HashColor {
     }
 }
 
-impl Eq for HashColor {
-    fn assert_receiver_is_total_eq(&self) {}
-}
+impl Eq for HashColor {}
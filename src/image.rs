@@ -1,9 +1,11 @@
 use crate::attr::Attributes;
 use crate::blur::{liq_blur, liq_max3, liq_min3};
 use crate::error::*;
-use crate::pal::{f_pixel, PalF, PalIndexRemap, MAX_COLORS, RGBA};
+use crate::pal::{f_pixel, PalF, PalIndexRemap, PalLen, MAX_COLORS, RGBA, RGBA16};
+use crate::region::{apply_regions, Region};
 use crate::remap::DitherMapMode;
 use crate::rows::{DynamicRows, PixelsSource};
+use crate::saliency;
 use crate::seacow::{RowBitmap, SeaCow};
 use crate::{PushInCapacity, LIQ_HIGH_MEMORY_LIMIT};
 use core::mem;
@@ -25,7 +27,9 @@ pub struct Image<'pixels> {
     pub(crate) edges: Option<Box<[u8]>>,
     pub(crate) dither_map: Option<Box<[u8]>>,
     pub(crate) background: Option<Box<Image<'pixels>>>,
-    pub(crate) fixed_colors: Vec<RGBA>,
+    /// With optional index they're pinned to
+    pub(crate) fixed_colors: Vec<(RGBA, Option<PalLen>)>,
+    pub(crate) regions: Vec<Region>,
 }
 
 impl<'pixels> Image<'pixels> {
@@ -128,6 +132,52 @@ impl<'pixels> Image<'pixels> {
         )
     }
 
+    /// Makes an image from 16-bit RGBA pixels, e.g. from a 16-bit PNG.
+    ///
+    /// The pixels are converted to the internal color space without rounding them to 8 bits first,
+    /// and the histogram keeps colors at higher precision too. The output is still an 8-bit palette.
+    ///
+    /// Rows must be contiguous (stride equal to `width`). Use `0.` for gamma if the image is sRGB.
+    pub fn new_rgba16<VecRGBA16>(
+        attr: &Attributes,
+        pixels: VecRGBA16,
+        width: usize,
+        height: usize,
+        gamma: f64,
+    ) -> Result<Self, Error>
+    where
+        VecRGBA16: Into<Box<[RGBA16]>>,
+    {
+        let pixels = SeaCow::boxed(pixels.into());
+        let width = width.try_into().map_err(|_| ValueOutOfRange)?;
+        let height = height.try_into().map_err(|_| ValueOutOfRange)?;
+        PixelsSource::check_area(&pixels, width, height)?;
+        Self::new_internal(attr, PixelsSource::Rgba16(pixels), width, height, gamma)
+    }
+
+    /// Makes an image from floating-point `[r, g, b, a]` pixels in 0..1 range.
+    ///
+    /// Values outside of 0..1 are clamped, so HDR images need to be tone-mapped first.
+    /// The color channels are in the image's gamma (sRGB for `0.`), not linear light, unless you set `gamma` to `1.`.
+    ///
+    /// Otherwise the same as [`Image::new_rgba16`].
+    pub fn new_rgba_f32<VecF32>(
+        attr: &Attributes,
+        pixels: VecF32,
+        width: usize,
+        height: usize,
+        gamma: f64,
+    ) -> Result<Self, Error>
+    where
+        VecF32: Into<Box<[[f32; 4]]>>,
+    {
+        let pixels = SeaCow::boxed(pixels.into());
+        let width = width.try_into().map_err(|_| ValueOutOfRange)?;
+        let height = height.try_into().map_err(|_| ValueOutOfRange)?;
+        PixelsSource::check_area(&pixels, width, height)?;
+        Self::new_internal(attr, PixelsSource::Float(pixels), width, height, gamma)
+    }
+
     pub(crate) fn free_histogram_inputs(&mut self) {
         // importance_map must stay for remapping, because remap performs kmeans on potentially-unimportant pixels
         self.px.free_histogram_inputs();
@@ -148,18 +198,21 @@ impl<'pixels> Image<'pixels> {
             attr.verbose_print("  error: gamma must be >= 0 and <= 1 (try 1/gamma instead)");
             return Err(ValueOutOfRange);
         }
+        let mut px = DynamicRows::new(
+            width,
+            height,
+            pixels,
+            if gamma > 0. { gamma } else { 0.45455 },
+        );
+        px.grayscale = attr.grayscale;
         let img = Image {
-            px: DynamicRows::new(
-                width,
-                height,
-                pixels,
-                if gamma > 0. { gamma } else { 0.45455 },
-            ),
+            px,
             importance_map: None,
             edges: None,
             dither_map: None,
             background: None,
             fixed_colors: Vec::new(),
+            regions: Vec::new(),
         };
         // if image is huge or converted pixels are not likely to be reused then don't cache converted pixels
         let low_memory_hint = !attr.use_contrast_maps && attr.use_dither_map == DitherMapMode::None;
@@ -174,6 +227,38 @@ impl<'pixels> Image<'pixels> {
         Ok(img)
     }
 
+    /// Image of a band of rows of a larger image, for [`RowSource`](crate::RowSource) streaming. The gamma must be already validated.
+    pub(crate) fn new_band(
+        pixels: &'pixels [RGBA],
+        width: usize,
+        gamma: f64,
+        grayscale: bool,
+    ) -> Result<Self, Error> {
+        let height = pixels.len() / width.max(1);
+        let width = width.try_into().map_err(|_| ValueOutOfRange)?;
+        let height = height.try_into().map_err(|_| ValueOutOfRange)?;
+        if !Self::check_image_size(width, height) {
+            return Err(ValueOutOfRange);
+        }
+        let pixels = PixelsSource::for_pixels(SeaCow::borrowed(pixels), width, height, width)?;
+        let mut px = DynamicRows::new(
+            width,
+            height,
+            pixels,
+            if gamma > 0. { gamma } else { 0.45455 },
+        );
+        px.grayscale = grayscale;
+        Ok(Image {
+            px,
+            importance_map: None,
+            edges: None,
+            dither_map: None,
+            background: None,
+            fixed_colors: Vec::new(),
+            regions: Vec::new(),
+        })
+    }
+
     fn check_image_size(width: u32, height: u32) -> bool {
         if width == 0 || height == 0 {
             return false;
@@ -248,9 +333,48 @@ impl<'pixels> Image<'pixels> {
         Ok(())
     }
 
+    /// Thin high-contrast structures like text get fringed when dithered, so they and their surroundings aren't dithered in [`ContentMode::Text`](crate::ContentMode::Text)
+    pub(crate) fn suppress_dithering_on_edges(&mut self) -> Result<(), Error> {
+        if self.edges.is_none() {
+            self.contrast_maps()?;
+        }
+        let Some(edges) = self.edges.as_deref() else {
+            return Ok(());
+        };
+        let width = self.width();
+        let height = self.height();
+        // spreads edges to neighboring pixels
+        let mut near_edges = try_zero_vec(width * height)?;
+        liq_min3(edges, &mut near_edges, width, height);
+        let dither_map = match self.dither_map.as_deref_mut() {
+            Some(map) => map,
+            None => {
+                let mut map = try_zero_vec(width * height)?;
+                map.copy_from_slice(edges);
+                self.dither_map.get_or_insert(map.into_boxed_slice())
+            }
+        };
+        for (d, &e) in dither_map.iter_mut().zip(near_edges.iter()) {
+            if e < TEXT_EDGE_THRESHOLD {
+                *d = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Anti-aliased edges of text aren't noise, so they shouldn't get as low importance as noise in [`ContentMode::Text`](crate::ContentMode::Text)
+    pub(crate) fn raise_importance_of_edges(&mut self) {
+        if let Some(map) = self.importance_map.as_deref_mut() {
+            map.iter_mut()
+                .for_each(|m| *m = (*m).max(TEXT_MIN_IMPORTANCE));
+        }
+    }
+
     /// Set which pixels are more important (and more likely to get a palette entry)
     ///
     /// The map must be `width`×`height` pixels large. Higher numbers = more important.
+    ///
+    /// It replaces the map that would be generated from the image's noise and edges. To keep that map, use [`Image::add_importance_rect`] and similar instead.
     pub fn set_importance_map(&mut self, map: impl Into<Box<[u8]>>) -> Result<(), Error> {
         let map = map.into();
         if map.len() != self.width() * self.height() {
@@ -260,6 +384,143 @@ impl<'pixels> Image<'pixels> {
         Ok(())
     }
 
+    /// The importance map set with [`Image::set_importance_map`], or generated from noise in the image when it was quantized (255 = flat area, low = noisy).
+    ///
+    /// It can be passed to [`Image::set_importance_map`] of another image with the same pixels to skip analysing them again.
+    #[must_use]
+    pub fn importance_map(&self) -> Option<&[u8]> {
+        self.importance_map.as_deref()
+    }
+
+    /// Map of noise and edges generated when the image was quantized (255 = flat area, low = edge or noise).
+    ///
+    /// The [dither map](Image::dither_map) is made from it. Remapping uses up this map to make the dither map, so it's usually `None` after [`QuantizationResult::remapped`](crate::QuantizationResult::remapped) and similar.
+    #[must_use]
+    pub fn edge_map(&self) -> Option<&[u8]> {
+        self.edges.as_deref()
+    }
+
+    /// Map of how much each pixel is dithered (255 = fully, 0 = not at all), generated when the image was remapped.
+    ///
+    /// It's only generated at speed 6 or slower, for images up to 4 megapixels (or any size at speed 1-2).
+    #[must_use]
+    pub fn dither_map(&self) -> Option<&[u8]> {
+        self.dither_map.as_deref()
+    }
+
+    /// Use a precomputed dither map (e.g. from [`Image::dither_map`] of the same image) instead of generating one when remapping.
+    ///
+    /// The map must be `width`×`height` pixels large. 255 = dither fully, 0 = don't dither. It's ignored at speeds that don't use dither maps (7 and faster).
+    pub fn set_dither_map(&mut self, map: impl Into<Box<[u8]>>) -> Result<(), Error> {
+        let map = map.into();
+        if map.len() != self.width() * self.height() {
+            return Err(BufferTooSmall);
+        }
+        self.dither_map = Some(map);
+        Ok(())
+    }
+
+    /// Make pixels in the rectangle `weight` times as important as they'd be otherwise (e.g. `4.` for faces or text, `0.` to ignore an area).
+    ///
+    /// Unlike [`Image::set_importance_map`], this is combined with the importance of noise and edges detected in the image. Overlapping regions multiply their weights.
+    ///
+    /// The rectangle is clipped to the image. It must be called before the image is quantized.
+    /// Regions are consumed by quantization: they're merged into the importance map (see [`Image::importance_map`]), and aren't applied again if the image is quantized again.
+    pub fn add_importance_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        weight: f32,
+    ) -> Result<(), Error> {
+        self.add_region(Region::rect(x, y, width, height, weight), weight)
+    }
+
+    /// Like [`Image::add_importance_rect`], but for a polygon with vertices at the given `(x, y)` pixel coordinates.
+    ///
+    /// Pixels are inside if their centers are (by the even-odd rule). At least 3 points are needed.
+    pub fn add_importance_polygon(
+        &mut self,
+        points: &[(f32, f32)],
+        weight: f32,
+    ) -> Result<(), Error> {
+        if points.len() < 3
+            || points
+                .iter()
+                .any(|&(x, y)| !x.is_finite() || !y.is_finite())
+        {
+            return Err(ValueOutOfRange);
+        }
+        let mut copy = Vec::new();
+        copy.try_reserve_exact(points.len())?;
+        copy.extend_from_slice(points);
+        self.add_region(Region::polygon(copy.into_boxed_slice(), weight), weight)
+    }
+
+    /// Like [`Image::add_importance_rect`], but for an arbitrary `width`×`height` mask, where 255 means fully in the region, and 0 means not in it.
+    ///
+    /// Partial coverage gets proportionally smaller weight.
+    pub fn add_importance_mask(
+        &mut self,
+        mask: impl Into<Box<[u8]>>,
+        weight: f32,
+    ) -> Result<(), Error> {
+        let mask = mask.into();
+        if mask.len() != self.width() * self.height() {
+            return Err(BufferTooSmall);
+        }
+        self.add_region(Region::mask(mask, weight), weight)
+    }
+
+    fn add_region(&mut self, region: Region, weight: f32) -> Result<(), Error> {
+        if !weight.is_finite() || weight < 0. {
+            return Err(ValueOutOfRange);
+        }
+        self.regions.try_reserve(1)?;
+        self.regions.push(region);
+        Ok(())
+    }
+
+    /// Combines saliency with the importance map, which is made if the image doesn't have one
+    pub(crate) fn apply_saliency(&mut self) -> Result<(), Error> {
+        let len = self.width() * self.height();
+        let map = importance_map_or_default(&mut self.importance_map, len)?;
+        saliency::apply_saliency(&mut self.px, map)
+    }
+
+    /// Combines regions with the importance map, which is made if the image doesn't have one
+    pub(crate) fn apply_importance_regions(&mut self) -> Result<(), Error> {
+        if self.regions.is_empty() {
+            return Ok(());
+        }
+        let width = self.width();
+        let height = self.height();
+        let map = importance_map_or_default(&mut self.importance_map, width * height)?;
+        apply_regions(&self.regions, map, width, height)?;
+        self.regions = Vec::new();
+        Ok(())
+    }
+
+    /// Declare that the pixels have premultiplied alpha, i.e. their color channels have already been multiplied by alpha
+    /// (as used by Cairo, Skia, and GPU readbacks).
+    ///
+    /// Such pixels are converted to the internal color space directly, without rounding them to straight alpha first.
+    /// Fixed colors and background must still use straight alpha.
+    ///
+    /// It must be called before the image is quantized. The default is `false`.
+    /// See [`QuantizationResult::set_output_premultiplied_alpha`](crate::QuantizationResult::set_output_premultiplied_alpha) for the output.
+    pub fn set_premultiplied_alpha(&mut self, premultiplied: bool) -> Result<(), Error> {
+        self.px.set_premultiplied(premultiplied)
+    }
+
+    /// Whether pixels are declared to have premultiplied alpha. See [`Image::set_premultiplied_alpha`]
+    #[must_use]
+    #[inline(always)]
+    pub fn premultiplied_alpha(&self) -> bool {
+        self.px.premultiplied
+    }
+
     /// Remap pixels assuming they will be displayed on this background. This is designed for GIF's "keep" mode.
     ///
     /// Pixels that match the background color will be made transparent if there's a fully transparent color available in the palette.
@@ -288,7 +549,39 @@ impl<'pixels> Image<'pixels> {
             return Err(Unsupported);
         }
         self.fixed_colors.try_reserve(1)?;
-        self.fixed_colors.push_in_cap(color);
+        self.fixed_colors.push_in_cap((color, None));
+        Ok(())
+    }
+
+    /// Like [`Image::add_fixed_color`], but the color is also guaranteed to be at the given `index` of the palette,
+    /// e.g. index 0 = black for a game engine. The rest of the palette is sorted around the pinned colors.
+    ///
+    /// If the palette has fewer colors than `index`, the color is placed as if it wasn't pinned. It takes precedence over [`Attributes::set_last_index_transparent`].
+    /// A color that has already been added is moved to the `index` instead of being added again.
+    pub fn add_fixed_color_at(&mut self, color: RGBA, index: usize) -> Result<(), Error> {
+        if index >= MAX_COLORS
+            || self
+                .fixed_colors
+                .iter()
+                .any(|&(c, i)| i == Some(index as PalLen) && c != color)
+        {
+            return Err(ValueOutOfRange);
+        }
+        // the color may have been added already without the index
+        let mut found = false;
+        for (_, i) in self.fixed_colors.iter_mut().filter(|(c, _)| *c == color) {
+            *i = Some(index as PalLen);
+            found = true;
+        }
+        if found {
+            return Ok(());
+        }
+        if self.fixed_colors.len() >= MAX_COLORS {
+            return Err(Unsupported);
+        }
+        self.fixed_colors.try_reserve(1)?;
+        self.fixed_colors
+            .push_in_cap((color, Some(index as PalLen)));
         Ok(())
     }
 
@@ -318,6 +611,8 @@ impl<'pixels> Image<'pixels> {
     /// Builds two maps:
     ///    `importance_map` - approximation of areas with high-frequency noise, except straight edges. 1=flat, 0=noisy.
     ///    edges - noise map including all edges
+    ///
+    /// An existing `importance_map` is kept (it may have been set by the user, or combined with regions), and only edges are updated.
     pub(crate) fn contrast_maps(&mut self) -> Result<(), Error> {
         let width = self.width();
         let height = self.height();
@@ -325,8 +620,10 @@ impl<'pixels> Image<'pixels> {
             return Ok(()); // shrug
         }
 
-        let noise = if let Some(n) = self.importance_map.as_deref_mut() {
-            n
+        let mut temp_noise;
+        let noise = if self.importance_map.is_some() {
+            temp_noise = try_zero_vec(width * height)?;
+            &mut temp_noise[..]
         } else {
             let vec = try_zero_vec(width * height)?;
             self.importance_map
@@ -466,9 +763,25 @@ impl<'pixels> Image<'pixels> {
     }
 }
 
+/// Pixels with lower values in the edge map are considered to be on an edge
+pub(crate) const TEXT_EDGE_THRESHOLD: u8 = 160;
+/// Flat areas have 255, noise goes down to 80
+const TEXT_MIN_IMPORTANCE: u8 = 176;
+
 fn try_zero_vec(len: usize) -> Result<Vec<u8>, Error> {
     let mut vec = Vec::new();
     vec.try_reserve_exact(len)?;
     vec.resize(len, 0);
     Ok(vec)
 }
+
+/// The importance map, or a new one with all pixels equally important
+fn importance_map_or_default(map: &mut Option<Box<[u8]>>, len: usize) -> Result<&mut [u8], Error> {
+    if map.is_none() {
+        let mut vec = Vec::new();
+        vec.try_reserve_exact(len)?;
+        vec.resize(len, 255);
+        *map = Some(vec.into_boxed_slice());
+    }
+    map.as_deref_mut().ok_or(Error::InternalError)
+}
@@ -2,7 +2,7 @@ use crate::hist::{HistItem, HistogramInternal};
 use crate::nearest::Nearest;
 use crate::pal::{f_pixel, PalF, PalIndex, PalPop};
 use crate::rayoff::*;
-use crate::{CacheLineAlign, Error};
+use crate::{CacheLineAlign, Error, OrdFloat};
 use core::cell::RefCell;
 use rgb::prelude::*;
 use rgb::Argb;
@@ -184,3 +184,37 @@ fn replace_unused_colors(palette: &mut PalF, hist: &HistogramInternal) -> Result
     }
     Ok(())
 }
+
+/// Replaces palette colors with the closest histogram colors that map to them, so the palette has only colors from the image.
+///
+/// After a k-means iteration each color is the average of its colors, and the closest of them has the lowest squared error, so it's their medoid.
+/// Entries that no color maps to get the closest color of the whole image.
+pub(crate) fn snap_to_medoids(palette: &mut PalF, hist: &HistogramInternal) -> Result<(), Error> {
+    let mut closest: Vec<Option<(f32, f_pixel)>> = Vec::new();
+    closest.try_reserve_exact(palette.len())?;
+    closest.resize(palette.len(), None);
+    let n = Nearest::new(palette)?;
+    for item in hist.items.iter() {
+        let (matched, diff) = n.search(&item.color, item.likely_palette_index());
+        let best = &mut closest[matched as usize];
+        if best.map_or(true, |(best_diff, _)| diff < best_diff) {
+            *best = Some((diff, item.color));
+        }
+    }
+
+    for ((color, pop), closest) in palette.iter_mut().zip(closest) {
+        if pop.is_fixed() {
+            continue;
+        }
+        let medoid = closest.map(|(_, c)| c).or_else(|| {
+            hist.items
+                .iter()
+                .map(|item| item.color)
+                .min_by_key(|c| OrdFloat::new(c.diff(color)))
+        });
+        if let Some(medoid) = medoid {
+            *color = medoid;
+        }
+    }
+    Ok(())
+}
@@ -29,19 +29,32 @@ extern crate alloc as std;
 #[cfg(all(not(feature = "std"), feature = "no_std"))]
 use std::vec::Vec;
 
+mod analysis;
 mod attr;
 mod blur;
+mod candidates;
+mod edit;
 mod error;
 mod hist;
 mod image;
 mod kmeans;
 mod mediancut;
+mod merge;
 mod nearest;
+mod neuquant;
+mod octree;
+mod optimal;
+mod order;
 mod pal;
 mod quant;
+mod region;
 mod remap;
 mod rows;
+mod saliency;
 mod seacow;
+mod stream;
+mod tiles;
+mod wu;
 
 #[cfg(not(feature = "threads"))]
 mod rayoff;
@@ -67,20 +80,26 @@ pub mod capi;
 
 use core::cmp::Ordering;
 
-pub use attr::{Attributes, ControlFlow};
+pub use analysis::{ContentProfile, ImageAnalysis};
+pub use attr::{
+    Attributes, ContentMode, ControlFlow, HistogramReduction, PaletteAlgorithm, PaletteOrder,
+};
 
 #[doc(hidden)]
 pub mod _bench {
     //! Internal benchmarking helpers - not part of public API
     pub use crate::blur::{liq_max3, liq_max3_scalar_ref, liq_min3, liq_min3_scalar_ref};
 }
+pub use edit::PaletteMapping;
 pub use error::Error;
 pub use hist::{Histogram, HistogramEntry};
 pub use image::Image;
 #[doc(hidden)]
 pub use pal::Palette;
-pub use pal::RGBA;
+pub use pal::{RGBA, RGBA16};
 pub use quant::QuantizationResult;
+pub use stream::{RowSink, RowSource};
+pub use tiles::TileQuantizationResult;
 
 #[doc(hidden)]
 #[deprecated(note = "Please use the imagequant::Error type. This will be removed")]
@@ -455,6 +474,1133 @@ fn test_fixed_colors() {
     }
 }
 
+/// `width`×`height` test image with pixels from `color(x, y)`
+#[cfg(test)]
+fn test_image(width: usize, height: usize, color: impl Fn(usize, usize) -> RGBA) -> Vec<RGBA> {
+    (0..width * height)
+        .map(|i| color(i % width, i / width))
+        .collect()
+}
+
+#[test]
+fn premultiplied_alpha() {
+    let mut attr = Attributes::new();
+    attr.set_max_colors(16).unwrap();
+    let straight = (0..64u8)
+        .map(|i| RGBA::new(i * 4, 255 - i * 4, 128, i * 4 + 3))
+        .collect::<Vec<_>>();
+    let premul = straight
+        .iter()
+        .map(|px| {
+            let m = |c: u8| ((u16::from(c) * u16::from(px.a) + 127) / 255) as u8;
+            RGBA::new(m(px.r), m(px.g), m(px.b), px.a)
+        })
+        .collect::<Vec<_>>();
+
+    let mut img = attr.new_image_borrowed(&straight, 8, 8, 0.).unwrap();
+    let res = attr.quantize(&mut img).unwrap();
+    let straight_err = res.quantization_error().unwrap();
+
+    let mut img = attr.new_image_borrowed(&premul, 8, 8, 0.).unwrap();
+    img.set_premultiplied_alpha(true).unwrap();
+    assert!(img.premultiplied_alpha());
+    let mut res = attr.quantize(&mut img).unwrap();
+    assert!((res.quantization_error().unwrap() - straight_err).abs() < 1.);
+
+    res.set_output_premultiplied_alpha(true);
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert!(pal.iter().all(|p| p.r <= p.a && p.g <= p.a && p.b <= p.a));
+}
+
+#[test]
+fn high_bit_depth() {
+    let attr = Attributes::new();
+    // all of these colors would be rounded to one or two 8-bit colors
+    let px16 = (0..64u16)
+        .map(|i| RGBA16::new(1000 + i * 4, 30000, 65535 - i * 4, 65535))
+        .collect::<Vec<_>>();
+    let px32 = px16
+        .iter()
+        .map(|px| [px.r, px.g, px.b, px.a].map(|c| f32::from(c) / 65535.))
+        .collect::<Vec<_>>();
+
+    let mut img16 = Image::new_rgba16(&attr, &px16[..], 8, 8, 0.).unwrap();
+    let mut h = Histogram::new(&attr);
+    h.add_image(&attr, &mut img16).unwrap();
+    assert_eq!(64, h.finalize_builder(0.45455).unwrap().items.len());
+
+    let mut posterized = Attributes::new();
+    posterized.set_min_posterization(2).unwrap();
+    let mut h = Histogram::new(&posterized);
+    h.add_image(&posterized, &mut img16).unwrap();
+    assert!(h.finalize_builder(0.45455).unwrap().items.len() <= 2);
+
+    let mut img32 = Image::new_rgba_f32(&attr, px32, 8, 8, 0.).unwrap();
+    let (pal16, idx16) = attr
+        .quantize(&mut img16)
+        .unwrap()
+        .remapped(&mut img16)
+        .unwrap();
+    let (pal32, idx32) = attr
+        .quantize(&mut img32)
+        .unwrap()
+        .remapped(&mut img32)
+        .unwrap();
+    assert_eq!(pal16, pal32);
+    assert_eq!(idx16, idx32);
+
+    assert!(Image::new_rgba16(&attr, &px16[..], 8, 9, 0.).is_err());
+}
+
+#[test]
+fn grayscale() {
+    let mut attr = Attributes::new();
+    attr.set_max_colors(8).unwrap();
+    let px = (0..64 * 64u32)
+        .map(|i| {
+            let v = ((i * 7) % 251) as u8;
+            RGBA::new(v, v / 2, 255 - v, 255)
+        })
+        .collect::<Vec<_>>();
+    let gray = px.iter().map(|&p| pal::to_gray(p)).collect::<Vec<_>>();
+
+    let mut img = attr.new_image_borrowed(&gray, 64, 64, 0.).unwrap();
+    let heuristic_err = attr
+        .quantize(&mut img)
+        .unwrap()
+        .quantization_error()
+        .unwrap();
+
+    attr.set_grayscale(true);
+    assert!(attr.grayscale());
+    let mut img = attr.new_image_borrowed(&gray, 64, 64, 0.).unwrap();
+    let optimal_err = attr
+        .quantize(&mut img)
+        .unwrap()
+        .quantization_error()
+        .unwrap();
+    assert!(
+        optimal_err <= heuristic_err * 1.001,
+        "{optimal_err} {heuristic_err}"
+    );
+
+    // colors are converted to grays
+    let mut img = attr.new_image_borrowed(&px, 64, 64, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    assert!((res.quantization_error().unwrap() - optimal_err).abs() < 0.01);
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert_eq!(8, pal.len());
+    assert!(pal.iter().all(|p| p.r == p.g && p.g == p.b && p.a == 255));
+}
+
+#[test]
+fn optimal_palette_for_gradient() {
+    let mut attr = Attributes::new();
+    attr.set_max_colors(12).unwrap();
+    assert_eq!(PaletteAlgorithm::MedianCut, attr.palette_algorithm());
+    // two channels change together, so it's a line in the internal color space
+    let px = (0..64 * 64u32)
+        .map(|i| {
+            let v = ((i * 13) % 256) as u8;
+            RGBA::new(v, v, 100, 255)
+        })
+        .collect::<Vec<_>>();
+
+    let mut img = attr.new_image_borrowed(&px, 64, 64, 0.).unwrap();
+    let heuristic_err = attr
+        .quantize(&mut img)
+        .unwrap()
+        .quantization_error()
+        .unwrap();
+
+    attr.set_palette_algorithm(PaletteAlgorithm::Optimal);
+    let log = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+    let log2 = log.clone();
+    attr.set_log_callback(move |_, msg| log2.lock().unwrap().push_str(msg));
+    let mut img = attr.new_image_borrowed(&px, 64, 64, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    let optimal_err = res.quantization_error().unwrap();
+    assert!(
+        optimal_err <= heuristic_err * 1.001,
+        "{optimal_err} {heuristic_err}"
+    );
+    assert_eq!(12, res.palette_len());
+    assert!(log.lock().unwrap().contains("principal axis"));
+
+    // an 8-bit UI gradient between two colors is slightly curved by gamma and rounding, but still a line
+    let lerp = |a: u8, b: u8, x: usize| {
+        (f32::from(a) + (f32::from(b) - f32::from(a)) * x as f32 / 255.).round() as u8
+    };
+    let ramp = test_image(256, 16, |x, _| {
+        RGBA::new(lerp(10, 240, x), lerp(40, 180, x), lerp(200, 20, x), 255)
+    });
+    let ramp_err = |attr: &Attributes| {
+        let mut img = attr.new_image_borrowed(&ramp, 256, 16, 0.).unwrap();
+        attr.quantize(&mut img)
+            .unwrap()
+            .quantization_error()
+            .unwrap()
+    };
+    let mut ramp_attr = Attributes::new();
+    ramp_attr.set_max_colors(8).unwrap();
+    let heuristic_err = ramp_err(&ramp_attr);
+    ramp_attr.set_palette_algorithm(PaletteAlgorithm::Optimal);
+    log.lock().unwrap().clear();
+    let log2 = log.clone();
+    ramp_attr.set_log_callback(move |_, msg| log2.lock().unwrap().push_str(msg));
+    let optimal_err = ramp_err(&ramp_attr);
+    assert!(log.lock().unwrap().contains("principal axis"));
+    assert!(optimal_err < heuristic_err, "{optimal_err} {heuristic_err}");
+
+    // not a line
+    let px = (0..64 * 64u32)
+        .map(|i| RGBA::new((i * 13) as u8, (i * 7) as u8, (i / 16) as u8, 255))
+        .collect::<Vec<_>>();
+    let mut img = attr.new_image_borrowed(&px, 64, 64, 0.).unwrap();
+    log.lock().unwrap().clear();
+    assert!(attr
+        .quantize(&mut img)
+        .unwrap()
+        .quantization_error()
+        .is_some());
+    assert!(log.lock().unwrap().contains("not on a line"));
+}
+
+#[test]
+fn streaming() {
+    let (width, height) = (150, 300);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new(x as u8, (y * 255 / height) as u8, ((x ^ y) & 63) as u8, 255)
+    });
+    let error = |pal: &[RGBA], indices: &[u8]| {
+        indices
+            .iter()
+            .zip(&px)
+            .map(|(&i, p)| {
+                let c = pal[i as usize];
+                let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2) as u64;
+                d(c.r, p.r) + d(c.g, p.g) + d(c.b, p.b)
+            })
+            .sum::<u64>() as f64
+            / px.len() as f64
+    };
+
+    let mut attr = Attributes::new();
+    attr.set_max_colors(32).unwrap();
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let (pal, indices) = attr.quantize(&mut img).unwrap().remapped(&mut img).unwrap();
+    let in_memory_error = error(&pal, &indices);
+
+    let next_row = core::cell::Cell::new(0);
+    let mut source = |first_row: usize, rows: &mut [RGBA]| {
+        assert_eq!(next_row.get(), first_row);
+        next_row.set(first_row + rows.len() / width);
+        rows.copy_from_slice(&px[first_row * width..][..rows.len()]);
+        Ok(())
+    };
+    let mut hist = Histogram::new(&attr);
+    hist.add_rows(&attr, &mut source, width, height, 0.)
+        .unwrap();
+    assert_eq!(next_row.get(), height);
+    let mut res = hist.quantize(&attr).unwrap();
+    let pal = res.palette_vec();
+
+    next_row.set(0);
+    let mut indices = Vec::new();
+    res.remap_rows(
+        &mut source,
+        width,
+        height,
+        0.,
+        &mut |first_row: usize, rows: &[u8]| {
+            assert_eq!(indices.len(), first_row * width);
+            indices.extend_from_slice(rows);
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(indices.len(), width * height);
+    assert_eq!(pal, res.palette_vec());
+    let streamed_error = error(&pal, &indices);
+    assert!(
+        streamed_error < in_memory_error * 1.5,
+        "{streamed_error} {in_memory_error}"
+    );
+}
+
+#[test]
+fn add_rows_text() {
+    // within one band, the window is the whole image, so the histogram should be the same as from add_image()
+    let (width, height) = (64, 48);
+    let px = test_image(width, height, |x, y| {
+        let ink = (x / 3 + y / 5) % 4 == 0;
+        let aa = ((x * 37 + y * 11) % 5 * 40) as u8;
+        if ink {
+            RGBA::new(aa, aa / 2, 40, 255)
+        } else {
+            RGBA::new(250, 245 - aa / 8, 230, 255)
+        }
+    });
+    // weights of colors depend on the importance map
+    let counts = |hist: &Histogram| {
+        let mut counts = hist
+            .hashmap
+            .iter()
+            .map(|(&k, &(n, _))| (k, n))
+            .collect::<Vec<_>>();
+        counts.sort_unstable();
+        counts
+    };
+    let mut source = |first_row: usize, rows: &mut [RGBA]| {
+        rows.copy_from_slice(&px[first_row * width..][..rows.len()]);
+        Ok(())
+    };
+
+    // speed 8 doesn't make contrast maps
+    for speed in [4, 8] {
+        let mut attr = Attributes::new();
+        attr.set_speed(speed).unwrap();
+        attr.set_content_mode(ContentMode::Text);
+        let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+        let mut hist = Histogram::new(&attr);
+        hist.add_image(&attr, &mut img).unwrap();
+        let expected = counts(&hist);
+
+        // saliency isn't supported in streams
+        attr.set_saliency(true);
+        let mut hist = Histogram::new(&attr);
+        hist.add_rows(&attr, &mut source, width, height, 0.)
+            .unwrap();
+        assert_eq!(expected, counts(&hist), "{speed}");
+    }
+}
+
+#[test]
+fn remap_into_sink() {
+    let (width, height) = (200, 150);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new(x as u8, y as u8, ((y * width + x) % 7 * 30) as u8, 255)
+    });
+    let error = |pal: &[RGBA], indices: &[u8]| -> u32 {
+        indices
+            .iter()
+            .zip(&px)
+            .map(|(&i, p)| {
+                let c = pal[usize::from(i)];
+                [(c.r, p.r), (c.g, p.g), (c.b, p.b)]
+                    .into_iter()
+                    .map(|(a, b)| u32::from(a.abs_diff(b)).pow(2))
+                    .sum::<u32>()
+            })
+            .sum()
+    };
+
+    for speed in [4, 8] {
+        for dithering in [0., 1.] {
+            let mut attr = Attributes::new();
+            attr.set_max_colors(256).unwrap();
+            attr.set_speed(speed).unwrap();
+            let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+            let mut res = attr.quantize(&mut img).unwrap();
+            res.set_dithering_level(dithering).unwrap();
+            let (pal, expected) = res.clone().remapped(&mut img).unwrap();
+            // the palette can be written out before remapping
+            let written_pal = res.palette_vec();
+
+            // remapped() refines the palette while making the dither map, but it can't change fixed colors
+            let uses_dither_map = speed <= 6 && dithering > 0.;
+            let (pal, expected) = if uses_dither_map {
+                let mut fixed = QuantizationResult::from_palette(&attr, &written_pal, 0.).unwrap();
+                fixed.set_dithering_level(dithering).unwrap();
+                let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+                let (fixed_pal, fixed_indices) = fixed.remapped(&mut img).unwrap();
+                let to_written = fixed_pal
+                    .iter()
+                    .map(|c| written_pal.iter().position(|w| w == c).unwrap() as u8)
+                    .collect::<Vec<_>>();
+                let fixed_indices = fixed_indices
+                    .iter()
+                    .map(|&i| to_written[usize::from(i)])
+                    .collect();
+                (written_pal.clone(), fixed_indices)
+            } else {
+                (pal, expected)
+            };
+            assert_eq!(pal, written_pal, "{speed} {dithering}");
+
+            // images in tests aren't tall enough for more than one band, so bands are also made shorter
+            for band_rows in [None, Some(64)] {
+                let mut sink_res = res.clone();
+                let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+                let mut indices = Vec::new();
+                let mut sink = |first_row: usize, rows: &[u8]| {
+                    assert_eq!(indices.len(), first_row * width);
+                    assert!(rows.len() <= band_rows.unwrap_or(height) * width);
+                    indices.extend_from_slice(rows);
+                    Ok(())
+                };
+                match band_rows {
+                    None => sink_res.remap_into_sink(&mut img, &mut sink),
+                    Some(band_rows) => {
+                        sink_res.write_remapped_image_rows_to_sink(&mut img, &mut sink, band_rows)
+                    }
+                }
+                .unwrap();
+                let case = format!("{speed} {dithering} {band_rows:?}");
+                assert_eq!(written_pal, sink_res.palette_vec(), "{case}");
+                assert_eq!(expected.len(), indices.len(), "{case}");
+
+                if band_rows.is_none() || dithering == 0. || uses_dither_map {
+                    assert_eq!(expected, indices, "{case}");
+                } else {
+                    // error diffusion restarts at every band after a warmup, like in chunks dithered in parallel
+                    let first_band = 64 * width;
+                    assert_eq!(expected[..first_band], indices[..first_band], "{case}");
+                    let (expected_error, error) = (error(&pal, &expected), error(&pal, &indices));
+                    assert!(
+                        error < expected_error + expected_error / 50,
+                        "{case} {error} {expected_error}"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn importance_regions() {
+    let (width, height) = (64, 64);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new((x * 4) as u8, (y * 4) as u8, 128, 255)
+    });
+    let face_error = |img: &mut Image<'_>, attr: &Attributes| {
+        let mut res = attr.quantize(img).unwrap();
+        res.set_dithering_level(0.).unwrap();
+        let (pal, indices) = res.remapped(img).unwrap();
+        (0..16 * 16)
+            .map(|i| {
+                let idx = (40 + i / 16) * width + 40 + i % 16;
+                let (c, p) = (pal[indices[idx] as usize], px[idx]);
+                (i32::from(c.r) - i32::from(p.r)).pow(2) + (i32::from(c.g) - i32::from(p.g)).pow(2)
+            })
+            .sum::<i32>()
+    };
+
+    let mut attr = Attributes::new();
+    attr.set_max_colors(8).unwrap();
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let unweighted = face_error(&mut img, &attr);
+
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    assert!(img
+        .add_importance_polygon(&[(0., 0.), (1., 1.)], 2.)
+        .is_err());
+    assert!(img.add_importance_rect(0, 0, 1, 1, -1.).is_err());
+    img.add_importance_rect(40, 40, 16, 16, 8.).unwrap();
+    img.add_importance_polygon(&[(0., 0.), (64., 0.), (0., 10.)], 0.5)
+        .unwrap();
+    let weighted = face_error(&mut img, &attr);
+    assert!(weighted * 4 < unweighted * 3, "{weighted} {unweighted}");
+    let map = img.importance_map.as_deref().unwrap();
+    assert!(map[45 * width + 45] > map[20 * width + 20]);
+    assert!(map[2 * width + 2] < map[20 * width + 20]);
+
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    img.add_importance_rect(4, 4, usize::MAX, usize::MAX, 4.)
+        .unwrap();
+    attr.quantize(&mut img).unwrap();
+    let map = img.importance_map.as_deref().unwrap();
+    assert!(map[50 * width + 50] > map[2 * width + 2]);
+}
+
+#[test]
+fn analysis_maps() {
+    let (width, height) = (64, 48);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new(
+            (x * 4) as u8,
+            (y * 5) as u8,
+            ((y * width + x) * 13 % 7) as u8,
+            255,
+        )
+    });
+    let mut attr = Attributes::new();
+    attr.set_speed(4).unwrap();
+    attr.set_max_colors(16).unwrap();
+
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    assert!(img.importance_map().is_none() && img.dither_map().is_none());
+    let mut res = attr.quantize(&mut img).unwrap();
+    assert_eq!(width * height, img.importance_map().unwrap().len());
+    assert_eq!(width * height, img.edge_map().unwrap().len());
+    res.set_dithering_level(0.).unwrap();
+    let undithered = res.remapped(&mut img).unwrap();
+
+    res.set_dithering_level(1.).unwrap();
+    let mut res2 = res.clone();
+    res.remapped(&mut img).unwrap();
+    assert_eq!(width * height, img.dither_map().unwrap().len());
+
+    // no dithering anywhere
+    assert!(img.set_dither_map(vec![0; 10]).is_err());
+    img.set_dither_map(vec![0; width * height]).unwrap();
+    assert_eq!(undithered, res2.remapped(&mut img).unwrap());
+}
+
+#[test]
+fn saliency() {
+    let (width, height) = (96, 96);
+    let in_subject = |x: usize, y: usize| (36..60).contains(&x) && (36..60).contains(&y);
+    let px = test_image(width, height, |x, y| {
+        if in_subject(x, y) {
+            RGBA::new((x * 10) as u8, (y * 10) as u8, 255 - (x * 5) as u8, 255)
+        } else {
+            let g = 100 + ((x + y) & 15) as u8;
+            RGBA::new(g, g, g + (y & 7) as u8, 255)
+        }
+    });
+    let subject_error = |attr: &Attributes| {
+        let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+        let mut res = attr.quantize(&mut img).unwrap();
+        res.set_dithering_level(0.).unwrap();
+        let (pal, indices) = res.remapped(&mut img).unwrap();
+        if attr.saliency() {
+            let map = img.importance_map().unwrap();
+            assert!(map[48 * width + 48] > map[5 * width + 5] * 2);
+        }
+        (0..width * height)
+            .filter(|&i| in_subject(i % width, i / width))
+            .map(|i| {
+                let (c, p) = (pal[indices[i] as usize], px[i]);
+                let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+                d(c.r, p.r) + d(c.g, p.g) + d(c.b, p.b)
+            })
+            .sum::<i32>()
+    };
+
+    let mut attr = Attributes::new();
+    attr.set_max_colors(16).unwrap();
+    attr.set_speed(4).unwrap();
+    let without = subject_error(&attr);
+    attr.set_saliency(true);
+    let with = subject_error(&attr);
+    assert!(with < without, "{with} {without}");
+}
+
+#[test]
+fn text_content_mode() {
+    let (width, height) = (120, 80);
+    let background = RGBA::new(250, 250, 245, 255);
+    let brand = RGBA::new(37, 99, 235, 255);
+    let px = test_image(width, height, |x, y| {
+        if y < 12 {
+            return brand;
+        }
+        if y >= 60 {
+            // shading close to the background color
+            let g = 250 - (x % 20) as u8;
+            return RGBA::new(g, g, g - 5, 255);
+        }
+        if x >= 90 && y >= 40 {
+            return RGBA::new((x * 8) as u8, (y * 8) as u8, 128, 255);
+        }
+        // anti-aliased vertical strokes, like glyphs
+        let coverage = [0, 0, 0, 0, 40, 96, 160, 220, 255, 255, 200, 120, 50][x % 13];
+        let mix = |a: u8, b: u8| {
+            ((u32::from(a) * (255 - coverage) + u32::from(b) * coverage) / 255) as u8
+        };
+        RGBA::new(
+            mix(background.r, 20),
+            mix(background.g, 20),
+            mix(background.b, 30),
+            255,
+        )
+    });
+
+    let mut attr = Attributes::new();
+    attr.set_max_colors(6).unwrap();
+    attr.set_speed(8).unwrap();
+    attr.set_content_mode(ContentMode::Auto);
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    let (pal, indices) = res.remapped(&mut img).unwrap();
+    assert!(pal.contains(&background) && pal.contains(&brand), "{pal:?}");
+    // flat areas next to the strokes don't get dithering noise
+    for (&i, p) in indices.iter().zip(&px) {
+        if *p == background || *p == brand {
+            assert_eq!(pal[i as usize], *p);
+        }
+    }
+
+    // finding edges for dithering doesn't replace the importance map
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    img.set_importance_map(vec![200; width * height]).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    res.remapped(&mut img).unwrap();
+    assert!(img.importance_map().unwrap().iter().all(|&m| m == 200));
+
+    // dominant colors are fixed only in the palette made for text, not in the histogram
+    attr.set_content_mode(ContentMode::Text);
+    let mut hist = Histogram::new(&attr);
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    hist.add_image(&attr, &mut img).unwrap();
+    let (pal, _) = hist.quantize(&attr).unwrap().remapped(&mut img).unwrap();
+    assert!(pal.contains(&background) && pal.contains(&brand), "{pal:?}");
+    assert!(hist
+        .finalize_builder(0.45455)
+        .unwrap()
+        .fixed_colors
+        .is_empty());
+}
+
+#[test]
+fn auto_tune() {
+    let (width, height) = (100, 100);
+    let noise = |i: usize, shift: u32| ((i as u32).wrapping_mul(2654435761) >> shift) as u8;
+
+    let photo = (0..width * height)
+        .map(|i| RGBA::new(noise(i, 24) & !7, noise(i, 16) & !7, noise(i, 8) & !7, 255))
+        .collect::<Vec<_>>();
+    let screenshot = (0..width * height)
+        .map(|i| match (i % width) % 10 {
+            0 | 1 => RGBA::new(20, 20, 30, 255),
+            2 => RGBA::new(130, 130, 140, 255),
+            _ if i / width < 10 => RGBA::new(37, 99, 235, 255),
+            _ => RGBA::new(250, 250, 245, 255),
+        })
+        .collect::<Vec<_>>();
+    let icon = test_image(32, 32, |x, y| {
+        if x.abs_diff(16) + y.abs_diff(16) < 12 {
+            RGBA::new(200, (x * 8) as u8, 40, 255)
+        } else {
+            RGBA::new(0, 0, 0, 0)
+        }
+    });
+
+    let mut attr = Attributes::new();
+    let mut img = attr.new_image_borrowed(&photo, width, height, 0.).unwrap();
+    let analysis = attr.auto_tune(&mut img).unwrap();
+    assert_eq!(analysis.profile, ContentProfile::Photo, "{analysis:?}");
+    assert_eq!(analysis.min_posterization, 3);
+    assert_eq!(attr.min_posterization(), 3);
+    assert!(img.importance_map().is_none());
+
+    let mut img = attr
+        .new_image_borrowed(&screenshot, width, height, 0.)
+        .unwrap();
+    let analysis = attr.auto_tune(&mut img).unwrap();
+    assert_eq!(analysis.profile, ContentProfile::Screenshot, "{analysis:?}");
+    assert_eq!(attr.content_mode(), ContentMode::Text);
+    assert_eq!(attr.min_posterization(), 0);
+
+    let mut img = attr.new_image_borrowed(&icon, 32, 32, 0.).unwrap();
+    let analysis = attr.auto_tune(&mut img).unwrap();
+    assert_eq!(analysis.profile, ContentProfile::Icon, "{analysis:?}");
+    assert_eq!(attr.speed(), 1);
+    let mut res = attr.quantize(&mut img).unwrap();
+    res.set_dithering_level(analysis.dithering_level).unwrap();
+    res.remapped(&mut img).unwrap();
+}
+
+#[test]
+fn palette_editing() {
+    let (width, height) = (64, 64);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new((x * 4) as u8, (y * 4) as u8, ((x ^ y) * 2) as u8, 255)
+    });
+    let mut attr = Attributes::new();
+    attr.set_max_colors(32).unwrap();
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    let (pal, mut indices) = res.remapped(&mut img).unwrap();
+    let colors =
+        |pal: &[RGBA], indices: &[u8]| indices.iter().map(|&i| pal[i as usize]).collect::<Vec<_>>();
+    let before = colors(&pal, &indices);
+
+    res.set_palette_index_locked(3, true).unwrap();
+    assert!(res.is_palette_index_locked(3));
+    assert!(res.set_palette_index_locked(99, true).is_err());
+    let luma = |c: RGBA| u32::from(c.r) * 2 + u32::from(c.g) * 5 + u32::from(c.b);
+    let mapping = res.sort_palette_by_key(luma).unwrap();
+    mapping.apply(&mut indices);
+    let pal = res.palette_vec();
+    assert_eq!(
+        pal[3],
+        before[indices.iter().position(|&i| i == 3).unwrap()]
+    );
+    assert_eq!(colors(&pal, &indices), before);
+    let unlocked = pal
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 3)
+        .map(|(_, &c)| luma(c))
+        .collect::<Vec<_>>();
+    assert!(unlocked.windows(2).all(|w| w[0] <= w[1]));
+
+    // later remapping uses the new order
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let (pal2, indices2) = res.remapped(&mut img).unwrap();
+    assert_eq!(pal2.len(), pal.len());
+    assert_eq!(colors(&pal2, &indices2)[..64], before[..64]);
+
+    let used_part = &indices[..width * 8];
+    let mut part = used_part.to_vec();
+    let part_before = colors(&pal2, used_part);
+    let mapping = res.remove_unused_palette_entries(used_part).unwrap();
+    mapping.apply(&mut part);
+    let pal = res.palette_vec();
+    assert!(pal.len() < 32);
+    assert!(res.is_palette_index_locked(mapping.new_index(3).unwrap()));
+    assert_eq!(colors(&pal, &part), part_before);
+
+    let len = pal.len();
+    let mapping = res.merge_similar_palette_entries(2000.).unwrap();
+    assert!(res.palette_len() < len);
+    assert_eq!(res.palette_vec().len(), res.palette_len());
+    mapping.apply(&mut part);
+    assert!(part.iter().all(|&i| usize::from(i) < res.palette_len()));
+}
+
+#[test]
+fn palette_order() {
+    let (width, height) = (64, 64);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new((x * 4) as u8, (y * 3) as u8, 255 - (x * 2) as u8, 255)
+    });
+    let index_jumps = |indices: &[u8]| -> u32 {
+        indices
+            .windows(2)
+            .map(|w| u32::from(w[0].abs_diff(w[1])))
+            .sum()
+    };
+
+    let mut attr = Attributes::new();
+    attr.set_max_colors(24).unwrap();
+    attr.set_palette_order(PaletteOrder::Luminance);
+    assert_eq!(PaletteOrder::Luminance, attr.palette_order());
+    let grays = (0..width * height)
+        .map(|i| {
+            let g = ((i * 7) % 256) as u8;
+            RGBA::new(g, g, g, 255)
+        })
+        .collect::<Vec<_>>();
+    let mut img = attr.new_image_borrowed(&grays, width, height, 0.).unwrap();
+    let pal = attr.quantize(&mut img).unwrap().palette_vec();
+    assert!(pal.windows(2).all(|w| w[0].g < w[1].g), "{pal:?}");
+
+    attr.set_palette_order(PaletteOrder::Popularity);
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    let (pal, mut indices) = res.remapped(&mut img).unwrap();
+    let colors = indices.iter().map(|&i| pal[i as usize]).collect::<Vec<_>>();
+    let mut jumps = index_jumps(&indices);
+    for order in [
+        PaletteOrder::Popularity,
+        PaletteOrder::Hue,
+        PaletteOrder::NearestNeighbor,
+        PaletteOrder::IndexEntropy,
+    ] {
+        let mapping = res.reorder_palette(order, &indices).unwrap();
+        mapping.apply(&mut indices);
+        let pal = res.palette_vec();
+        assert!(indices
+            .iter()
+            .zip(&colors)
+            .all(|(&i, c)| pal[i as usize] == *c));
+        let new_jumps = index_jumps(&indices);
+        if order == PaletteOrder::Popularity {
+            jumps = new_jumps;
+        }
+        if matches!(
+            order,
+            PaletteOrder::NearestNeighbor | PaletteOrder::IndexEntropy
+        ) {
+            assert!(new_jumps < jumps, "{order:?} {new_jumps} {jumps}");
+        }
+    }
+}
+
+/// Size of the remapped image as a PNG, i.e. its filtered index rows after deflate, for every order.
+/// `palette_order` in `benches/simd_bench.rs` prints these sizes for its image.
+#[test]
+fn palette_order_compressed_size() {
+    let (width, height) = (256, 256);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new(x as u8, y as u8, (x ^ y) as u8, 255)
+    });
+    let mut attr = Attributes::new();
+    attr.set_speed(8).unwrap();
+    attr.set_max_colors(256).unwrap();
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let res = attr.quantize(&mut img).unwrap();
+    let (_, indices) = res.clone().remapped(&mut img).unwrap();
+
+    let size = |order| {
+        let mut reordered = indices.clone();
+        res.clone()
+            .reorder_palette(order, &indices)
+            .unwrap()
+            .apply(&mut reordered);
+        lodepng::encode_memory(&reordered, width, height, lodepng::ColorType::GREY, 8)
+            .unwrap()
+            .len()
+    };
+    let popularity = size(PaletteOrder::Popularity);
+    let nearest = size(PaletteOrder::NearestNeighbor);
+    let entropy = size(PaletteOrder::IndexEntropy);
+    assert!(nearest < popularity, "{nearest} {popularity}");
+    assert!(entropy < popularity, "{entropy} {popularity}");
+    // the lowest entropy of differences isn't always the smallest after deflate, but it's close
+    assert!(entropy <= nearest + nearest / 100, "{entropy} {nearest}");
+}
+
+#[test]
+fn pinned_fixed_colors() {
+    let (width, height) = (32, 32);
+    let px = test_image(width, height, |x, y| {
+        if x < 4 {
+            RGBA::new(0, 0, 0, 0)
+        } else {
+            RGBA::new((x * 8) as u8, (y * 8) as u8, 90, 255)
+        }
+    });
+    let black = RGBA::new(0, 0, 0, 255);
+    let white = RGBA::new(255, 255, 255, 255);
+
+    let mut attr = Attributes::new();
+    attr.set_max_colors(20).unwrap();
+    attr.set_last_index_transparent(true);
+    attr.set_palette_order(PaletteOrder::Luminance);
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    img.add_fixed_color(white).unwrap();
+    img.add_fixed_color_at(white, 15).unwrap();
+    img.add_fixed_color_at(white, 15).unwrap();
+    img.add_fixed_color_at(black, 0).unwrap();
+    assert!(img.add_fixed_color_at(white, 0).is_err());
+    assert!(img.add_fixed_color_at(white, 9999).is_err());
+    let mut res = attr.quantize(&mut img).unwrap();
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert_eq!(pal.len(), 20);
+    assert_eq!(pal[0], black);
+    assert_eq!(pal[15], white);
+    assert_eq!(pal[19].a, 0);
+    assert_eq!(pal.iter().filter(|&&c| c == white).count(), 1);
+    assert!(res.is_palette_index_locked(15) && !res.is_palette_index_locked(1));
+    res.reorder_palette(PaletteOrder::Hue, &[]).unwrap();
+    assert_eq!(res.palette()[15], white);
+
+    let mut hist = Histogram::new(&attr);
+    hist.add_fixed_color(white, 0.).unwrap();
+    hist.add_fixed_color_at(white, 0., 3).unwrap();
+    hist.add_fixed_color_at(black, 0., 1).unwrap();
+    assert!(hist.add_fixed_color_at(white, 0., 1).is_err());
+    hist.add_image(
+        &attr,
+        &mut attr.new_image_borrowed(&px, width, height, 0.).unwrap(),
+    )
+    .unwrap();
+    let pal = hist.quantize(&attr).unwrap().palette_vec();
+    assert_eq!(pal[1], black);
+    assert_eq!(pal[3], white);
+    assert_eq!(pal.iter().filter(|&&c| c == white).count(), 1);
+}
+
+#[test]
+fn tiles() {
+    let (width, height) = (64, 36);
+    // left half reds, right half blues, with a gradient in each tile
+    let px = test_image(width, height, |x, y| {
+        let shade = ((x % 8) * 8 + (y % 8) * 4) as u8;
+        if x < 32 {
+            RGBA::new(120 + shade, shade / 2, 20, 255)
+        } else {
+            RGBA::new(10, shade / 2, 120 + shade, 255)
+        }
+    });
+    let mut attr = Attributes::new();
+    attr.set_speed(8).unwrap();
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    img.add_fixed_color_at(RGBA::new(0, 0, 0, 0), 0).unwrap();
+    assert!(attr.quantize_tiles(&mut img, 0, 8, 2, 16).is_err());
+    let mut res = attr.quantize_tiles(&mut img, 8, 8, 2, 16).unwrap();
+
+    // 8×5 tiles, the last row is partial
+    let tile_palettes = res.tile_palettes().to_vec();
+    assert_eq!(tile_palettes.len(), 8 * 5);
+    for (tile, &p) in tile_palettes.iter().enumerate() {
+        assert_eq!(p, tile_palettes[if tile % 8 < 4 { 0 } else { 7 }]);
+    }
+    assert_ne!(tile_palettes[0], tile_palettes[7]);
+
+    res.set_dithering_level(0.).unwrap();
+    let indices = res.remapped(&mut img).unwrap();
+    let palettes = res.palettes();
+    let global = res.global_palette();
+    assert_eq!(global.len(), 32);
+    for p in &palettes {
+        assert!(p.len() <= 16);
+        assert_eq!(p[0], RGBA::new(0, 0, 0, 0));
+    }
+    for (i, (&index, orig)) in indices.iter().zip(&px).enumerate() {
+        let tile = (i / width / 8) * 8 + (i % width) / 8;
+        let p = tile_palettes[tile];
+        let c = palettes[p][usize::from(index)];
+        assert_eq!(c, global[p * 16 + usize::from(index)]);
+        assert!(
+            c.r.abs_diff(orig.r) < 24 && c.b.abs_diff(orig.b) < 24,
+            "{c:?} {orig:?}"
+        );
+    }
+}
+
+#[test]
+fn output_channel_values() {
+    let (width, height) = (64, 64);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new(
+            (x * 4) as u8,
+            (y * 4) as u8,
+            ((x + y) * 2) as u8,
+            if x < 4 { 0 } else { 255 },
+        )
+    });
+    let mut attr = Attributes::new();
+    attr.set_max_colors(256).unwrap();
+    assert!(attr.set_output_bit_depths(5, 6, 5, 0).is_err());
+    assert!(attr
+        .set_output_channel_values(&[0], &[], &[0], &[0])
+        .is_err());
+    assert!(attr.output_channel_values().is_none());
+
+    attr.set_output_bit_depths(5, 6, 5, 1).unwrap();
+    let [r, g, b, a] = attr.output_channel_values().unwrap();
+    assert_eq!((r.len(), g.len(), b.len()), (32, 64, 32));
+    assert_eq!(&r[..3], &[0, 8, 16]);
+    assert_eq!(r[31], 255);
+    assert_eq!(a, [0, 255]);
+
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    res.set_dithering_level(0.).unwrap();
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert!(pal.len() > 100);
+    for c in &pal {
+        assert!(
+            r.contains(&c.r) && g.contains(&c.g) && b.contains(&c.b) && a.contains(&c.a),
+            "{c:?}"
+        );
+    }
+
+    let web_safe = [0, 51, 102, 153, 204, 255];
+    attr.set_output_channel_values(&web_safe, &web_safe, &web_safe, &[255])
+        .unwrap();
+    attr.set_max_colors(32).unwrap();
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    let pal = res.palette_vec();
+    for c in &pal {
+        assert!(
+            web_safe.contains(&c.r) && web_safe.contains(&c.g) && web_safe.contains(&c.b),
+            "{c:?}"
+        );
+        assert_eq!(c.a, 255);
+    }
+
+    attr.set_output_bit_depths(8, 8, 8, 8).unwrap();
+    assert!(attr.output_channel_values().is_none());
+}
+
+#[test]
+fn candidate_colors() {
+    let (width, height) = (64, 64);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new((x * 4) as u8, (y * 4) as u8, 128, 255)
+    });
+    let candidates = (0..1000u32)
+        .map(|i| {
+            RGBA::new(
+                (i * 37 % 256) as u8,
+                (i * 101 % 256) as u8,
+                (i * 7 % 256) as u8,
+                255,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut attr = Attributes::new();
+    attr.set_max_colors(64).unwrap();
+    attr.set_candidate_colors(&candidates).unwrap();
+    assert_eq!(attr.candidate_colors().len(), 1000);
+
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    img.add_fixed_color(RGBA::new(1, 2, 3, 255)).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert!(pal.len() > 16 && pal.len() <= 64, "{}", pal.len());
+    assert!(pal.contains(&RGBA::new(1, 2, 3, 255)));
+    for c in pal.iter().filter(|&&c| c != RGBA::new(1, 2, 3, 255)) {
+        assert!(candidates.contains(c), "{c:?}");
+    }
+
+    // colors aren't copied from the histogram even when it has few of them
+    let few = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255)];
+    attr.set_candidate_colors(&few).unwrap();
+    let mut img = attr.new_image_borrowed(&px[..16], 4, 4, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    for c in res.palette_vec() {
+        assert!(few.contains(&c), "{c:?}");
+    }
+
+    attr.set_candidate_colors(&[]).unwrap();
+    assert!(attr.candidate_colors().is_empty());
+}
+
+#[test]
+fn medoids() {
+    let (width, height) = (64, 64);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new((x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8, 255)
+    });
+    let mut attr = Attributes::new();
+    assert!(!attr.medoids());
+    attr.set_medoids(true);
+    attr.set_max_colors(32).unwrap();
+    attr.set_speed(1).unwrap();
+
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    img.add_fixed_color(RGBA::new(1, 2, 3, 255)).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert_eq!(pal.len(), 32);
+    for c in pal.iter().filter(|&&c| c != RGBA::new(1, 2, 3, 255)) {
+        assert!(px.contains(c), "{c:?}");
+    }
+}
+
+#[test]
+fn pixel_art() {
+    let (width, height) = (96, 96);
+    let sprite_color = |i: usize| RGBA::new((i * 53) as u8, (i * 97) as u8, (i * 31) as u8, 255);
+    let px = test_image(width, height, |x, y| {
+        // a few hundred rare colors sprinkled over 40 flat blocks
+        if (x * 7 + y * 13) % 29 == 0 {
+            let c = sprite_color((x / 8 + y / 8 * 12) % 40);
+            return RGBA::new(c.r ^ (x as u8 & 7), c.g ^ (y as u8 & 7), c.b, 255);
+        }
+        sprite_color((x / 8 + y / 8 * 12) % 40)
+    });
+
+    let mut attr = Attributes::new();
+    attr.set_max_colors(64).unwrap();
+    attr.set_content_mode(ContentMode::PixelArt);
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    assert_eq!(res.dithering_level(), 0.);
+    let (pal, indices) = res.remapped(&mut img).unwrap();
+    assert_eq!(pal.len(), 64);
+    for c in &pal {
+        assert!(px.contains(c), "{c:?}");
+    }
+    for i in 0..40 {
+        assert!(pal.contains(&sprite_color(i)), "{i}");
+    }
+    for (&i, p) in indices.iter().zip(&px) {
+        if pal.contains(p) {
+            assert_eq!(pal[i as usize], *p);
+        }
+    }
+
+    // too many colors for pixel art
+    let gradient = test_image(width, height, |x, y| {
+        RGBA::new((x * 2) as u8, (y * 2) as u8, 0, 255)
+    });
+    let mut img = attr
+        .new_image_borrowed(&gradient, width, height, 0.)
+        .unwrap();
+    let res = attr.quantize(&mut img).unwrap();
+    assert_eq!(res.dithering_level(), 1.);
+}
+
+#[test]
+fn pixel_sampling() {
+    let mut attr = Attributes::new();
+    attr.set_max_colors(32).unwrap();
+    let px = test_image(256, 256, |x, y| {
+        RGBA::new(x as u8, y as u8, ((x * y) / 256) as u8, 255)
+    });
+
+    let mut img = attr.new_image_borrowed(&px, 256, 256, 0.).unwrap();
+    let res = attr.quantize(&mut img).unwrap();
+    assert_eq!(1., res.pixel_sampling_rate());
+    let full_err = res.quantization_error().unwrap();
+
+    assert!(attr.set_pixel_sampling(0., 1).is_err());
+    assert!(attr.set_pixel_sampling(1.5, 1).is_err());
+    attr.set_pixel_sampling(0.05, 1).unwrap();
+    assert_eq!((0.05, 1), attr.pixel_sampling());
+    let mut palettes = Vec::new();
+    for _ in 0..2 {
+        let mut img = attr.new_image_borrowed(&px, 256, 256, 0.).unwrap();
+        let mut res = attr.quantize(&mut img).unwrap();
+        let rate = res.pixel_sampling_rate();
+        assert!((0.045..0.055).contains(&rate), "{rate}");
+        let (palette, _) = res.remapped(&mut img).unwrap();
+        let sampled_err = res.remapping_error().unwrap();
+        assert!(sampled_err < full_err * 1.5, "{sampled_err} {full_err}");
+        palettes.push(palette);
+    }
+    // the same seed picks the same pixels
+    assert_eq!(palettes[0], palettes[1]);
+}
+
+#[test]
+fn adaptive_histogram_reduction() {
+    let mut attr = Attributes::new();
+    attr.set_max_colors(256).unwrap();
+    attr.max_histogram_entries = 2000;
+    // a dense gradient, and a few rare colors far from it
+    let rare = [RGBA::new(255, 0, 255, 255), RGBA::new(0, 255, 255, 200)];
+    let px = test_image(128, 128, |x, y| {
+        let i = y * 128 + x;
+        if i % 4000 == 0 {
+            rare[i / 4000 % 2]
+        } else {
+            RGBA::new(x as u8, y as u8, 100 + ((x + y) % 7) as u8, 255)
+        }
+    });
+
+    let lut = pal::gamma_lut(0.45455);
+    let mut errors = Vec::new();
+    for reduction in [HistogramReduction::Posterize, HistogramReduction::Adaptive] {
+        attr.set_histogram_reduction(reduction);
+        let mut img = attr.new_image_borrowed(&px, 128, 128, 0.).unwrap();
+        let mut hist = Histogram::new(&attr);
+        hist.add_image(&attr, &mut img).unwrap();
+        let items = hist.finalize_builder(0.45455).unwrap().items;
+        if reduction == HistogramReduction::Adaptive {
+            assert!(items.len() <= 2000, "{}", items.len());
+            for c in rare {
+                let c = pal::f_pixel::from_rgba(&lut, c);
+                assert!(items.iter().any(|item| item.color == c));
+            }
+            // merged colors are colors of the image
+            let image_colors = px.iter().copied().collect::<std::collections::HashSet<_>>();
+            assert!(items
+                .iter()
+                .all(|item| image_colors.contains(&item.color.to_rgb(0.45455))));
+        }
+        let mut res = hist.quantize(&attr).unwrap();
+        res.remapped(&mut img).unwrap();
+        errors.push(res.remapping_error().unwrap());
+    }
+    // far fewer colors, but not worse
+    assert!(errors[1] < errors[0] * 1.1, "{errors:?}");
+}
+
 #[cfg(all(not(feature = "std"), feature = "no_std"))]
 pub(crate) mod no_std_compat {
     pub use std::boxed::Box;
@@ -466,6 +1612,7 @@ pub(crate) mod no_std_compat {
         fn powi(self, n: u32) -> Self;
         fn powf(self, e: Self) -> Self;
         fn sqrt(self) -> Self;
+        fn log2(self) -> Self;
     }
 
     impl NoMath for f32 {
@@ -482,6 +1629,9 @@ pub(crate) mod no_std_compat {
         fn sqrt(self) -> Self {
             libm::sqrtf(self)
         }
+        fn log2(self) -> Self {
+            libm::log2f(self)
+        }
     }
 
     impl NoMath for f64 {
@@ -498,5 +1648,8 @@ pub(crate) mod no_std_compat {
         fn sqrt(self) -> Self {
             libm::sqrt(self)
         }
+        fn log2(self) -> Self {
+            libm::log2(self)
+        }
     }
 }
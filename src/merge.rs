@@ -0,0 +1,393 @@
+//! Greedy pairwise merging of histogram colors.
+//!
+//! Pixel art keeps exact colors, and merges the least important ones into others.
+//! The agglomerative quantizer merges the pair of clusters that adds the least error into their average, until the palette is small enough.
+use crate::error::Error;
+use crate::hist::HistogramInternal;
+use crate::nearest::Nearest;
+use crate::pal::{f_pixel, PalF, PalLen, PalPop};
+use crate::OrdFloat;
+use core::cmp::Reverse;
+use rgb::prelude::*;
+use rgb::Argb;
+use std::collections::BinaryHeap;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Images with more distinct colors than this aren't treated as pixel art
+pub(crate) const PIXEL_ART_MAX_COLORS: usize = 1024;
+
+struct Entry {
+    color: f_pixel,
+    weight: f64,
+    is_fixed: bool,
+    alive: bool,
+    /// Entry it'd be merged into, and the error that would add
+    closest: Option<(usize, f64)>,
+}
+
+/// Palette of exact image colors. Returns the palette and its error, like `find_best_palette`.
+///
+/// While there are too many colors, the color that adds the least error when replaced by its closest color is merged into it.
+/// Important colors are never merged into less important ones, so all colors that remain are kept exactly.
+pub(crate) fn pixel_art_palette(
+    hist: &HistogramInternal,
+    max_colors: PalLen,
+) -> Result<(PalF, Option<f64>), Error> {
+    let mut entries = Vec::new();
+    entries.try_reserve_exact(hist.items.len())?;
+    entries.extend(hist.items.iter().map(|item| Entry {
+        color: item.color,
+        weight: f64::from(item.perceptual_weight),
+        is_fixed: hist.fixed_colors.contains(&item.color),
+        alive: true,
+        closest: None,
+    }));
+    for i in 0..entries.len() {
+        entries[i].closest = closest(&entries, i);
+    }
+
+    let mut alive = entries.len();
+    while alive > usize::from(max_colors) {
+        let merged = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.alive)
+            .filter_map(|(i, e)| Some((i, e.closest?)))
+            .min_by_key(|&(_, (_, cost))| OrdFloat::new(cost as f32));
+        let Some((from, (into, _))) = merged else {
+            break; // only fixed colors are left
+        };
+        entries[from].alive = false;
+        let old_weight = entries[into].weight;
+        entries[into].weight += entries[from].weight;
+        alive -= 1;
+
+        // the merged color's cost grew with its weight, colors that were closest to the removed one need a new one,
+        // and colors that were heavier than the merged one can now be merged into it
+        let new_weight = entries[into].weight;
+        for i in 0..entries.len() {
+            if entries[i].alive
+                && (i == into
+                    || entries[i].closest.map_or(false, |(c, _)| c == from)
+                    || (old_weight < entries[i].weight && entries[i].weight <= new_weight))
+            {
+                entries[i].closest = closest(&entries, i);
+            }
+        }
+    }
+
+    let mut palette = PalF::new();
+    for e in entries.iter().filter(|e| e.alive) {
+        palette.push(e.color, PalPop::new(e.weight as f32));
+    }
+    let palette = palette.with_fixed_colors(max_colors, &hist.fixed_colors);
+
+    let n = Nearest::new(&palette)?;
+    let error = hist
+        .items
+        .iter()
+        .map(|item| f64::from(n.search(&item.color, 0).1 * item.perceptual_weight))
+        .sum::<f64>();
+    Ok((
+        palette,
+        Some(error / hist.total_perceptual_weight.max(1e-10)),
+    ))
+}
+
+/// The closest entry at least as important as the `i`th one, which it can be merged into, and the error that'd add.
+///
+/// `None` for fixed colors, which are never merged.
+fn closest(entries: &[Entry], i: usize) -> Option<(usize, f64)> {
+    let entry = &entries[i];
+    if entry.is_fixed {
+        return None;
+    }
+    entries
+        .iter()
+        .enumerate()
+        .filter(|&(j, e)| e.alive && j != i && (e.is_fixed || e.weight >= entry.weight))
+        .map(|(j, e)| (j, entry.color.diff(&e.color)))
+        .min_by_key(|&(_, diff)| OrdFloat::new(diff))
+        .map(|(j, diff)| (j, entry.weight * f64::from(diff)))
+}
+
+/// Cells per channel of the finest grid for finding the closest clusters
+const MAX_GRID_SIZE: usize = 32;
+
+struct Cluster {
+    color: f_pixel,
+    /// Weighted sum of colors
+    sum: Argb<f64>,
+    weight: f64,
+    popularity: f64,
+    /// Cluster it has been merged into, or itself if it's still in the palette
+    parent: u32,
+    /// Incremented when the cluster changes, to skip outdated pairs in the heap
+    version: u32,
+}
+
+/// Clusters by their premultiplied RGB. Difference of colors is at least their squared RGB distance, so far cells can be skipped.
+struct Grid {
+    size: usize,
+    cells: Vec<Vec<u32>>,
+}
+
+impl Grid {
+    /// Colors of images usually lie on surfaces rather than fill the RGB cube, so this aims for a few clusters per used cell
+    fn size_for(clusters: usize) -> usize {
+        ((clusters as f32 / 3.).sqrt() as usize).clamp(2, MAX_GRID_SIZE)
+    }
+
+    fn new(clusters: &[Cluster]) -> Result<Self, Error> {
+        let alive = clusters
+            .iter()
+            .enumerate()
+            .filter(|&(i, c)| c.parent == i as u32);
+        let size = Self::size_for(alive.clone().count());
+        let mut cells = Vec::new();
+        cells.try_reserve_exact(size * size * size)?;
+        cells.resize_with(size * size * size, Vec::new);
+        let mut grid = Self { size, cells };
+        for (i, c) in alive {
+            grid.insert(&c.color, i as u32)?;
+        }
+        Ok(grid)
+    }
+
+    #[inline]
+    fn coords(&self, color: &f_pixel) -> [usize; 3] {
+        [color.r, color.g, color.b].map(|c| ((c * self.size as f32) as usize).min(self.size - 1))
+    }
+
+    #[inline]
+    fn cell_index(&self, [r, g, b]: [usize; 3]) -> usize {
+        (r * self.size + g) * self.size + b
+    }
+
+    fn insert(&mut self, color: &f_pixel, i: u32) -> Result<(), Error> {
+        let idx = self.cell_index(self.coords(color));
+        let cell = &mut self.cells[idx];
+        cell.try_reserve(1)?;
+        cell.push(i);
+        Ok(())
+    }
+
+    fn remove(&mut self, color: &f_pixel, i: u32) {
+        let idx = self.cell_index(self.coords(color));
+        let cell = &mut self.cells[idx];
+        if let Some(pos) = cell.iter().position(|&c| c == i) {
+            cell.swap_remove(pos);
+        }
+    }
+
+    /// The closest other cluster, searching rings of cells around the color until no cell can have a closer one
+    fn closest(&self, clusters: &[Cluster], i: u32) -> Option<(u32, f32)> {
+        let color = &clusters[i as usize].color;
+        let mut best: Option<(u32, f32)> = None;
+        let center = self.coords(color);
+        for ring in 0..self.size {
+            if ring > 0 {
+                // the color can be anywhere in its cell, so cells in the ring are at least ring-1 cells away
+                let min_distance = (ring - 1) as f32 / self.size as f32;
+                if best.map_or(false, |(_, d)| d <= min_distance * min_distance) {
+                    break;
+                }
+            }
+            let range = |c: usize| c.saturating_sub(ring)..=(c + ring).min(self.size - 1);
+            for r in range(center[0]) {
+                for g in range(center[1]) {
+                    let on_ring = r.abs_diff(center[0]) == ring || g.abs_diff(center[1]) == ring;
+                    for b in range(center[2]) {
+                        if !on_ring && b.abs_diff(center[2]) != ring {
+                            continue;
+                        }
+                        for &j in &self.cells[self.cell_index([r, g, b])] {
+                            if j != i {
+                                let diff = color.diff(&clusters[j as usize].color);
+                                if best.map_or(true, |(_, d)| diff < d) {
+                                    best = Some((j, diff));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        best
+    }
+}
+
+/// Error added by replacing colors of both clusters with their weighted average
+#[inline]
+fn merge_cost(a: &Cluster, b: &Cluster, diff: f32) -> f64 {
+    a.weight * b.weight / (a.weight + b.weight).max(1e-10) * f64::from(diff)
+}
+
+/// Heap of the closest pair of each cluster, cheapest first: cost, cluster, its closest cluster, and their versions
+type PairHeap = BinaryHeap<Reverse<(OrdFloat<f64>, u32, u32, u32, u32)>>;
+
+/// Agglomerative clustering of the histogram: starts with every color as a cluster,
+/// and repeatedly merges the pair that adds the least error, until there are at most `max_colors`
+/// and merging more would exceed `target_mse`.
+///
+/// Pairs are made of each cluster and its closest cluster, which is much faster than checking all pairs, and nearly as good.
+pub(crate) fn agglomerative(
+    hist: &mut HistogramInternal,
+    max_colors: PalLen,
+    target_mse: f64,
+) -> Result<PalF, Error> {
+    let mut clusters = Vec::new();
+    clusters.try_reserve_exact(hist.items.len())?;
+    for (i, item) in hist.items.iter().enumerate() {
+        let weight = f64::from(item.adjusted_weight);
+        clusters.push(Cluster {
+            color: item.color,
+            sum: item.color.0.map(|c| f64::from(c) * weight),
+            weight,
+            popularity: f64::from(item.perceptual_weight),
+            parent: i as u32,
+            version: 0,
+        });
+    }
+    let mut grid = Grid::new(&clusters)?;
+
+    let mut alive = clusters.len();
+    let mut heap = PairHeap::new();
+    heap.try_reserve(alive)?;
+    let push = |heap: &mut PairHeap, clusters: &[Cluster], grid: &Grid, i: u32| {
+        if let Some((j, diff)) = grid.closest(clusters, i) {
+            let (a, b) = (&clusters[i as usize], &clusters[j as usize]);
+            let cost = merge_cost(a, b, diff);
+            heap.push(Reverse((OrdFloat::new64(cost), i, j, a.version, b.version)));
+        }
+    };
+    for i in 0..clusters.len() as u32 {
+        push(&mut heap, &clusters, &grid, i);
+    }
+
+    let max_error = target_mse * clusters.iter().map(|c| c.weight).sum::<f64>();
+    let mut error = 0.;
+    while let Some(Reverse((OrdFloat(cost), i, j, i_version, j_version))) = heap.pop() {
+        let (iu, ju) = (i as usize, j as usize);
+        if clusters[iu].parent != i || clusters[iu].version != i_version {
+            continue; // there's a newer pair for this cluster
+        }
+        if clusters[ju].parent != j || clusters[ju].version != j_version {
+            push(&mut heap, &clusters, &grid, i);
+            continue;
+        }
+        if alive <= usize::from(max_colors) && error + cost > max_error {
+            break;
+        }
+        error += cost;
+
+        grid.remove(&clusters[iu].color, i);
+        grid.remove(&clusters[ju].color, j);
+        let merged = &clusters[ju];
+        let (sum, weight, popularity) = (merged.sum, merged.weight, merged.popularity);
+        clusters[ju].parent = i;
+        let c = &mut clusters[iu];
+        c.sum += sum;
+        c.weight += weight;
+        c.popularity += popularity;
+        c.version += 1;
+        if c.weight > 0. {
+            c.color = f_pixel(c.sum.map(|v| (v / c.weight) as f32));
+        }
+        grid.insert(&clusters[iu].color, i)?;
+        alive -= 1;
+        if alive <= 1 {
+            break;
+        }
+        // with fewer clusters, a coarser grid needs fewer empty cells to be searched
+        if Grid::size_for(alive) * 2 <= grid.size {
+            grid = Grid::new(&clusters)?;
+        }
+        push(&mut heap, &clusters, &grid, i);
+    }
+
+    let mut palette = PalF::new();
+    let mut palette_index = Vec::new();
+    palette_index.try_reserve_exact(clusters.len())?;
+    palette_index.resize(clusters.len(), 0u32);
+    for (i, c) in clusters.iter().enumerate() {
+        if c.parent == i as u32 {
+            palette_index[i] = palette.len() as u32;
+            palette.push(c.color, PalPop::new(c.popularity as f32));
+        }
+    }
+    for (i, item) in hist.items.iter_mut().enumerate() {
+        let mut root = i as u32;
+        while clusters[root as usize].parent != root {
+            root = clusters[root as usize].parent;
+        }
+        // shortens chains of merges for the next items
+        let mut next = i as u32;
+        while next != root {
+            next = core::mem::replace(&mut clusters[next as usize].parent, root);
+        }
+        item.tmp = palette_index[root as usize];
+    }
+    Ok(palette)
+}
+
+#[test]
+fn agglomerative_merges_cheapest_pair() {
+    use crate::RGBA;
+    // the light pair is 4 times farther apart, but merging it adds less error than merging the heavy pair
+    let heavy = [RGBA::new(100, 100, 100, 255), RGBA::new(104, 100, 100, 255)];
+    let light = [RGBA::new(200, 50, 50, 255), RGBA::new(200, 66, 50, 255)];
+    // a far, common color keeps weights of the others under the limit of 1/10th of the total
+    let common = (RGBA::new(0, 0, 255, 255), 10000);
+    let colors = [
+        (heavy[0], 100),
+        (heavy[1], 100),
+        (light[0], 1),
+        (light[1], 1),
+        common,
+    ];
+    let mut hist = HistogramInternal::from_colors(&colors);
+    let palette = agglomerative(&mut hist, 4, 0.).unwrap();
+    assert_eq!(palette.len(), 4);
+
+    let lut = crate::pal::gamma_lut(0.45455);
+    let index_of = |hist: &HistogramInternal, c: RGBA| {
+        let c = f_pixel::from_rgba(&lut, c);
+        hist.items.iter().find(|item| item.color == c).unwrap().tmp
+    };
+    assert_ne!(index_of(&hist, heavy[0]), index_of(&hist, heavy[1]));
+    assert_eq!(index_of(&hist, light[0]), index_of(&hist, light[1]));
+    // heavy colors are kept exactly, and the light ones are merged into their average
+    for c in heavy {
+        assert!(palette.as_slice().contains(&f_pixel::from_rgba(&lut, c)));
+    }
+    let merged = palette.as_slice()[index_of(&hist, light[0]) as usize];
+    let [a, b] = light.map(|c| f_pixel::from_rgba(&lut, c));
+    assert!(merged.g > a.g && merged.g < b.g);
+}
+
+#[test]
+fn agglomerative_stops_at_target_mse() {
+    use crate::RGBA;
+    // pairs of nearly the same color, far from each other
+    let colors: Vec<_> = (0..4u8)
+        .flat_map(|i| {
+            [
+                RGBA::new(i * 80, 255 - i * 80, 0, 255),
+                RGBA::new(i * 80 + 1, 255 - i * 80, 0, 255),
+            ]
+        })
+        .map(|c| (c, 10))
+        .collect();
+    let mut hist = HistogramInternal::from_colors(&colors);
+    // merging the pairs is cheap enough, but merging any more isn't
+    let palette = agglomerative(&mut hist, 8, 1e-4).unwrap();
+    assert_eq!(palette.len(), 4);
+
+    // and without the limit it merges into as many as allowed
+    let mut hist = HistogramInternal::from_colors(&colors);
+    let palette = agglomerative(&mut hist, 8, 1.).unwrap();
+    assert_eq!(palette.len(), 1);
+}
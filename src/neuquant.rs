@@ -0,0 +1,251 @@
+//! NeuQuant: Anthony Dekker's quantizer, a self-organizing (Kohonen) neural network.
+//!
+//! Neurons start as a ramp of grays, and are pulled towards colors sampled from the histogram, together with their neighbors
+//! in the network. The pull and the neighborhood shrink as the training goes on. Neurons that win too often are penalized
+//! by a bias, so that all of them get used. It works in the same premultiplied color space as the rest of the library.
+use crate::hist::HistogramInternal;
+use crate::pal::{f_pixel, PalF, PalLen, PalPop};
+use crate::Error;
+use rgb::Argb;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Learning rate and radius are decreased this many times during the training
+const CYCLES: usize = 100;
+/// Colors sampled at sampling factor 1
+const MAX_SAMPLES: usize = 1 << 20;
+/// How quickly frequencies of wins follow the recent ones
+const BETA: f64 = 1. / 1024.;
+/// How much frequent wins are penalized
+const GAMMA: f64 = 1024.;
+
+struct Neuron {
+    /// ARGB
+    color: [f64; 4],
+    /// Estimate of how often it's the closest
+    freq: f64,
+    bias: f64,
+    popularity: f64,
+}
+
+/// Sampling factor of the original NeuQuant, from 1 (every color) to 28 for speed 10. Default speed 4 gives NeuQuant's usual 10.
+fn sampling_factor(speed: u32) -> usize {
+    1 + 3 * speed.saturating_sub(1) as usize
+}
+
+/// Trains a network of `max_colors` neurons on colors of the histogram, sampled by their adjusted weight.
+/// Faster speeds sample fewer colors.
+pub(crate) fn neuquant(
+    hist: &HistogramInternal,
+    max_colors: PalLen,
+    speed: u32,
+) -> Result<PalF, Error> {
+    let mut palette = PalF::new();
+    if hist.items.is_empty() {
+        return Ok(palette);
+    }
+
+    let mut cumulative = Vec::new();
+    cumulative.try_reserve_exact(hist.items.len())?;
+    let mut total = 0.;
+    for item in hist.items.iter() {
+        total += f64::from(item.adjusted_weight);
+        cumulative.push(total);
+    }
+
+    let len = usize::from(max_colors).min(hist.items.len()).max(1);
+    let mut neurons = Vec::new();
+    neurons.try_reserve_exact(len)?;
+    neurons.extend((0..len).map(|i| {
+        let gray = i as f64 / len as f64;
+        Neuron {
+            color: [1., gray, gray, gray],
+            freq: 1. / len as f64,
+            bias: 0.,
+            popularity: 0.,
+        }
+    }));
+
+    let sampling_factor = sampling_factor(speed);
+    let samples = (MAX_SAMPLES / sampling_factor).max(len * CYCLES);
+    let samples_per_cycle = samples / CYCLES;
+    let alpha_decrease = 30. + (sampling_factor - 1) as f64 / 3.;
+    let mut alpha = 1.;
+    let mut radius = (len / 8) as f64;
+    for sample in 0..samples {
+        // evenly spread, deterministic positions in the distribution of weights
+        let position = (sample as f64 * 0.618_033_988_749_895 + 0.5) % 1. * total;
+        let index = cumulative
+            .partition_point(|&w| w <= position)
+            .min(hist.items.len() - 1);
+        let color = hist.items[index].color.0;
+        let color = [color.a, color.r, color.g, color.b].map(f64::from);
+
+        let winner = contest(&mut neurons, &color);
+        move_towards(&mut neurons[winner].color, &color, alpha);
+        let neighbors = radius as usize;
+        if neighbors > 1 {
+            let radius_sq = (neighbors * neighbors) as f64;
+            for distance in 1..neighbors {
+                let pull = alpha * (1. - (distance * distance) as f64 / radius_sq);
+                if let Some(n) = winner.checked_sub(distance) {
+                    move_towards(&mut neurons[n].color, &color, pull);
+                }
+                if let Some(n) = neurons.get_mut(winner + distance) {
+                    move_towards(&mut n.color, &color, pull);
+                }
+            }
+        }
+
+        if (sample + 1) % samples_per_cycle == 0 {
+            alpha -= alpha / alpha_decrease;
+            radius -= radius / 30.;
+        }
+    }
+
+    for n in &neurons {
+        let [a, r, g, b] = n.color.map(|c| c as f32);
+        palette.push(
+            f_pixel(Argb { a, r, g, b }),
+            PalPop::new(n.popularity as f32),
+        );
+    }
+    Ok(palette)
+}
+
+/// Finds the closest neuron, and returns the one that's closest after penalizing frequent winners
+fn contest(neurons: &mut [Neuron], color: &[f64; 4]) -> usize {
+    let mut best = (f64::MAX, 0);
+    let mut best_biased = (f64::MAX, 0);
+    for (i, n) in neurons.iter_mut().enumerate() {
+        // distance in 8-bit units, like in the original, so that the bias has the same scale
+        let dist = n
+            .color
+            .iter()
+            .zip(color)
+            .map(|(a, b)| if a > b { a - b } else { b - a })
+            .sum::<f64>()
+            * 255.;
+        if dist < best.0 {
+            best = (dist, i);
+        }
+        let biased = dist - n.bias;
+        if biased < best_biased.0 {
+            best_biased = (biased, i);
+        }
+        let decay = n.freq * BETA;
+        n.freq -= decay;
+        n.bias += decay * GAMMA;
+    }
+    let n = &mut neurons[best.1];
+    n.freq += BETA;
+    n.bias -= BETA * GAMMA;
+    n.popularity += 1.;
+    best_biased.1
+}
+
+#[inline]
+fn move_towards(neuron: &mut [f64; 4], color: &[f64; 4], amount: f64) {
+    for (n, c) in neuron.iter_mut().zip(color) {
+        *n += amount * (c - *n);
+    }
+}
+
+#[test]
+fn sampling_factor_of_speed() {
+    assert_eq!(sampling_factor(1), 1);
+    assert_eq!(sampling_factor(4), 10);
+    assert_eq!(sampling_factor(10), 28);
+}
+
+#[test]
+fn neurons_converge_on_colors() {
+    use crate::RGBA;
+    let colors = [
+        RGBA::new(255, 0, 0, 255),
+        RGBA::new(0, 255, 0, 255),
+        RGBA::new(0, 0, 255, 255),
+        RGBA::new(255, 255, 0, 255),
+        RGBA::new(0, 0, 0, 128),
+        RGBA::new(200, 200, 200, 255),
+    ];
+    let weighted: Vec<_> = colors
+        .iter()
+        .zip([50, 40, 30, 20, 20, 10])
+        .map(|(&c, w)| (c, w))
+        .collect();
+    let hist = HistogramInternal::from_colors(&weighted);
+
+    let palette = neuquant(&hist, 6, 1).unwrap();
+    assert_eq!(palette.len(), 6);
+    // the bias against frequent winners gives every color its own neuron, even the rare ones
+    for item in hist.items.iter() {
+        let closest = palette
+            .as_slice()
+            .iter()
+            .map(|p| p.diff(&item.color))
+            .fold(f32::MAX, f32::min);
+        assert!(closest < 1e-3, "{closest} {:?}", item.color);
+    }
+    // neurons remember how often they've won
+    assert!(palette.pop_as_slice().iter().all(|p| p.popularity() > 0.));
+
+    // training is deterministic
+    let again = neuquant(&hist, 6, 1).unwrap();
+    assert_eq!(palette.as_slice(), again.as_slice());
+}
+
+#[test]
+fn no_more_neurons_than_colors() {
+    let hist = HistogramInternal::from_colors(&[(crate::RGBA::new(10, 20, 30, 255), 1)]);
+    assert_eq!(neuquant(&hist, 16, 4).unwrap().len(), 1);
+}
+
+#[test]
+fn quantize_with_neuquant() {
+    use crate::{test_image, Attributes, PaletteAlgorithm, RGBA};
+    let mut attr = Attributes::new();
+    attr.set_max_colors(64).unwrap();
+    let px = test_image(128, 128, |x, y| {
+        RGBA::new(
+            (x * 2) as u8,
+            (y * 2) as u8,
+            ((x * y) / 64) as u8,
+            if x < 8 { 128 } else { 255 },
+        )
+    });
+
+    let mut img = attr.new_image_borrowed(&px, 128, 128, 0.).unwrap();
+    let mediancut_err = attr
+        .quantize(&mut img)
+        .unwrap()
+        .quantization_error()
+        .unwrap();
+
+    attr.set_palette_algorithm(PaletteAlgorithm::NeuQuant);
+    let mut errors = Vec::new();
+    for speed in [4, 10] {
+        attr.set_speed(speed).unwrap();
+        let mut img = attr.new_image_borrowed(&px, 128, 128, 0.).unwrap();
+        img.add_fixed_color(RGBA::new(255, 0, 0, 255)).unwrap();
+        let mut res = attr.quantize(&mut img).unwrap();
+        let err = res.quantization_error().unwrap();
+        assert!(err < mediancut_err * 1.5, "{err} {mediancut_err}");
+
+        let (pal, _) = res.remapped(&mut img).unwrap();
+        assert_eq!(64, pal.len());
+        assert!(pal.contains(&RGBA::new(255, 0, 0, 255)));
+        errors.push(err);
+    }
+    // same input, same result
+    let mut img = attr.new_image_borrowed(&px, 128, 128, 0.).unwrap();
+    img.add_fixed_color(RGBA::new(255, 0, 0, 255)).unwrap();
+    assert_eq!(
+        errors[1],
+        attr.quantize(&mut img)
+            .unwrap()
+            .quantization_error()
+            .unwrap()
+    );
+}
@@ -0,0 +1,614 @@
+//! Octree quantizer for the fastest, low-latency mode.
+//!
+//! Colors are counted in a tree, where each level splits every channel (RGBA) in half, so nodes have 16 children.
+//! The lightest nodes of the deepest level are merged into their parents until there are few enough leaves.
+//! Fully transparent pixels are kept apart from the tree, so that they're never merged with faint colors.
+//!
+//! It works on 8-bit pixels, without converting the image to the internal color space. Rows are counted in parallel
+//! into integer sums, and only the cells of the deepest level that have any colors are kept. The cells are mapped
+//! to the palette once, so remapping doesn't search the palette for every pixel.
+use crate::error::Error;
+use crate::hist::HistogramInternal;
+use crate::nearest::Nearest;
+use crate::pal::{
+    f_pixel, gamma_lut, unpremultiply, PalF, PalIndex, PalIndexRemap, PalLen, PalPop, RGBA,
+};
+use crate::rayoff::*;
+use crate::rows::{temp_buf, DynamicRows};
+use crate::seacow::{RowBitmap, RowBitmapMut};
+use crate::CacheLineAlign;
+use core::cell::RefCell;
+use core::ops::Range;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Levels below the root. 4 bits per channel is 65536 cells at the deepest level.
+const DEPTH: usize = 4;
+/// Cells of the deepest level, followed by one for fully transparent pixels
+const CELLS: usize = 1 << (4 * DEPTH);
+/// Index of the cell of fully transparent pixels
+const TRANSPARENT: usize = CELLS;
+
+/// Pixels that can be counted in `u32` sums of 8-bit channels without overflowing them
+const MAX_PIXELS_PER_PASS: usize = (u32::MAX / 255) as usize;
+/// Histogram weights are fractional, so they're counted in units of `1/HISTOGRAM_WEIGHT_SCALE`
+const HISTOGRAM_WEIGHT_SCALE: f64 = 4096.;
+
+/// Count of pixels and sums of their RGBA, in one cell, counted by one thread
+type CellSums = [u32; 5];
+
+#[derive(Clone, Copy, Default)]
+struct Node {
+    /// Weighted sum of RGBA
+    sum: [u64; 4],
+    weight: u64,
+    /// Children that have any colors
+    children: u8,
+    /// Leaves of the reduced tree are the colors of the palette
+    is_leaf: bool,
+}
+
+impl Node {
+    fn new(px: RGBA, weight: u64) -> Self {
+        Self {
+            sum: [px.r, px.g, px.b, px.a].map(|c| u64::from(c) * weight),
+            weight,
+            ..Self::default()
+        }
+    }
+
+    fn from_sums([count, r, g, b, a]: CellSums) -> Self {
+        Self {
+            sum: [r, g, b, a].map(u64::from),
+            weight: count.into(),
+            ..Self::default()
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (sum, c) in self.sum.iter_mut().zip(other.sum) {
+            *sum += c;
+        }
+        self.weight += other.weight;
+    }
+
+    fn color(&self) -> RGBA {
+        let [r, g, b, a] = self
+            .sum
+            .map(|c| ((c + self.weight / 2) / self.weight.max(1)) as u8);
+        RGBA::new(r, g, b, a)
+    }
+}
+
+/// Cell of the deepest level that has any colors
+#[derive(Clone, Copy)]
+struct Cell {
+    index: u32,
+    node: Node,
+}
+
+pub(crate) struct Octree {
+    /// Cells of the deepest level that have any colors, by index. Fully transparent pixels, if any, are the last one.
+    cells: Vec<Cell>,
+    /// Level `l` has `16^l` nodes, indexed by the top `l` bits of the channels, see [`tree_index`]. The deepest level is `cells`.
+    levels: Vec<Box<[Node]>>,
+    /// Popularity of palette entries, per unit of weight
+    popularity_per_weight: f64,
+    gamma: f64,
+    /// Cells mapped to the palette of the quantization result, see [`Octree::map_cells`]
+    mapped: Option<CellPalette>,
+}
+
+/// Palette entries of the cells, see [`Octree::cell_palette`]
+struct CellPalette {
+    /// Colors of the palette the cells were mapped to
+    palette: Vec<f_pixel>,
+    /// Entry closest to the average color of every cell, and its error, or `None` for colors that weren't in the tree
+    cells: Box<[Option<(PalIndex, f32)>]>,
+}
+
+impl Octree {
+    fn new(cells: Vec<Cell>, gamma: f64, popularity_per_weight: f64) -> Result<Self, Error> {
+        let mut levels = Vec::new();
+        levels.try_reserve_exact(DEPTH)?;
+        for level in 0..DEPTH {
+            levels.push(temp_buf(1 << (4 * level))?);
+        }
+        Ok(Self {
+            cells,
+            levels,
+            popularity_per_weight,
+            gamma,
+            mapped: None,
+        })
+    }
+
+    /// Tree of all pixels of the image
+    pub(crate) fn from_image(px: &DynamicRows) -> Result<Self, Error> {
+        let mut tree = Self::new(Vec::new(), px.gamma, 1.)?;
+        // passes are short enough that sums of a cell can't overflow
+        let cols_per_pass = px.width().clamp(1, MAX_PIXELS_PER_PASS);
+        let rows_per_pass = MAX_PIXELS_PER_PASS / cols_per_pass;
+        for first_row in (0..px.height()).step_by(rows_per_pass) {
+            for first_col in (0..px.width()).step_by(cols_per_pass) {
+                tree.count_pixels(
+                    px,
+                    first_row..px.height().min(first_row + rows_per_pass),
+                    first_col..px.width().min(first_col + cols_per_pass),
+                )?;
+            }
+        }
+        Ok(tree)
+    }
+
+    /// Adds pixels of the rows to the cells. Rows are counted in parallel, every thread into its own sums, which are merged afterwards.
+    fn count_pixels(
+        &mut self,
+        px: &DynamicRows,
+        rows: Range<usize>,
+        cols: Range<usize>,
+    ) -> Result<(), Error> {
+        let input_rows = px.rgba_rows_iter()?;
+        let width = px.width();
+        let premultiplied = px.premultiplied;
+
+        let tls = ThreadLocal::new();
+        let per_thread_buffers = move || -> Result<_, Error> {
+            Ok(CacheLineAlign(RefCell::new((
+                temp_buf::<CellSums>(CELLS + 1)?,
+                temp_buf(width)?,
+            ))))
+        };
+        let counted = rows
+            .par_bridge()
+            .map(|row| {
+                #[allow(irrefutable_let_patterns)]
+                let Ok(tls_res) = tls.get_or_try(per_thread_buffers) else {
+                    return false;
+                };
+                let (sums, temp_row) = &mut *tls_res.0.borrow_mut();
+                let input_row = &input_rows.row_rgba(temp_row, row)[cols.clone()];
+                for &color in input_row {
+                    let color = if premultiplied {
+                        unpremultiply(color)
+                    } else {
+                        color
+                    };
+                    let [count, r, g, b, a] = &mut sums[cell_index(color)];
+                    *count += 1;
+                    *r += u32::from(color.r);
+                    *g += u32::from(color.g);
+                    *b += u32::from(color.b);
+                    *a += u32::from(color.a);
+                }
+                true
+            })
+            .all(|ok| ok);
+        if !counted {
+            return Err(Error::OutOfMemory);
+        }
+        for sums in tls {
+            self.add_sums(&sums.0.into_inner().0)?;
+        }
+        Ok(())
+    }
+
+    /// Merges sums counted by a thread into the cells
+    fn add_sums(&mut self, sums: &[CellSums]) -> Result<(), Error> {
+        let used = sums.iter().filter(|s| s[0] > 0).count();
+        let mut cells = Vec::new();
+        cells.try_reserve_exact(self.cells.len() + used)?;
+        let mut old = core::mem::take(&mut self.cells).into_iter().peekable();
+        for (index, &s) in sums.iter().enumerate().filter(|(_, s)| s[0] > 0) {
+            let index = index as u32;
+            while let Some(cell) = old.next_if(|c| c.index < index) {
+                cells.push(cell);
+            }
+            let mut node = Node::from_sums(s);
+            if let Some(cell) = old.next_if(|c| c.index == index) {
+                node.add(&cell.node);
+            }
+            cells.push(Cell { index, node });
+        }
+        cells.extend(old);
+        self.cells = cells;
+        Ok(())
+    }
+
+    /// Tree of the colors of the histogram
+    pub(crate) fn from_histogram(hist: &HistogramInternal, gamma: f64) -> Result<Self, Error> {
+        let colors = hist.items.iter().map(|item| {
+            let weight = f64::from(item.adjusted_weight).mul_add(HISTOGRAM_WEIGHT_SCALE, 0.5);
+            (item.color.to_rgb(gamma), weight as u64)
+        });
+        Self::from_colors(colors, gamma, 1. / HISTOGRAM_WEIGHT_SCALE)
+    }
+
+    /// Tree of colors with integer weights
+    fn from_colors(
+        colors: impl ExactSizeIterator<Item = (RGBA, u64)>,
+        gamma: f64,
+        popularity_per_weight: f64,
+    ) -> Result<Self, Error> {
+        let mut cells = Vec::new();
+        cells.try_reserve_exact(colors.len())?;
+        cells.extend(
+            colors
+                .filter(|&(_, weight)| weight > 0)
+                .map(|(color, weight)| Cell {
+                    index: cell_index(color) as u32,
+                    node: Node::new(color, weight),
+                }),
+        );
+        cells.sort_unstable_by_key(|c| c.index);
+        cells.dedup_by(|cell, prev| {
+            let same = cell.index == prev.index;
+            if same {
+                prev.node.add(&cell.node);
+            }
+            same
+        });
+        Self::new(cells, gamma, popularity_per_weight)
+    }
+
+    /// Number of cells of the deepest level that have any colors, counting transparent pixels as one
+    pub(crate) fn used_cells(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Merges the lightest nodes into their parents until there are at most `max_colors` leaves,
+    /// and returns the palette of the leaves. Transparent pixels count as a leaf, unless the palette has only one color.
+    pub(crate) fn reduce(&mut self, max_colors: PalLen) -> Result<PalF, Error> {
+        let (transparent, cells) = match self.cells.split_last() {
+            Some((last, cells)) if last.index as usize == TRANSPARENT => (Some(last.node), cells),
+            _ => (None, &self.cells[..]),
+        };
+
+        for level in self.levels.iter_mut() {
+            level.fill(Node::default());
+        }
+        for cell in cells {
+            let parent = &mut self.levels[DEPTH - 1][tree_index(cell.index as usize) >> 4];
+            parent.add(&cell.node);
+            parent.children += 1;
+        }
+        for level in (1..DEPTH).rev() {
+            let (parents, children) = self.levels.split_at_mut(level);
+            for (i, child) in children[0].iter().enumerate().filter(|(_, n)| n.weight > 0) {
+                let parent = &mut parents[level - 1][i >> 4];
+                parent.add(child);
+                parent.children += 1;
+            }
+        }
+
+        let transparent = transparent.filter(|_| max_colors > 1);
+        let max_colors = usize::from(max_colors) - usize::from(transparent.is_some());
+        let mut leaves = cells.len();
+        for nodes in self.levels.iter_mut().rev() {
+            if leaves <= max_colors {
+                break;
+            }
+            let mut by_weight = Vec::new();
+            by_weight.try_reserve_exact(nodes.len())?;
+            by_weight.extend((0..nodes.len()).filter(|&i| nodes[i].weight > 0));
+            by_weight.sort_unstable_by_key(|&i| nodes[i].weight);
+
+            // all nodes of the level below are leaves at this point
+            for i in by_weight {
+                nodes[i].is_leaf = true;
+                leaves -= usize::from(nodes[i].children) - 1;
+                if leaves <= max_colors {
+                    break;
+                }
+            }
+        }
+
+        let lut = gamma_lut(self.gamma);
+        let mut palette = PalF::new();
+        let mut push = |node: &Node| {
+            let popularity = node.weight as f64 * self.popularity_per_weight;
+            palette.push(
+                f_pixel::from_rgba(&lut, node.color()),
+                PalPop::new(popularity as f32),
+            );
+        };
+        if let Some(node) = &transparent {
+            push(node);
+        }
+        // a leaf merged into its parent is covered by it
+        for (level, nodes) in self.levels.iter().enumerate() {
+            for (i, node) in nodes.iter().enumerate() {
+                if node.is_leaf && (level == 0 || !self.levels[level - 1][i >> 4].is_leaf) {
+                    push(node);
+                }
+            }
+        }
+        for cell in cells {
+            if !self.levels[DEPTH - 1][tree_index(cell.index as usize) >> 4].is_leaf {
+                push(&cell.node);
+            }
+        }
+        Ok(palette)
+    }
+
+    /// Palette entry closest to the average color of every cell, and its error.
+    /// The palette may have been sorted or edited since the tree was made.
+    fn cell_palette(&self, palette: &PalF) -> Result<CellPalette, Error> {
+        let n = Nearest::new(palette)?;
+        let lut = gamma_lut(self.gamma);
+        let mut cells = temp_buf(CELLS + 1)?;
+        let mut last_match = 0;
+        for cell in &self.cells {
+            let found = n.search(&f_pixel::from_rgba(&lut, cell.node.color()), last_match);
+            last_match = found.0;
+            cells[cell.index as usize] = Some(found);
+        }
+        let mut colors = Vec::new();
+        colors.try_reserve_exact(palette.len())?;
+        colors.extend_from_slice(palette.as_slice());
+        Ok(CellPalette {
+            palette: colors,
+            cells,
+        })
+    }
+
+    /// Maps the cells to the palette of the result once, so that [`Octree::remap`] doesn't have to.
+    /// Returns an estimate of the error of the palette for the colors of the tree, from the averages of the cells.
+    pub(crate) fn map_cells(&mut self, palette: &PalF) -> Result<f64, Error> {
+        let mapped = self.cell_palette(palette)?;
+        let (error, weight) = self.cells.iter().fold((0., 0.), |(error, weight), cell| {
+            let cell_error = mapped.cells[cell.index as usize].map_or(0., |(_, e)| f64::from(e));
+            let cell_weight = cell.node.weight as f64;
+            (error + cell_error * cell_weight, weight + cell_weight)
+        });
+        self.mapped = Some(mapped);
+        Ok(error / weight.max(1e-10))
+    }
+
+    /// Remaps 8-bit pixels using the cells, rows in parallel. Colors that weren't in the tree are searched in the palette.
+    ///
+    /// The error is estimated from the averages of the cells.
+    pub(crate) fn remap<'x, 'b: 'x>(
+        &self,
+        px: &DynamicRows,
+        first_row: usize,
+        output_pixels: &'x mut RowBitmapMut<'b, PalIndexRemap>,
+        palette: &PalF,
+    ) -> Result<(f64, RowBitmap<'x, PalIndexRemap>), Error> {
+        let rows = px.rgba_rows_iter()?;
+        let n = Nearest::new(palette)?;
+        let fresh;
+        let cells = match &self.mapped {
+            Some(mapped) if mapped.palette[..] == *palette.as_slice() => &mapped.cells,
+            _ => {
+                fresh = self.cell_palette(palette)?;
+                &fresh.cells
+            }
+        };
+        let lut = gamma_lut(px.gamma);
+        let width = px.width();
+        let premultiplied = px.premultiplied;
+
+        let tls = ThreadLocal::new();
+        let per_thread_buffers =
+            move || -> Result<_, Error> { Ok(CacheLineAlign(RefCell::new(temp_buf(width)?))) };
+        let remapping_error = output_pixels
+            .rows_mut()
+            .enumerate()
+            .par_bridge()
+            .map(|(row, output_row)| {
+                #[allow(irrefutable_let_patterns)]
+                let Ok(tls_res) = tls.get_or_try(per_thread_buffers) else {
+                    return f64::NAN;
+                };
+                let temp_row = &mut *tls_res.0.borrow_mut();
+                let mut row_error = 0.;
+                let input_row = rows.row_rgba(temp_row, first_row + row);
+                for (&color, out) in input_row.iter().zip(&mut output_row[..width]) {
+                    let color = if premultiplied {
+                        unpremultiply(color)
+                    } else {
+                        color
+                    };
+                    let (matched, diff) = match cells[cell_index(color)] {
+                        Some(cell) => cell,
+                        None => n.search(&f_pixel::from_rgba(&lut, color), 0),
+                    };
+                    *out = matched as PalIndexRemap;
+                    row_error += diff;
+                }
+                f64::from(row_error)
+            })
+            .sum::<f64>();
+        if remapping_error.is_nan() {
+            return Err(Error::OutOfMemory);
+        }
+        let remapping_error = remapping_error / (width * output_pixels.len().max(1)) as f64;
+        Ok((remapping_error, output_pixels.as_init()))
+    }
+}
+
+/// Index of the cell of the deepest level: the top bits of R, G, B and A, one after another.
+///
+/// Fully transparent pixels have their own cell, [`TRANSPARENT`], instead of sharing one with nearly transparent dark colors.
+#[inline(always)]
+fn cell_index(px: RGBA) -> usize {
+    const _: () = assert!(DEPTH == 4);
+    let nibbles = u32::from_be_bytes([px.r, px.g, px.b, px.a]) >> 4 & 0x0F0F_0F0F;
+    let nibbles = (nibbles | nibbles >> 4) & 0x00FF_00FF;
+    let index = (nibbles | nibbles >> 8) & 0xFFFF;
+    if px.a == 0 {
+        TRANSPARENT
+    } else {
+        index as usize
+    }
+}
+
+/// Index of a cell in the tree, made of interleaved bits of the channels, starting from the top ones, so that `>> 4` gives its parent
+fn tree_index(cell_index: usize) -> usize {
+    let [r, g, b, a] = [12, 8, 4, 0].map(|shift| cell_index >> shift & 0xF);
+    let mut index = 0;
+    for bit in (0..DEPTH).rev() {
+        let child =
+            (r >> bit & 1) << 3 | (g >> bit & 1) << 2 | (b >> bit & 1) << 1 | (a >> bit & 1);
+        index = index << 4 | child;
+    }
+    index
+}
+
+#[test]
+fn reduction_keeps_most_populous_leaves() {
+    let common = [
+        RGBA::new(0, 0, 0, 255),
+        RGBA::new(255, 0, 0, 255),
+        RGBA::new(0, 255, 0, 255),
+        RGBA::new(0, 0, 255, 255),
+    ];
+    // rare colors differ only in the lowest bit of the tree, so they're children of the same node
+    let rare = (0..8u8).map(|i| {
+        let c = |bit: u8| 128 + (i >> bit & 1) * 16;
+        RGBA::new(c(0), c(1), c(2), 255)
+    });
+    let colors: Vec<_> = common
+        .iter()
+        .map(|&c| (c, 100))
+        .chain(rare.map(|c| (c, 1)))
+        .collect();
+    let mut tree = Octree::from_colors(colors.into_iter(), 0.45455, 1.).unwrap();
+    assert_eq!(tree.used_cells(), 12);
+
+    let palette = tree.reduce(5).unwrap();
+    assert_eq!(palette.len(), 5);
+    let lut = gamma_lut(0.45455);
+    for c in common {
+        assert!(palette.as_slice().contains(&f_pixel::from_rgba(&lut, c)));
+    }
+    // the rare colors are merged into their average
+    let merged = f_pixel::from_rgba(&lut, RGBA::new(136, 136, 136, 255));
+    assert!(palette.as_slice().iter().any(|c| c.diff(&merged) < 1e-4));
+}
+
+#[test]
+fn transparent_pixels_have_own_leaf() {
+    // nearly transparent dark pixels have all bits of the tree zero, like transparent ones used to
+    let faint = RGBA::new(8, 4, 12, 10);
+    let colors = [
+        (RGBA::new(0, 0, 0, 0), 100),
+        (RGBA::new(255, 255, 255, 0), 100),
+        (faint, 10),
+        (RGBA::new(200, 100, 50, 255), 50),
+    ];
+    let mut tree = Octree::from_colors(colors.into_iter(), 0.45455, 1.).unwrap();
+    assert_eq!(tree.used_cells(), 3);
+
+    let palette = tree.reduce(3).unwrap();
+    assert_eq!(palette.len(), 3);
+    let lut = gamma_lut(0.45455);
+    assert!(palette.as_slice().contains(&f_pixel::default()));
+    assert!(palette
+        .as_slice()
+        .contains(&f_pixel::from_rgba(&lut, faint)));
+
+    let cells = tree.cell_palette(&palette).unwrap().cells;
+    let transparent = cells[cell_index(RGBA::new(1, 2, 3, 0))].unwrap().0;
+    assert!(palette.as_slice()[usize::from(transparent)].is_fully_transparent());
+    assert_ne!(cells[cell_index(faint)].unwrap().0, transparent);
+
+    // with one color there's no room for a separate transparent entry
+    assert_eq!(tree.reduce(1).unwrap().len(), 1);
+}
+
+#[test]
+fn image_is_counted_in_passes() {
+    let (width, height) = (300, 200);
+    let px: Vec<_> = (0..width * height)
+        .map(|i| {
+            RGBA::new(
+                (i % width) as u8,
+                (i / width) as u8,
+                7,
+                if i % 7 == 0 { 0 } else { 255 },
+            )
+        })
+        .collect();
+    let attr = crate::Attributes::new();
+    let img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let rows = &img.px;
+    let whole = Octree::from_image(rows).unwrap();
+    // the same as counting a few rows or parts of rows at a time
+    let mut tree = Octree::new(Vec::new(), 0.45455, 1.).unwrap();
+    for first_row in (0..height).step_by(30) {
+        for first_col in (0..width).step_by(100) {
+            tree.count_pixels(
+                rows,
+                first_row..height.min(first_row + 30),
+                first_col..first_col + 100,
+            )
+            .unwrap();
+        }
+    }
+    assert_eq!(whole.used_cells(), tree.used_cells());
+    for (a, b) in whole.cells.iter().zip(&tree.cells) {
+        assert_eq!(a.index, b.index);
+        assert_eq!((a.node.sum, a.node.weight), (b.node.sum, b.node.weight));
+    }
+    let pixels: u64 = whole.cells.iter().map(|c| c.node.weight).sum();
+    assert_eq!(pixels, (width * height) as u64);
+}
+
+#[test]
+fn quantize_with_octree() {
+    use crate::{test_image, Attributes, Histogram, PaletteAlgorithm};
+    let (width, height) = (256, 128);
+    let px = test_image(width, height, |x, y| {
+        RGBA::new(
+            x as u8,
+            (y * 2) as u8,
+            ((x ^ y) & 0xF0) as u8,
+            if x < 16 { 0 } else { 255 },
+        )
+    });
+    let mut attr = Attributes::new();
+    attr.set_max_colors(64).unwrap();
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    res.set_dithering_level(0.).unwrap();
+    res.remapped(&mut img).unwrap();
+    let mediancut_err = res.remapping_error().unwrap();
+
+    attr.set_palette_algorithm(PaletteAlgorithm::Octree);
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    img.add_fixed_color(RGBA::new(255, 0, 0, 255)).unwrap();
+    let mut res = attr.quantize(&mut img).unwrap();
+    assert_eq!(res.dithering_level(), 0.);
+    let (pal, indices) = res.remapped(&mut img).unwrap();
+    assert!(pal.len() > 32 && pal.len() <= 64, "{}", pal.len());
+    assert!(pal.contains(&RGBA::new(255, 0, 0, 255)));
+    let octree_err = res.remapping_error().unwrap();
+    assert!(
+        octree_err < mediancut_err * 3.,
+        "{octree_err} {mediancut_err}"
+    );
+    // transparent pixels get a transparent color
+    assert_eq!(pal[indices[0] as usize].a, 0);
+
+    // the same result can remap other images
+    let other = vec![RGBA::new(10, 200, 30, 255); 64];
+    let mut img = attr.new_image_borrowed(&other, 8, 8, 0.).unwrap();
+    let (pal, indices) = res.remapped(&mut img).unwrap();
+    let c = pal[indices[0] as usize];
+    assert!(c.a == 255 && c.g > 128, "{c:?}");
+
+    // the histogram uses the octree instead of median cut
+    let mut hist = Histogram::new(&attr);
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    hist.add_image(&attr, &mut img).unwrap();
+    let mut res = hist.quantize(&attr).unwrap();
+    assert!(res.palette().len() > 32);
+
+    // importance regions need the histogram
+    let mut img = attr.new_image_borrowed(&px, width, height, 0.).unwrap();
+    img.add_importance_rect(0, 0, 64, 64, 4.).unwrap();
+    assert!(attr.quantize(&mut img).unwrap().octree.is_none());
+}
@@ -0,0 +1,365 @@
+//! Optimal palette for colors that lie on a line (e.g. grays), using dynamic programming.
+//!
+//! In one dimension the best partition into `k` groups is always contiguous, so it can be found exactly,
+//! unlike in the RGBA space where mediancut and k-means are only heuristics.
+use crate::hist::{HistItem, HistogramInternal};
+use crate::pal::{f_pixel, PalF, PalLen, PalPop, ARGBF};
+use crate::{Error, OrdFloat};
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Larger histograms are grouped into this many bins. The cost is O(colors × bins × log(bins))
+const MAX_BINS: usize = 4096;
+
+/// Colors up to about 3 steps of 8-bit color (RMS) from the principal axis still count as a line, regardless of the quality target.
+/// Gamma makes gradients between colors slightly curved, and rounding and noise add more.
+const MAX_OFF_AXIS_MSE: f64 = (3. / 255.) * (3. / 255.);
+
+/// Palette for a histogram in which all colors are gray and have the same alpha.
+///
+/// Returns `None` if the histogram has other colors. The error is the same kind as returned by `Kmeans`.
+pub(crate) fn gray_palette(
+    hist: &mut HistogramInternal,
+    max_colors: PalLen,
+    target_mse: f64,
+) -> Result<Option<(PalF, f64)>, Error> {
+    let Some(alpha) = hist.items.first().map(|item| item.color.a) else {
+        return Ok(None);
+    };
+    let mut positions = Vec::new();
+    positions.try_reserve_exact(hist.items.len())?;
+    for (i, item) in hist.items.iter().enumerate() {
+        if (item.color.a - alpha).abs() > 1. / 4096. {
+            return Ok(None);
+        }
+        let Some(pos) = item.color.gray_axis_position() else {
+            return Ok(None);
+        };
+        positions.push((pos, i as u32));
+    }
+    let total = hist.total_perceptual_weight;
+    line_palette(&mut hist.items, positions, max_colors, target_mse * total)
+        .map(|(palette, error)| Some((palette, if total > 0. { error / total } else { 0. })))
+}
+
+/// Palette for a histogram in which all colors are close to a line (the weighted principal axis).
+///
+/// Returns `None` if colors deviate from the line by more than [`MAX_OFF_AXIS_MSE`].
+/// The returned error is only for positions along the line, so it has to be measured again.
+pub(crate) fn principal_axis_palette(
+    hist: &mut HistogramInternal,
+    max_colors: PalLen,
+    target_mse: f64,
+) -> Result<Option<PalF>, Error> {
+    let total = hist.total_perceptual_weight;
+    if hist.items.is_empty() || total <= 0. {
+        return Ok(None);
+    }
+    let channels = |c: f_pixel| [c.a, c.r, c.g, c.b].map(f64::from);
+
+    let mut mean = [0.; 4];
+    for item in hist.items.iter() {
+        let w = f64::from(item.perceptual_weight);
+        mean.iter_mut()
+            .zip(channels(item.color))
+            .for_each(|(m, c)| *m += c * w);
+    }
+    mean.iter_mut().for_each(|m| *m /= total);
+
+    let mut covariance = [[0.; 4]; 4];
+    for item in hist.items.iter() {
+        let w = f64::from(item.perceptual_weight);
+        let d = channels(item.color);
+        for (row, di) in covariance
+            .iter_mut()
+            .zip(d.iter().zip(mean).map(|(c, m)| c - m))
+        {
+            for (cov, dj) in row.iter_mut().zip(d.iter().zip(mean).map(|(c, m)| c - m)) {
+                *cov += w * di * dj;
+            }
+        }
+    }
+
+    // power iteration converges quickly, since for line-like colors one eigenvalue dominates
+    let mut axis = [0.5; 4];
+    for _ in 0..32 {
+        let next = covariance.map(|row| row.iter().zip(axis).map(|(c, a)| c * a).sum::<f64>());
+        let len = next.iter().map(|c| c * c).sum::<f64>().sqrt();
+        if len <= 0. {
+            break;
+        }
+        axis = next.map(|c| c / len);
+    }
+
+    let total_variance: f64 = (0..4).map(|i| covariance[i][i]).sum();
+    let axis_variance: f64 = covariance
+        .iter()
+        .zip(axis)
+        .map(|(row, ai)| ai * row.iter().zip(axis).map(|(c, aj)| c * aj).sum::<f64>())
+        .sum();
+    let off_axis_mse = (total_variance - axis_variance).max(0.) / total;
+    if off_axis_mse > MAX_OFF_AXIS_MSE {
+        return Ok(None);
+    }
+
+    let mut positions = Vec::new();
+    positions.try_reserve_exact(hist.items.len())?;
+    positions.extend(hist.items.iter().enumerate().map(|(i, item)| {
+        let pos: f64 = channels(item.color)
+            .iter()
+            .zip(mean)
+            .zip(axis)
+            .map(|((c, m), a)| (c - m) * a)
+            .sum();
+        (pos as f32, i as u32)
+    }));
+    let max_error = (target_mse - off_axis_mse).max(0.) * total;
+    line_palette(&mut hist.items, positions, max_colors, max_error)
+        .map(|(palette, _)| Some(palette))
+}
+
+/// `positions` are `(position on the line, index in items)`, and the distance along the line must be the color difference.
+///
+/// Sets palette indices in the items' `tmp`. Returns the palette and sum of weighted squared errors.
+pub(crate) fn line_palette(
+    items: &mut [HistItem],
+    mut positions: Vec<(f32, u32)>,
+    max_colors: PalLen,
+    max_error: f64,
+) -> Result<(PalF, f64), Error> {
+    positions.sort_unstable_by_key(|&(pos, _)| OrdFloat::new(pos));
+
+    let (Some(&(min, _)), Some(&(max, _))) = (positions.first(), positions.last()) else {
+        return Ok((PalF::new(), 0.));
+    };
+    let distinct = 1 + positions.windows(2).filter(|w| w[0].0 != w[1].0).count();
+    let bucket_scale = (MAX_BINS - 1) as f32 / (max - min).max(f32::MIN_POSITIVE);
+    let bin_key = move |pos: f32| {
+        if distinct <= MAX_BINS {
+            pos.to_bits()
+        } else {
+            ((pos - min) * bucket_scale) as u32
+        }
+    };
+
+    // bin of each sorted position, and prefix sums of the bins
+    let mut bins = Vec::new();
+    bins.try_reserve_exact(positions.len())?;
+    let mut sums = PrefixSums::with_capacity(distinct.min(MAX_BINS) + 1)?;
+    let mut last_key = None;
+    for &(pos, idx) in &positions {
+        let key = bin_key(pos);
+        if last_key != Some(key) {
+            last_key = Some(key);
+            sums.push_bin();
+        }
+        bins.push(sums.len() - 1);
+        let w = f64::from(items[idx as usize].perceptual_weight).max(1e-9);
+        sums.add(f64::from(pos), w);
+    }
+
+    let (ends, error) = partition(&sums, max_colors as usize, max_error)?;
+
+    let mut palette = PalF::new();
+    let mut group_start = 0;
+    let mut sorted = positions.iter().zip(&bins).peekable();
+    for (group, &end) in ends.iter().enumerate() {
+        let mut sum = ARGBF::default();
+        let mut total = 0.;
+        while let Some((&(_, idx), _)) = sorted.next_if(|&(_, &bin)| bin < end) {
+            let item = &mut items[idx as usize];
+            let w = item.perceptual_weight.max(1e-9);
+            sum += item.color.0 * w;
+            total += w;
+            item.tmp = group as u32;
+        }
+        debug_assert!(group_start < end);
+        group_start = end;
+        palette.push(f_pixel(sum * (1. / total)), PalPop::new(total));
+    }
+    debug_assert_eq!(group_start, sums.len());
+    Ok((palette, error))
+}
+
+/// Weighted moments of bins, for O(1) cost of any contiguous range of bins
+struct PrefixSums {
+    w: Vec<f64>,
+    wx: Vec<f64>,
+    wxx: Vec<f64>,
+}
+
+impl PrefixSums {
+    fn with_capacity(bins: usize) -> Result<Self, Error> {
+        let mut s = Self {
+            w: Vec::new(),
+            wx: Vec::new(),
+            wxx: Vec::new(),
+        };
+        s.w.try_reserve_exact(bins)?;
+        s.wx.try_reserve_exact(bins)?;
+        s.wxx.try_reserve_exact(bins)?;
+        s.w.push(0.);
+        s.wx.push(0.);
+        s.wxx.push(0.);
+        Ok(s)
+    }
+
+    /// Number of bins
+    fn len(&self) -> usize {
+        self.w.len() - 1
+    }
+
+    fn push_bin(&mut self) {
+        let last = self.len();
+        self.w.push(self.w[last]);
+        self.wx.push(self.wx[last]);
+        self.wxx.push(self.wxx[last]);
+    }
+
+    /// Adds a point to the last bin
+    fn add(&mut self, x: f64, w: f64) {
+        let last = self.len();
+        self.w[last] += w;
+        self.wx[last] += w * x;
+        self.wxx[last] += w * x * x;
+    }
+
+    /// Weighted squared error of bins `start..end` from their weighted average
+    #[inline]
+    fn cost(&self, start: usize, end: usize) -> f64 {
+        let w = self.w[end] - self.w[start];
+        if w <= 0. {
+            return 0.;
+        }
+        let wx = self.wx[end] - self.wx[start];
+        let wxx = self.wxx[end] - self.wxx[start];
+        wx.mul_add(-wx / w, wxx).max(0.)
+    }
+}
+
+/// Splits bins into at most `max_groups` contiguous groups, using as few groups as needed to get the error under `max_error`.
+///
+/// Returns exclusive ends of the groups and the total error.
+fn partition(
+    sums: &PrefixSums,
+    max_groups: usize,
+    max_error: f64,
+) -> Result<(Vec<usize>, f64), Error> {
+    let n = sums.len();
+    let max_groups = max_groups.clamp(1, n);
+
+    // best error of first `i` bins split into the current number of groups
+    let mut prev: Vec<f64> = Vec::new();
+    prev.try_reserve_exact(n + 1)?;
+    prev.extend((0..=n).map(|i| sums.cost(0, i)));
+    let mut cur = prev.clone();
+
+    // start of the last group, for every number of groups > 1
+    let mut splits: Vec<u32> = Vec::new();
+    splits.try_reserve_exact((max_groups - 1) * (n + 1))?;
+
+    let mut groups = 1;
+    while groups < max_groups && prev[n] > max_error {
+        let row_start = splits.len();
+        splits.resize(row_start + n + 1, 0);
+        cur.fill(f64::INFINITY);
+        // every group must have at least one bin
+        fill_layer(
+            sums,
+            &prev,
+            &mut cur,
+            &mut splits[row_start..],
+            groups + 1,
+            n,
+            groups,
+            n - 1,
+        );
+        groups += 1;
+        core::mem::swap(&mut prev, &mut cur);
+    }
+
+    let mut ends = Vec::with_capacity(groups);
+    let mut end = n;
+    for g in (1..groups).rev() {
+        ends.push(end);
+        end = splits[(g - 1) * (n + 1) + end] as usize;
+    }
+    ends.push(end);
+    ends.reverse();
+    Ok((ends, prev[n]))
+}
+
+/// Divide-and-conquer optimization: the best split point doesn't decrease as the range grows,
+/// because the squared-error cost satisfies the quadrangle inequality.
+fn fill_layer(
+    sums: &PrefixSums,
+    prev: &[f64],
+    cur: &mut [f64],
+    splits: &mut [u32],
+    lo: usize,
+    hi: usize,
+    opt_lo: usize,
+    opt_hi: usize,
+) {
+    if lo > hi {
+        return;
+    }
+    let mid = (lo + hi) / 2;
+    let mut best = (f64::INFINITY, opt_lo);
+    let candidates = prev
+        .iter()
+        .enumerate()
+        .take(opt_hi.min(mid - 1) + 1)
+        .skip(opt_lo);
+    for (j, &prev_err) in candidates {
+        let c = prev_err + sums.cost(j, mid);
+        if c < best.0 {
+            best = (c, j);
+        }
+    }
+    cur[mid] = best.0;
+    splits[mid] = best.1 as u32;
+    if mid > lo {
+        fill_layer(sums, prev, cur, splits, lo, mid - 1, opt_lo, best.1);
+    }
+    fill_layer(sums, prev, cur, splits, mid + 1, hi, best.1, opt_hi);
+}
+
+#[test]
+fn partition_is_optimal() {
+    let points = [
+        (0., 1.),
+        (1., 1.),
+        (2., 1.),
+        (10., 5.),
+        (11., 1.),
+        (20., 1.),
+        (21., 1.),
+        (22., 2.),
+    ];
+    let mut sums = PrefixSums::with_capacity(points.len() + 1).unwrap();
+    for (x, w) in points {
+        sums.push_bin();
+        sums.add(x, w);
+    }
+
+    // exhaustive search over all ways of splitting into 3 groups
+    let mut best = f64::INFINITY;
+    for a in 1..points.len() {
+        for b in a + 1..points.len() {
+            best = best.min(sums.cost(0, a) + sums.cost(a, b) + sums.cost(b, points.len()));
+        }
+    }
+    let (ends, err) = partition(&sums, 3, 0.).unwrap();
+    assert_eq!(ends, [3, 5, 8]);
+    assert!((err - best).abs() < 1e-9);
+
+    // stops as soon as the error is low enough
+    let (ends, err) = partition(&sums, 8, 10.).unwrap();
+    assert_eq!(ends.len(), 3);
+    assert!(err <= 10.);
+    let (ends, err) = partition(&sums, 8, 0.).unwrap();
+    assert_eq!(ends.len(), 8);
+    assert_eq!(err, 0.);
+}
@@ -0,0 +1,264 @@
+//! Orders of palette entries. Similar colors at nearby indices make PNG-filtered rows compress better,
+//! and give smoother index gradients in palette textures.
+use crate::attr::PaletteOrder;
+use crate::edit::PaletteMapping;
+use crate::error::Error;
+use crate::pal::{f_pixel, PalIndexRemap, PalPop, LIQ_WEIGHT_B, LIQ_WEIGHT_G, LIQ_WEIGHT_R};
+use crate::quant::QuantizationResult;
+use crate::OrdFloat;
+use core::cmp::Reverse;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Indices of `colors` in the given order. Only [`PaletteOrder::IndexEntropy`] uses `neighbors` from [`neighbor_counts`].
+pub(crate) fn palette_order(
+    order: PaletteOrder,
+    colors: &[f_pixel],
+    pops: &[PalPop],
+    neighbors: &[u32],
+) -> Result<Vec<usize>, Error> {
+    let mut out = Vec::new();
+    out.try_reserve_exact(colors.len())?;
+    out.extend(0..colors.len());
+    let by_popularity = |i: &usize| Reverse(OrdFloat::new(pops[*i].popularity()));
+    match order {
+        PaletteOrder::Luminance => out.sort_by_key(|&i| OrdFloat::new(luma(colors[i]))),
+        PaletteOrder::Hue => out.sort_by_key(|&i| {
+            let hue = hue(colors[i]);
+            (hue.is_some(), OrdFloat::new(hue.unwrap_or(luma(colors[i]))))
+        }),
+        PaletteOrder::NearestNeighbor => {
+            let start = out
+                .iter()
+                .copied()
+                .min_by_key(|&i| OrdFloat::new(luma(colors[i])));
+            out = path(colors.len(), start, |from, to| {
+                Reverse(OrdFloat::new(colors[from].diff(&colors[to])))
+            })?;
+        }
+        PaletteOrder::IndexEntropy if !neighbors.is_empty() => {
+            out.sort_by_key(by_popularity);
+            out = chained(neighbors, &out)?;
+        }
+        PaletteOrder::Popularity | PaletteOrder::IndexEntropy => out.sort_by_key(by_popularity),
+    }
+    Ok(out)
+}
+
+/// `n`×`n` matrix of how many times entry `a` is followed by entry `b` in `indices`, at `a * n + b`
+fn neighbor_counts(n: usize, indices: &[PalIndexRemap]) -> Result<Vec<u32>, Error> {
+    let mut counts = Vec::new();
+    counts.try_reserve_exact(n * n)?;
+    counts.resize(n * n, 0u32);
+    for pair in indices.windows(2) {
+        let (a, b) = (usize::from(pair[0]), usize::from(pair[1]));
+        if a < n && b < n {
+            counts[a * n + b] += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// Entropy in bits per pixel of differences between indices of consecutive pixels after reordering,
+/// which is what PNG's Sub filter leaves for deflate (it ignores the starts of rows).
+fn filtered_entropy(counts: &[u32], new_to_old: &[usize]) -> f64 {
+    let n = new_to_old.len();
+    let mut old_to_new = [0u8; 256];
+    for (new, &old) in new_to_old.iter().enumerate() {
+        if let Some(o) = old_to_new.get_mut(old) {
+            *o = new as u8;
+        }
+    }
+    let mut diffs = [0u64; 256];
+    for (a, row) in counts.chunks_exact(n).take(256).enumerate() {
+        for (b, &count) in row.iter().take(256).enumerate() {
+            diffs[usize::from(old_to_new[b].wrapping_sub(old_to_new[a]))] += u64::from(count);
+        }
+    }
+    let total = diffs.iter().sum::<u64>() as f64;
+    diffs
+        .iter()
+        .filter(|&&d| d > 0)
+        .map(|&d| d as f64 / total)
+        .map(|p| -p * p.log2())
+        .sum()
+}
+
+/// Greedy path through all entries, starting at `start`, where the next entry is the one with the highest `score` from the previous one
+fn path<S: Ord>(
+    len: usize,
+    start: Option<usize>,
+    mut score: impl FnMut(usize, usize) -> S,
+) -> Result<Vec<usize>, Error> {
+    let mut out = Vec::new();
+    out.try_reserve_exact(len)?;
+    let mut remaining: Vec<usize> = (0..len).filter(|&i| Some(i) != start).collect();
+    let Some(mut current) = start else {
+        return Ok(out);
+    };
+    out.push(current);
+    while !remaining.is_empty() {
+        let (pos, _) = remaining
+            .iter()
+            .enumerate()
+            .max_by_key(|&(pos, &i)| (score(current, i), Reverse(pos)))
+            .unwrap_or((0, &0));
+        current = remaining.remove(pos);
+        out.push(current);
+    }
+    Ok(out)
+}
+
+/// Channels without the perceptual weights of `f_pixel`
+#[inline]
+fn rgb(c: f_pixel) -> [f32; 3] {
+    [c.r / LIQ_WEIGHT_R, c.g / LIQ_WEIGHT_G, c.b / LIQ_WEIGHT_B]
+}
+
+/// Links entries into chains, starting from the pairs that are the most often next to each other (greedy matching for the travelling salesman problem).
+///
+/// `counts` is from [`neighbor_counts`]. Chains are concatenated in the order of the entries in `by_popularity`.
+fn chained(counts: &[u32], by_popularity: &[usize]) -> Result<Vec<usize>, Error> {
+    let n = by_popularity.len();
+    let mut pairs = Vec::new();
+    for a in 0..n {
+        for b in a + 1..n {
+            let count = counts[a * n + b] + counts[b * n + a];
+            if count > 0 {
+                pairs.try_reserve(1)?;
+                pairs.push((count, a, b));
+            }
+        }
+    }
+    pairs.sort_by_key(|&(count, _, _)| Reverse(count));
+
+    let mut links = Vec::new();
+    links.try_reserve_exact(n)?;
+    links.resize(n, [usize::MAX; 2]);
+    // end of the chain at the other end, only valid for ends of chains
+    let mut other_end: Vec<usize> = (0..n).collect();
+    let has_room = |links: &[[usize; 2]], i: usize| links[i][1] == usize::MAX;
+    for (_, a, b) in pairs {
+        // linking ends of the same chain would make a loop
+        if !has_room(&links, a) || !has_room(&links, b) || other_end[a] == b {
+            continue;
+        }
+        for (from, to) in [(a, b), (b, a)] {
+            let slot = usize::from(links[from][0] != usize::MAX);
+            links[from][slot] = to;
+        }
+        let (end_a, end_b) = (other_end[a], other_end[b]);
+        other_end[end_a] = end_b;
+        other_end[end_b] = end_a;
+    }
+
+    let mut out = Vec::new();
+    out.try_reserve_exact(n)?;
+    let mut visited: Vec<bool> = (0..n).map(|_| false).collect();
+    for &start in by_popularity {
+        if visited[start] {
+            continue;
+        }
+        // walk from one end of the chain
+        let (mut prev, mut current) = (usize::MAX, other_end_of(start, &links));
+        while current != usize::MAX {
+            visited[current] = true;
+            out.push(current);
+            let [l0, l1] = links[current];
+            let next = if l0 != prev { l0 } else { l1 };
+            (prev, current) = (current, next);
+        }
+    }
+    Ok(out)
+}
+
+/// Walks to an end of the chain that `start` is in
+fn other_end_of(start: usize, links: &[[usize; 2]]) -> usize {
+    let (mut prev, mut current) = (usize::MAX, start);
+    loop {
+        let [l0, l1] = links[current];
+        let next = if l0 != prev { l0 } else { l1 };
+        if next == usize::MAX {
+            return current;
+        }
+        (prev, current) = (current, next);
+    }
+}
+
+#[inline]
+fn luma(c: f_pixel) -> f32 {
+    let [r, g, b] = rgb(c);
+    0.114f32.mul_add(b, 0.299f32.mul_add(r, 0.587 * g))
+}
+
+/// Position on the color wheel in 0..6, or `None` for grays
+fn hue(c: f_pixel) -> Option<f32> {
+    let [r, g, b] = rgb(c);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let chroma = max - min;
+    if chroma <= 1. / 256. {
+        return None;
+    }
+    let h = if max == r {
+        (g - b) / chroma
+    } else if max == g {
+        (b - r) / chroma + 2.
+    } else {
+        (r - g) / chroma + 4.
+    };
+    Some(if h < 0. { h + 6. } else { h })
+}
+
+impl QuantizationResult {
+    /// Changes order of palette entries, e.g. to make PNG files smaller. Locked entries stay where they are.
+    ///
+    /// [`PaletteOrder::IndexEntropy`] needs `indices` of a remapped image. Other orders ignore them, so they can be empty.
+    /// The indices are reordered in memory, so with over 256 colors only the low bytes of differences between them are measured.
+    ///
+    /// Entries with transparency are moved only to indices that had transparent entries before,
+    /// so the order respects [`Attributes::set_last_index_transparent`](crate::Attributes::set_last_index_transparent) and keeps PNG's tRNS chunk short.
+    pub fn reorder_palette(
+        &mut self,
+        order: PaletteOrder,
+        indices: &[PalIndexRemap],
+    ) -> Result<PaletteMapping, Error> {
+        let neighbors = if order == PaletteOrder::IndexEntropy {
+            neighbor_counts(self.palette.len(), indices)?
+        } else {
+            Vec::new()
+        };
+        let mut new_to_old = self.slots_in_order(order, &neighbors)?;
+        if !neighbors.is_empty() {
+            // chains are greedy, so they're kept only if they beat the other orders
+            let mut entropy = filtered_entropy(&neighbors, &new_to_old);
+            for other in [PaletteOrder::NearestNeighbor, PaletteOrder::Popularity] {
+                let candidate = self.slots_in_order(other, &neighbors)?;
+                let candidate_entropy = filtered_entropy(&neighbors, &candidate);
+                if candidate_entropy < entropy {
+                    (entropy, new_to_old) = (candidate_entropy, candidate);
+                }
+            }
+        }
+        self.rebuild_palette(&new_to_old, &[])
+    }
+
+    /// New index to old index, with entries in `order` within their opaque or transparent class
+    fn slots_in_order(&self, order: PaletteOrder, neighbors: &[u32]) -> Result<Vec<usize>, Error> {
+        let colors = self.palette.as_slice();
+        let ordered = palette_order(order, colors, self.palette.pop_as_slice(), neighbors)?;
+
+        let mut new_to_old: Vec<usize> = (0..colors.len()).collect();
+        for opaque in [true, false] {
+            let in_class = |&i: &usize| {
+                colors[i].is_fully_opaque() == opaque && !self.is_palette_index_locked(i)
+            };
+            let slots = (0..colors.len()).filter(in_class);
+            for (slot, i) in slots.zip(ordered.iter().copied().filter(in_class)) {
+                new_to_old[slot] = i;
+            }
+        }
+        Ok(new_to_old)
+    }
+}
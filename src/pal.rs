@@ -8,17 +8,21 @@ use wide::f32x4;
 #[cfg(all(not(feature = "std"), feature = "no_std"))]
 use crate::no_std_compat::*;
 
-/// 8-bit RGBA in sRGB. This is the only color format *publicly* used by the library.
+/// 8-bit RGBA in sRGB. This is the main color format used by the library, and the only one used for output.
 pub type RGBA = rgb::Rgba<u8>;
 
+/// 16-bit RGBA in sRGB (or the image's gamma), like in 16-bit PNG files
+#[allow(clippy::upper_case_acronyms)]
+pub type RGBA16 = rgb::Rgba<u16>;
+
 #[allow(clippy::upper_case_acronyms)]
 pub type ARGBF = rgb::Argb<f32>;
 
 const INTERNAL_GAMMA: f64 = 0.57;
 const LIQ_WEIGHT_A: f32 = 0.625;
-const LIQ_WEIGHT_R: f32 = 0.5;
-const LIQ_WEIGHT_G: f32 = 1.;
-const LIQ_WEIGHT_B: f32 = 0.45;
+pub(crate) const LIQ_WEIGHT_R: f32 = 0.5;
+pub(crate) const LIQ_WEIGHT_G: f32 = 1.;
+pub(crate) const LIQ_WEIGHT_B: f32 = 0.45;
 
 /// This is a fudge factor - reminder that colors are not in 0..1 range any more
 const LIQ_WEIGHT_MSE: f64 = 0.45;
@@ -75,6 +79,40 @@ impl f_pixel {
             return RGBA::new(0, 0, 0, 0);
         }
 
+        let [r, g, b] = self.straight_channels(gamma);
+        // 256, because numbers are in range 1..255.9999… rounded down
+        RGBA {
+            r: (r * 256.) as u8,
+            g: (g * 256.) as u8,
+            b: (b * 256.) as u8,
+            a: (self.a * (256. / f64::from(LIQ_WEIGHT_A)) as f32) as u8,
+        }
+    }
+
+    /// Same as `to_rgb`, but the color channels are multiplied by alpha
+    #[inline]
+    pub(crate) fn to_premultiplied_rgb(self, gamma: f64) -> RGBA {
+        let straight = self.to_rgb(gamma);
+        if straight.a == 0 {
+            return straight;
+        }
+
+        // premultiplying the unrounded value, so it can't exceed alpha
+        let a = f32::from(straight.a);
+        let [r, g, b] = self
+            .straight_channels(gamma)
+            .map(move |c| c.min(1.).mul_add(a, 0.5) as u8);
+        RGBA {
+            r,
+            g,
+            b,
+            a: straight.a,
+        }
+    }
+
+    /// Un-premultiplied, in the given gamma, in 0..1 range (roughly)
+    #[inline(always)]
+    fn straight_channels(self, gamma: f64) -> [f32; 3] {
         let r = (f64::from(LIQ_WEIGHT_A) / f64::from(LIQ_WEIGHT_R)) as f32 * self.r / self.a;
         let g = (f64::from(LIQ_WEIGHT_A) / f64::from(LIQ_WEIGHT_G)) as f32 * self.g / self.a;
         let b = (f64::from(LIQ_WEIGHT_A) / f64::from(LIQ_WEIGHT_B)) as f32 * self.b / self.a;
@@ -82,13 +120,7 @@ impl f_pixel {
         let gamma = (gamma / INTERNAL_GAMMA) as f32;
         debug_assert!(gamma.is_finite());
 
-        // 256, because numbers are in range 1..255.9999… rounded down
-        RGBA {
-            r: (r.max(0.).powf(gamma) * 256.) as u8,
-            g: (g.max(0.).powf(gamma) * 256.) as u8,
-            b: (b.max(0.).powf(gamma) * 256.) as u8,
-            a: (self.a * (256. / f64::from(LIQ_WEIGHT_A)) as f32) as u8,
-        }
+        [r, g, b].map(move |c| c.max(0.).powf(gamma))
     }
 
     pub fn from_rgba(gamma_lut: &[f32; 256], px: RGBA) -> Self {
@@ -101,6 +133,72 @@ impl f_pixel {
         })
     }
 
+    /// Converts a pixel with channels in 0..1 range, without rounding it to 8 bits.
+    ///
+    /// `exp` is `INTERNAL_GAMMA / gamma`, see [`gamma_exponent`]. Out-of-range values (and NaN) are clamped.
+    pub(crate) fn from_rgba_f32(exp: f32, [r, g, b, a]: [f32; 4], premultiplied: bool) -> Self {
+        let a = clamp_unit(a);
+        if a <= 0. {
+            return Self::default();
+        }
+        let straight = move |c: f32| {
+            let c = if premultiplied { c / a } else { c };
+            clamp_unit(c).powf(exp)
+        };
+        Self(ARGBF {
+            a: a * LIQ_WEIGHT_A,
+            r: straight(r) * LIQ_WEIGHT_R * a,
+            g: straight(g) * LIQ_WEIGHT_G * a,
+            b: straight(b) * LIQ_WEIGHT_B * a,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn from_rgba16(exp: f32, px: RGBA16, premultiplied: bool) -> Self {
+        Self::from_rgba_f32(exp, rgba16_to_f32(px), premultiplied)
+    }
+
+    /// Converts premultiplied pixel without unpremultiplying it to 8-bit first, which would lose precision.
+    ///
+    /// The `gamma_lut` must be for the same `gamma`.
+    pub(crate) fn from_premultiplied_rgba(gamma_lut: &[f32; 256], gamma: f64, px: RGBA) -> Self {
+        match px.a {
+            255 => Self::from_rgba(gamma_lut, px),
+            0 => Self::default(),
+            alpha => {
+                let exp = gamma_exponent(gamma);
+                let a = f32::from(alpha);
+                let straight = move |c: u8| (f32::from(c) / a).min(1.).powf(exp);
+                let a = a / 255.;
+                Self(ARGBF {
+                    a: a * LIQ_WEIGHT_A,
+                    r: straight(px.r) * LIQ_WEIGHT_R * a,
+                    g: straight(px.g) * LIQ_WEIGHT_G * a,
+                    b: straight(px.b) * LIQ_WEIGHT_B * a,
+                })
+            }
+        }
+    }
+
+    /// Position on the axis of grays, scaled so that for colors with the same alpha
+    /// the squared distance is the same as `diff()`. `None` if the color isn't gray.
+    pub(crate) fn gray_axis_position(self) -> Option<f32> {
+        let g = self.g / LIQ_WEIGHT_G;
+        let tolerance = 1. / 4096.;
+        if (self.r / LIQ_WEIGHT_R - g).abs() > tolerance
+            || (self.b / LIQ_WEIGHT_B - g).abs() > tolerance
+        {
+            return None;
+        }
+        let axis_len = LIQ_WEIGHT_R
+            .mul_add(
+                LIQ_WEIGHT_R,
+                LIQ_WEIGHT_G.mul_add(LIQ_WEIGHT_G, LIQ_WEIGHT_B * LIQ_WEIGHT_B),
+            )
+            .sqrt();
+        Some(g * axis_len)
+    }
+
     #[inline]
     pub(crate) fn is_fully_transparent(self) -> bool {
         self.a < (1. / 255. * f64::from(LIQ_WEIGHT_A)) as f32
@@ -298,17 +396,43 @@ impl PalF {
         int_palette: &mut Palette,
         gamma: f64,
         posterize: u8,
+        levels: Option<&ChannelLevels>,
+        premultiplied: bool,
+        grayscale: bool,
     ) {
         let lut = gamma_lut(gamma);
         for ((f_color, f_pop), int_pal) in self.iter_mut().zip(&mut int_palette.entries) {
-            let mut px = f_color
-                .to_rgb(gamma)
-                .map(move |c| posterize_channel(c, posterize));
+            let mut px = if premultiplied {
+                f_color.to_premultiplied_rgb(gamma)
+            } else {
+                f_color.to_rgb(gamma)
+            }
+            .map(move |c| posterize_channel(c, posterize));
+            if grayscale && !f_pop.is_fixed() {
+                // the color is gray already, but channels could have been rounded differently
+                px.r = px.g;
+                px.b = px.g;
+            }
+            if let Some(levels) = levels {
+                px = levels.snap(px);
+            }
+            if premultiplied {
+                // posterization of alpha could have made it smaller than the color
+                px.r = px.r.min(px.a);
+                px.g = px.g.min(px.a);
+                px.b = px.b.min(px.a);
+                *f_color = f_pixel::from_premultiplied_rgba(&lut, gamma, px);
+                *int_pal = px;
+                continue;
+            }
             *f_color = f_pixel::from_rgba(&lut, px);
-            if px.a == 0 && !f_pop.is_fixed() {
+            if px.a == 0 && !f_pop.is_fixed() && !grayscale {
                 px.r = 71u8;
                 px.g = 112u8;
                 px.b = 76u8;
+                if let Some(levels) = levels {
+                    px = levels.snap(px);
+                }
             }
             *int_pal = px;
         }
@@ -316,6 +440,77 @@ impl PalF {
     }
 }
 
+/// Luma (Rec. 601) in the image's gamma, for the grayscale mode
+#[inline(always)]
+pub(crate) fn to_gray(px: RGBA) -> RGBA {
+    let y =
+        ((u32::from(px.r) * 77 + u32::from(px.g) * 150 + u32::from(px.b) * 29 + 128) >> 8) as u8;
+    RGBA::new(y, y, y, px.a)
+}
+
+/// Like [`to_gray`], but for 16-bit pixels
+#[inline(always)]
+pub(crate) fn to_gray16(px: RGBA16) -> RGBA16 {
+    let y = ((u32::from(px.r) * 19595 + u32::from(px.g) * 38470 + u32::from(px.b) * 7471 + 32768)
+        >> 16) as u16;
+    RGBA16::new(y, y, y, px.a)
+}
+
+/// Like [`to_gray`], but for floating-point pixels
+#[inline(always)]
+pub(crate) fn to_gray_f32([r, g, b, a]: [f32; 4]) -> [f32; 4] {
+    let y = 0.299f32.mul_add(r, 0.587f32.mul_add(g, 0.114 * b));
+    [y, y, y, a]
+}
+
+/// Rounded inverse of premultiplication, for places that need 8-bit straight alpha
+#[inline]
+pub(crate) fn unpremultiply(px: RGBA) -> RGBA {
+    if px.a == 255 || px.a == 0 {
+        return if px.a == 0 { RGBA::new(0, 0, 0, 0) } else { px };
+    }
+    let a = u16::from(px.a);
+    let ch = move |c: u8| ((u16::from(c) * 255 + a / 2) / a).min(255) as u8;
+    RGBA::new(ch(px.r), ch(px.g), ch(px.b), px.a)
+}
+
+/// Like [`unpremultiply`], but for 16-bit pixels
+#[inline]
+pub(crate) fn unpremultiply16(px: RGBA16) -> RGBA16 {
+    if px.a == u16::MAX || px.a == 0 {
+        return if px.a == 0 {
+            RGBA16::new(0, 0, 0, 0)
+        } else {
+            px
+        };
+    }
+    let a = u32::from(px.a);
+    let ch = move |c: u16| ((u32::from(c) * 65535 + a / 2) / a).min(65535) as u16;
+    RGBA16::new(ch(px.r), ch(px.g), ch(px.b), px.a)
+}
+
+#[inline(always)]
+pub(crate) fn rgba16_to_f32(px: RGBA16) -> [f32; 4] {
+    [px.r, px.g, px.b, px.a].map(move |c| f32::from(c) * (1. / 65535.))
+}
+
+/// Rounds to 16 bits, clamping out-of-range values
+#[inline(always)]
+pub(crate) fn f32_to_rgba16([r, g, b, a]: [f32; 4]) -> RGBA16 {
+    let ch = move |c: f32| clamp_unit(c).mul_add(65535., 0.5) as u16;
+    RGBA16::new(ch(r), ch(g), ch(b), ch(a))
+}
+
+/// 0..1, and NaN is 0
+#[inline(always)]
+fn clamp_unit(c: f32) -> f32 {
+    if c > 0. {
+        c.min(1.)
+    } else {
+        0.
+    }
+}
+
 #[inline]
 const fn posterize_channel(color: u8, bits: u8) -> u8 {
     if bits == 0 {
@@ -325,16 +520,75 @@ const fn posterize_channel(color: u8, bits: u8) -> u8 {
     }
 }
 
+/// Values that each channel of output colors can have, for hardware with a restricted color gamut (e.g. RGB565).
+///
+/// Stored as lookup tables from any 8-bit value to the closest allowed one, in R, G, B, A order.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct ChannelLevels(Box<[[u8; 256]; 4]>);
+
+impl ChannelLevels {
+    /// `None` if any channel has no allowed values
+    pub(crate) fn new(allowed: [&[u8]; 4]) -> Option<Self> {
+        let mut luts = Box::new([[0; 256]; 4]);
+        for (lut, allowed) in luts.iter_mut().zip(allowed) {
+            for (value, snapped) in (0..=255u8).zip(lut.iter_mut()) {
+                *snapped = allowed
+                    .iter()
+                    .copied()
+                    .min_by_key(|&a| (a.abs_diff(value), a))?;
+            }
+        }
+        Some(Self(luts))
+    }
+
+    /// All values of a channel with `bits` of precision, with high bits repeated in low bits like in RGB565 to RGB888 expansion
+    pub(crate) fn bit_depth_values(bits: u8) -> impl Iterator<Item = u8> {
+        let max = (1u32 << bits) - 1;
+        (0..=max).map(move |v| ((v * 255 + max / 2) / max) as u8)
+    }
+
+    /// Allowed values of the channel 0..4 (R, G, B, A)
+    pub(crate) fn values(&self, channel: usize) -> impl Iterator<Item = u8> + '_ {
+        (0..=255u8).filter(move |&v| self.0[channel][v as usize] == v)
+    }
+
+    #[inline]
+    pub(crate) fn snap(&self, px: RGBA) -> RGBA {
+        let [r, g, b, a] = &*self.0;
+        RGBA::new(
+            r[px.r as usize],
+            g[px.g as usize],
+            b[px.b as usize],
+            a[px.a as usize],
+        )
+    }
+
+    /// Moves palette colors to the closest allowed colors in the output `gamma`. Fixed colors are left as they are.
+    pub(crate) fn snap_palette(&self, palette: &mut PalF, gamma: f64) {
+        let lut = gamma_lut(gamma);
+        for (color, _) in palette.iter_mut().filter(|(_, pop)| !pop.is_fixed()) {
+            *color = f_pixel::from_rgba(&lut, self.snap(color.to_rgb(gamma)));
+        }
+    }
+}
+
 #[inline(always)]
 pub fn gamma_lut(gamma: f64) -> [f32; 256] {
     debug_assert!(gamma > 0.);
+    let exp = gamma_exponent(gamma);
     let mut tmp = [0.; 256];
     for (i, t) in tmp.iter_mut().enumerate() {
-        *t = ((i as f32) / 255.).powf((INTERNAL_GAMMA / gamma) as f32);
+        *t = ((i as f32) / 255.).powf(exp);
     }
     tmp
 }
 
+/// For converting colors from the image gamma to the internal gamma, without a LUT
+#[inline]
+pub(crate) fn gamma_exponent(gamma: f64) -> f32 {
+    (INTERNAL_GAMMA / gamma) as f32
+}
+
 /// MSE that assumes 0..1 channels scaled to MSE that we have in practice
 #[inline]
 pub(crate) fn unit_mse_to_internal_mse(internal_mse: f64) -> f64 {
@@ -346,6 +600,11 @@ pub(crate) fn internal_mse_to_standard_mse(mse: f64) -> f64 {
     (mse * 65536. / 6.) / LIQ_WEIGHT_MSE
 }
 
+/// Inverse of [`internal_mse_to_standard_mse`]
+pub(crate) fn standard_mse_to_internal_mse(mse: f64) -> f64 {
+    mse * LIQ_WEIGHT_MSE * 6. / 65536.
+}
+
 /// Not used in the Rust API.
 /// RGBA colors obtained from [`QuantizationResult`](crate::QuantizationResult)
 #[repr(C)]
@@ -573,7 +832,7 @@ fn pal_test() {
         count: 0,
         entries: [RGBA::default(); MAX_COLORS],
     };
-    p.init_int_palette(&mut int_pal, 0.45455, 0);
+    p.init_int_palette(&mut int_pal, 0.45455, 0, None, false, false);
 
     for i in 0..=255u8 {
         let rgba = p.as_slice()[i as usize].to_rgb(0.45455);
@@ -592,3 +851,23 @@ fn largepal() {
         p.push(f_pixel::from_rgba(&gamma, rgba), PalPop::new(1.));
     }
 }
+
+#[test]
+fn premultiplied_roundtrip() {
+    let gamma = 0.45455;
+    let lut = gamma_lut(gamma);
+    for a in [1u8, 2, 17, 128, 254, 255] {
+        for c in [0u8, 1, a / 3, a / 2, a] {
+            let px = RGBA::new(c, a / 2, a, a);
+            let f = f_pixel::from_premultiplied_rgba(&lut, gamma, px);
+            assert_eq!(px, f.to_premultiplied_rgb(gamma));
+            let straight = f_pixel::from_rgba(&lut, unpremultiply(px));
+            assert!(f.diff(&straight) < 1. / 256., "{px:?} {f:?} {straight:?}");
+        }
+    }
+    assert_eq!(RGBA::new(0, 0, 0, 0), unpremultiply(RGBA::new(0, 0, 0, 0)));
+    assert_eq!(
+        RGBA::new(255, 128, 0, 128),
+        unpremultiply(RGBA::new(128, 64, 0, 128))
+    );
+}
@@ -1,33 +1,59 @@
-use crate::attr::{Attributes, ControlFlow};
+use crate::attr::{Attributes, ContentMode, ControlFlow, PaletteAlgorithm};
+use crate::candidates::candidate_palette;
 use crate::error::*;
 use crate::hist::{Histogram, HistogramInternal};
 use crate::image::Image;
-use crate::kmeans::Kmeans;
+use crate::kmeans::{snap_to_medoids, Kmeans};
 use crate::mediancut::mediancut;
+use crate::merge::{agglomerative, pixel_art_palette};
+use crate::neuquant::neuquant;
+use crate::octree::Octree;
+use crate::optimal;
+use crate::order::palette_order;
+use crate::pal::{
+    f_pixel, gamma_lut, ChannelLevels, PalF, PalIndexRemap, PalLen, PalPop, Palette, MAX_COLORS,
+    RGBA,
+};
 use crate::pal::{internal_mse_to_standard_mse, unit_mse_to_internal_mse};
-use crate::pal::{PalF, PalIndexRemap, PalLen, PalPop, Palette, MAX_COLORS, RGBA};
 use crate::remap::{remap_to_palette, remap_to_palette_floyd, DitherMapMode, Remapped};
+use crate::rows::temp_buf;
 use crate::seacow::RowBitmapMut;
+use crate::stream::{self, RowSink, RowSource};
+use crate::wu::wu;
 use crate::OrdFloat;
 use arrayvec::ArrayVec;
-use core::cmp::Reverse;
 use core::fmt;
+use std::sync::Arc;
 
 #[cfg(all(not(feature = "std"), feature = "no_std"))]
 use crate::no_std_compat::*;
 
+/// Pixels remapped at a time by [`QuantizationResult::remap_into_sink`]
+const SINK_BAND_PIXELS: usize = 1 << 22;
+
 /// Remapping step, computed from [`Attributes::quantize()`]
 pub struct QuantizationResult {
-    remapped: Option<Box<Remapped>>,
+    pub(crate) remapped: Option<Box<Remapped>>,
     pub(crate) palette: PalF,
     progress_callback: Option<Box<dyn Fn(f32) -> ControlFlow + Send + Sync>>,
     pub(crate) int_palette: Palette,
     pub(crate) dither_level: f32,
+    /// Fraction of pixels counted in the histogram, see [`Attributes::set_pixel_sampling`]
+    pub(crate) pixel_sampling_rate: f32,
     pub(crate) gamma: f64,
     pub(crate) palette_error: Option<f64>,
     pub(crate) min_posterization_output: u8,
+    pub(crate) channel_levels: Option<ChannelLevels>,
     pub(crate) use_dither_map: DitherMapMode,
     pub(crate) single_threaded_dithering: bool,
+    pub(crate) output_premultiplied: bool,
+    pub(crate) grayscale: bool,
+    /// Never `Auto`, it's already decided
+    pub(crate) content_mode: ContentMode,
+    /// Palette entries that keep their index when the palette is edited. `None` if none are.
+    pub(crate) locked: Option<Box<[bool; MAX_COLORS]>>,
+    /// Maps pixels of the quantized image to the palette, see [`PaletteAlgorithm::Octree`]
+    pub(crate) octree: Option<Arc<Octree>>,
 }
 
 impl QuantizationResult {
@@ -36,14 +62,93 @@ impl QuantizationResult {
         hist: HistogramInternal,
         freeze_result_colors: bool,
         gamma: f64,
+        content_mode: ContentMode,
     ) -> Result<Self, Error> {
         if attr.progress(f32::from(attr.progress_stage1)) {
             return Err(Aborted);
         }
         let (max_mse, target_mse, target_mse_is_zero) = attr.target_mse(hist.items.len());
-        let (mut palette, palette_error) =
-            find_best_palette(attr, target_mse, target_mse_is_zero, max_mse, hist)?;
-        if freeze_result_colors {
+        let pinned_colors = hist.pinned_colors.clone();
+        let pixel_art = content_mode == ContentMode::PixelArt;
+        let palette = if pixel_art {
+            attr.verbose_print("  merging least important colors...");
+            pixel_art_palette(&hist, attr.max_colors)
+        } else {
+            find_best_palette(attr, target_mse, target_mse_is_zero, max_mse, gamma, hist)
+        };
+        // remapping must not move colors away from the candidates or image colors
+        let freeze =
+            freeze_result_colors || !attr.candidate_colors.is_empty() || attr.medoids || pixel_art;
+        // not `?`, which in debug builds would keep stack for more copies of the palette during the search for it
+        palette.and_then(|(palette, palette_error)| {
+            Self::with_palette(
+                attr,
+                palette,
+                palette_error,
+                max_mse,
+                &pinned_colors,
+                freeze,
+                gamma,
+                content_mode,
+            )
+        })
+    }
+
+    /// Palette straight from the pixels of the image, see [`PaletteAlgorithm::Octree`]
+    pub(crate) fn from_octree(attr: &Attributes, image: &mut Image<'_>) -> Result<Self, Error> {
+        if attr.progress(f32::from(attr.progress_stage1)) {
+            return Err(Aborted);
+        }
+        let gamma = image.px.gamma;
+        let mut tree = Octree::from_image(&image.px)?;
+        attr.verbose_print("  made octree");
+
+        let lut = gamma_lut(gamma);
+        let fixed_colors: Vec<_> = image
+            .fixed_colors
+            .iter()
+            .map(|&(c, _)| f_pixel::from_rgba(&lut, c))
+            .collect();
+        let pinned_colors: Vec<_> = image
+            .fixed_colors
+            .iter()
+            .filter_map(|&(c, pinned)| Some((f_pixel::from_rgba(&lut, c), pinned?)))
+            .collect();
+        // cells of the tree are the closest thing to distinct colors it has
+        let (max_mse, _, _) = attr.target_mse(tree.used_cells());
+        let palette = tree
+            .reduce(attr.max_colors)?
+            .with_fixed_colors(attr.max_colors, &fixed_colors);
+        let mut res = Self::with_palette(
+            attr,
+            palette,
+            None,
+            None,
+            &pinned_colors,
+            false,
+            gamma,
+            ContentMode::Photo,
+        )?;
+        // the cells are mapped to the palette after it's sorted, so that remapping can use them as they are
+        let palette_error = tree.map_cells(&res.palette)?;
+        check_palette_error(attr, palette_error, max_mse)?;
+        res.palette_error = Some(palette_error);
+        res.octree = Some(Arc::new(tree));
+        Ok(res)
+    }
+
+    /// Checks quality, and sorts and pins the palette
+    fn with_palette(
+        attr: &Attributes,
+        mut palette: PalF,
+        palette_error: Option<f64>,
+        max_mse: Option<f64>,
+        pinned_colors: &[(f_pixel, PalLen)],
+        freeze_colors: bool,
+        gamma: f64,
+        content_mode: ContentMode,
+    ) -> Result<Self, Error> {
+        if freeze_colors {
             palette.iter_mut().for_each(|(_, p)| *p = p.to_fixed());
         }
         if attr.progress(f32::from(attr.progress_stage3).mul_add(
@@ -52,26 +157,19 @@ impl QuantizationResult {
         )) {
             return Err(Aborted);
         }
-        if let (Some(palette_error), Some(max_mse)) = (palette_error, max_mse) {
-            if palette_error > max_mse {
-                attr.verbose_print(format!(
-                    "  image degradation MSE={:0.3} (Q={}) exceeded limit of {:0.3} ({})",
-                    internal_mse_to_standard_mse(palette_error),
-                    mse_to_quality(palette_error),
-                    internal_mse_to_standard_mse(max_mse),
-                    mse_to_quality(max_mse)
-                ));
-                return Err(QualityTooLow);
-            }
+        if let Some(palette_error) = palette_error {
+            check_palette_error(attr, palette_error, max_mse)?;
         }
 
         sort_palette(attr, &mut palette);
+        let locked = pin_colors(&mut palette, pinned_colors);
 
         Ok(Self {
             palette,
             gamma,
             palette_error,
             min_posterization_output: attr.min_posterization(),
+            channel_levels: attr.channel_levels.clone(),
             use_dither_map: attr.use_dither_map,
             remapped: None,
             progress_callback: None,
@@ -79,8 +177,20 @@ impl QuantizationResult {
                 count: 0,
                 entries: [RGBA::default(); MAX_COLORS],
             },
-            dither_level: 1.,
+            dither_level: if content_mode == ContentMode::PixelArt
+                || attr.palette_algorithm == PaletteAlgorithm::Octree
+            {
+                0.
+            } else {
+                1.
+            },
+            pixel_sampling_rate: 1.,
             single_threaded_dithering: attr.single_threaded_dithering,
+            output_premultiplied: false,
+            grayscale: attr.grayscale,
+            content_mode,
+            locked,
+            octree: None,
         })
     }
 
@@ -105,9 +215,19 @@ impl QuantizationResult {
 
     #[inline(never)]
     pub(crate) fn write_remapped_image_rows_internal(
+        &mut self,
+        image: &mut Image,
+        output_pixels: RowBitmapMut<'_, PalIndexRemap>,
+    ) -> Result<(), Error> {
+        self.write_remapped_image_rows(image, output_pixels, true)
+    }
+
+    /// If `refine_palette` is false, the palette stays as it was before remapping, even if a dither map is generated
+    fn write_remapped_image_rows(
         &mut self,
         image: &mut Image,
         mut output_pixels: RowBitmapMut<'_, PalIndexRemap>,
+        refine_palette: bool,
     ) -> Result<(), Error> {
         let progress_stage1 = if self.use_dither_map != DitherMapMode::None {
             20
@@ -133,26 +253,42 @@ impl QuantizationResult {
                 &mut remapped.int_palette,
                 self.gamma,
                 self.min_posterization_output,
+                self.channel_levels.as_ref(),
+                self.output_premultiplied,
+                self.grayscale,
             );
             remapped.palette_error = Some(
                 remap_to_palette(
                     &mut image.px,
                     image.background.as_deref_mut(),
                     image.importance_map.as_deref(),
+                    0,
                     &mut output_pixels,
                     &mut palette,
+                    self.octree.as_deref(),
                 )?
                 .0,
             );
         } else {
             let uses_background = image.background.is_some();
+            // kmeans runs while the dither map is generated
+            let mut throwaway_palette;
+            let dither_map_palette = if refine_palette {
+                &mut palette
+            } else {
+                throwaway_palette = palette.clone();
+                &mut throwaway_palette
+            };
             let dither_map_error = Self::optionally_generate_dither_map(
                 self.use_dither_map,
                 image,
                 uses_background,
                 &mut output_pixels,
-                &mut palette,
+                dither_map_palette,
             )?;
+            if self.content_mode == ContentMode::Text {
+                image.suppress_dithering_on_edges()?;
+            }
             if self.remap_progress(progress_stage1 as f32 * 0.5) {
                 return Err(Error::Aborted);
             }
@@ -165,12 +301,15 @@ impl QuantizationResult {
                 &mut remapped.int_palette,
                 self.gamma,
                 self.min_posterization_output,
+                self.channel_levels.as_ref(),
+                self.output_premultiplied,
+                self.grayscale,
             );
             remapped.palette_error = palette_error;
-            let max_dither_error =
-                (palette_error.unwrap_or(quality_to_mse(80)) * 2.4).max(quality_to_mse(35)) as f32;
+            let max_dither_error = max_dither_error(palette_error);
             remap_to_palette_floyd(
                 image,
+                0,
                 output_pixels,
                 &palette,
                 self,
@@ -182,6 +321,13 @@ impl QuantizationResult {
         Ok(())
     }
 
+    fn will_generate_dither_map(use_dither_map: DitherMapMode, image: &Image<'_>) -> bool {
+        let is_image_huge = (image.px.width * image.px.height) > 2000 * 2000;
+        let allow_dither_map = use_dither_map == DitherMapMode::Always
+            || (!is_image_huge && use_dither_map != DitherMapMode::None);
+        allow_dither_map && image.dither_map.is_none()
+    }
+
     fn optionally_generate_dither_map(
         use_dither_map: DitherMapMode,
         image: &mut Image<'_>,
@@ -189,11 +335,7 @@ impl QuantizationResult {
         output_pixels: &mut RowBitmapMut<'_, PalIndexRemap>,
         palette: &mut PalF,
     ) -> Result<Option<f64>, Error> {
-        let is_image_huge = (image.px.width * image.px.height) > 2000 * 2000;
-        let allow_dither_map = use_dither_map == DitherMapMode::Always
-            || (!is_image_huge && use_dither_map != DitherMapMode::None);
-        let generate_dither_map = allow_dither_map && image.dither_map.is_none();
-        if !generate_dither_map {
+        if !Self::will_generate_dither_map(use_dither_map, image) {
             return Ok(None);
         }
 
@@ -202,8 +344,10 @@ impl QuantizationResult {
             &mut image.px,
             None,
             image.importance_map.as_deref(),
+            0,
             output_pixels,
             palette,
+            None,
         )?;
         image.update_dither_map(&row_pointers_remapped, &*palette, uses_background)?;
         Ok(Some(palette_error))
@@ -241,12 +385,38 @@ impl QuantizationResult {
         self.gamma
     }
 
+    /// Make the palette use premultiplied alpha, i.e. color channels multiplied by alpha.
+    ///
+    /// The palette is rounded in premultiplied form, so it's better than premultiplying the straight-alpha palette afterwards.
+    /// The default is `false`. This is independent from [`Image::set_premultiplied_alpha`].
+    pub fn set_output_premultiplied_alpha(&mut self, premultiplied: bool) {
+        if self.output_premultiplied != premultiplied {
+            self.remapped = None;
+            self.int_palette.count = 0;
+            self.output_premultiplied = premultiplied;
+        }
+    }
+
+    /// Whether the palette uses premultiplied alpha. See [`QuantizationResult::set_output_premultiplied_alpha`]
+    #[inline]
+    #[must_use]
+    pub fn output_premultiplied_alpha(&self) -> bool {
+        self.output_premultiplied
+    }
+
     /// Number 0-100 guessing how nice the input image will look if remapped to this palette
     #[must_use]
     pub fn quantization_quality(&self) -> Option<u8> {
         self.palette_error.map(mse_to_quality)
     }
 
+    /// Fraction of pixels (0-1) that the palette was made from. It's below 1 only if [`Attributes::set_pixel_sampling`] has been used.
+    #[inline]
+    #[must_use]
+    pub fn pixel_sampling_rate(&self) -> f32 {
+        self.pixel_sampling_rate
+    }
+
     /// Approximate mean square error of the palette
     #[must_use]
     pub fn quantization_error(&self) -> Option<f64> {
@@ -292,6 +462,9 @@ impl QuantizationResult {
                     &mut self.int_palette,
                     self.gamma,
                     self.min_posterization_output,
+                    self.channel_levels.as_ref(),
+                    self.output_premultiplied,
+                    self.grayscale,
                 );
             }
             &self.int_palette
@@ -367,6 +540,137 @@ impl QuantizationResult {
         self.write_remapped_image_rows_internal(image, rows)
     }
 
+    /// Remap image, and pass rows of palette indices to the `sink` in order, as soon as they're done.
+    ///
+    /// This is for piping the output into an encoder (e.g. a PNG or GIF compressor) without holding the whole indexed image in memory.
+    /// Rows are remapped in bands, which are dithered in parallel as in [`remap_into()`][Self::remap_into].
+    ///
+    /// The palette is not refined while remapping, so [`palette()`][Self::palette] can be written out before calling it.
+    /// If a dither map is used (see [`Attributes::set_speed`]), a full-size temporary buffer is still needed.
+    /// Images over 4 megapixels don't use the dither map, unless the speed is 1 or 2.
+    pub fn remap_into_sink(
+        &mut self,
+        image: &mut Image<'_>,
+        sink: &mut impl RowSink,
+    ) -> Result<(), Error> {
+        // tall enough for every thread to get a chunk worth dithering in parallel
+        let band_rows = (SINK_BAND_PIXELS / image.width()).max(256);
+        self.write_remapped_image_rows_to_sink(image, sink, band_rows)
+    }
+
+    #[inline(never)]
+    pub(crate) fn write_remapped_image_rows_to_sink(
+        &mut self,
+        image: &mut Image<'_>,
+        sink: &mut dyn RowSink,
+        band_rows: usize,
+    ) -> Result<(), Error> {
+        let width = image.width();
+        let height = image.height();
+        let band_rows = band_rows.clamp(1, height);
+
+        if self.dither_level != 0. && Self::will_generate_dither_map(self.use_dither_map, image) {
+            // the dither map is made from the whole image remapped, so it has to be held anyway
+            let mut buf = Vec::new();
+            buf.try_reserve_exact(width * height)?;
+            buf.resize(width * height, 0);
+            self.write_remapped_image_rows(
+                image,
+                RowBitmapMut::new_contiguous(&mut buf, width),
+                false,
+            )?;
+            for (band, first_row) in buf.chunks(band_rows * width).zip((0..).step_by(band_rows)) {
+                sink.write_rows(first_row, band)?;
+            }
+            return Ok(());
+        }
+
+        if self.remap_progress(0.) {
+            return Err(Error::Aborted);
+        }
+        image.free_histogram_inputs();
+
+        self.remapped = None;
+        let mut palette = self.palette.clone();
+        let mut remapped = Box::new(Remapped {
+            int_palette: Palette {
+                count: 0,
+                entries: [RGBA::default(); MAX_COLORS],
+            },
+            palette_error: self.palette_error,
+        });
+        palette.init_int_palette(
+            &mut remapped.int_palette,
+            self.gamma,
+            self.min_posterization_output,
+            self.channel_levels.as_ref(),
+            self.output_premultiplied,
+            self.grayscale,
+        );
+        let max_dither_error = max_dither_error(self.palette_error);
+
+        if self.dither_level != 0. && self.content_mode == ContentMode::Text {
+            image.suppress_dithering_on_edges()?;
+        }
+
+        let mut buf = temp_buf(band_rows * width)?;
+        let mut remapping_error = 0.;
+        for first_row in (0..height).step_by(band_rows) {
+            let output = &mut buf[..band_rows.min(height - first_row) * width];
+            if self.dither_level == 0. {
+                // kmeans would change the palette between bands, so it works on a throwaway copy
+                remapping_error += remap_to_palette(
+                    &mut image.px,
+                    image.background.as_deref_mut(),
+                    image.importance_map.as_deref(),
+                    first_row,
+                    &mut RowBitmapMut::new_contiguous(&mut *output, width),
+                    &mut palette.clone(),
+                    self.octree.as_deref(),
+                )?
+                .0;
+            } else {
+                remap_to_palette_floyd(
+                    image,
+                    first_row,
+                    RowBitmapMut::new_contiguous(&mut *output, width),
+                    &palette,
+                    self,
+                    max_dither_error,
+                    false,
+                )?;
+            }
+            sink.write_rows(first_row, output)?;
+        }
+        if self.dither_level == 0. {
+            remapped.palette_error = Some(remapping_error);
+        }
+        self.remapped = Some(remapped);
+        Ok(())
+    }
+
+    /// Remap an image read from the `source` in bands of rows, and write palette indices to the `sink` as soon as each band is done.
+    ///
+    /// This is for images too large to keep in memory, together with [`Histogram::add_rows`]. Memory use is proportional to the `width`.
+    /// Dithering continues across bands, so there are no seams between them.
+    ///
+    /// Unlike [`remap_into()`][Self::remap_into], this doesn't refine the palette, so [`palette()`][Self::palette] can be written out before calling it.
+    /// Background images are not supported.
+    pub fn remap_rows(
+        &mut self,
+        source: &mut impl RowSource,
+        width: usize,
+        height: usize,
+        gamma: f64,
+        sink: &mut impl RowSink,
+    ) -> Result<(), Error> {
+        self.remapped = None;
+        self.remapped = Some(stream::remap_rows(
+            self, source, width, height, gamma, sink,
+        )?);
+        Ok(())
+    }
+
     /// The final palette, copied.
     ///
     /// It's slighly better if you get palette from the [`remapped()`][Self::remapped] call instead
@@ -421,27 +725,67 @@ impl Clone for QuantizationResult {
             gamma: self.gamma,
             palette_error: self.palette_error,
             min_posterization_output: self.min_posterization_output,
+            channel_levels: self.channel_levels.clone(),
             use_dither_map: self.use_dither_map,
+            pixel_sampling_rate: self.pixel_sampling_rate,
             single_threaded_dithering: self.single_threaded_dithering,
+            output_premultiplied: self.output_premultiplied,
+            grayscale: self.grayscale,
+            content_mode: self.content_mode,
+            locked: self.locked.clone(),
+            octree: self.octree.clone(),
+        }
+    }
+}
+
+/// Fails with [`QualityTooLow`] if the error of the palette exceeds `max_mse`
+fn check_palette_error(
+    attr: &Attributes,
+    palette_error: f64,
+    max_mse: Option<f64>,
+) -> Result<(), Error> {
+    match max_mse {
+        Some(max_mse) if palette_error > max_mse => {
+            attr.verbose_print(format!(
+                "  image degradation MSE={:0.3} (Q={}) exceeded limit of {:0.3} ({})",
+                internal_mse_to_standard_mse(palette_error),
+                mse_to_quality(palette_error),
+                internal_mse_to_standard_mse(max_mse),
+                mse_to_quality(max_mse)
+            ));
+            Err(QualityTooLow)
         }
+        _ => Ok(()),
     }
 }
 
 fn sort_palette(attr: &Attributes, palette: &mut PalF) {
     let last_index_transparent = attr.last_index_transparent;
 
-    let mut tmp: ArrayVec<_, { MAX_COLORS }> = palette.iter_mut().map(|(c, p)| (*c, *p)).collect();
-    tmp.sort_by_key(|(color, pop)| {
+    let mut ranks = [0; MAX_COLORS];
+    if let Ok(order) = palette_order(
+        attr.palette_order,
+        palette.as_slice(),
+        palette.pop_as_slice(),
+        &[],
+    ) {
+        for (rank, i) in order.into_iter().enumerate() {
+            ranks[i] = rank;
+        }
+    }
+    let mut tmp: ArrayVec<_, { MAX_COLORS }> = palette
+        .iter_mut()
+        .zip(ranks)
+        .map(|((c, p), rank)| (*c, *p, rank))
+        .collect();
+    tmp.sort_by_key(|&(color, _, rank)| {
         let trns = !color.is_fully_opaque();
-        (
-            trns == last_index_transparent,
-            Reverse(OrdFloat::new(pop.popularity())),
-        )
+        (trns == last_index_transparent, rank)
     });
     palette
         .iter_mut()
         .zip(tmp)
-        .for_each(|((dcol, dpop), (scol, spop))| {
+        .for_each(|((dcol, dpop), (scol, spop, _))| {
             *dcol = scol;
             *dpop = spop;
         });
@@ -476,6 +820,48 @@ fn sort_palette(attr: &Attributes, palette: &mut PalF) {
     }
 }
 
+/// Moves colors added with `add_fixed_color_at` to their indices. Order of other colors is kept.
+///
+/// Returns which entries are pinned (`None` if none are), so that they stay in place when the palette is edited.
+fn pin_colors(
+    palette: &mut PalF,
+    pinned_colors: &[(f_pixel, PalLen)],
+) -> Option<Box<[bool; MAX_COLORS]>> {
+    let len = palette.len();
+    let mut sources = [None; MAX_COLORS];
+    let mut is_pinned = [false; MAX_COLORS];
+    for &(color, index) in pinned_colors {
+        let index = usize::from(index);
+        if index >= len || sources[index].is_some() {
+            continue;
+        }
+        let source = palette
+            .as_slice()
+            .iter()
+            .enumerate()
+            .position(|(i, c)| *c == color && !is_pinned[i]);
+        if let Some(source) = source {
+            sources[index] = Some(source);
+            is_pinned[source] = true;
+        }
+    }
+    if !is_pinned.contains(&true) {
+        return None;
+    }
+
+    let mut rest = (0..len).filter(|&i| !is_pinned[i]);
+    let old = palette.clone();
+    for (index, source) in sources.iter().take(len).enumerate() {
+        let source = source.or_else(|| rest.next()).unwrap_or(index);
+        palette.set(index, old.as_slice()[source], old.pop_as_slice()[source]);
+    }
+    let mut locked = Box::new([false; MAX_COLORS]);
+    for (locked, source) in locked.iter_mut().zip(sources.iter().take(len)) {
+        *locked = source.is_some();
+    }
+    Some(locked)
+}
+
 impl fmt::Debug for QuantizationResult {
     #[cold]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -496,8 +882,14 @@ pub(crate) fn find_best_palette(
     target_mse: f64,
     target_mse_is_zero: bool,
     max_mse: Option<f64>,
+    gamma: f64,
     mut hist: HistogramInternal,
 ) -> Result<(PalF, Option<f64>), Error> {
+    if !attr.candidate_colors.is_empty() {
+        attr.verbose_print("  selecting from candidate colors...");
+        return candidate_palette(attr, &mut hist, gamma, target_mse);
+    }
+
     // hist.items includes fixed colors already
     let few_input_colors = hist.items.len() <= attr.max_colors as usize;
     // actual target_mse passed to this method has extra diff from posterization
@@ -505,6 +897,68 @@ pub(crate) fn find_best_palette(
         return Ok(palette_from_histogram(&hist, attr.max_colors));
     }
 
+    match palette_without_feedback_loop(attr, target_mse, max_mse, gamma, &mut hist) {
+        Ok(Some(palette)) => Ok(palette),
+        Ok(None) => feedback_loop_palette(attr, target_mse, max_mse, gamma, hist),
+        Err(e) => Err(e),
+    }
+}
+
+/// Grays, colors along a line, or NeuQuant, if they're selected and suit the histogram
+fn palette_without_feedback_loop(
+    attr: &Attributes,
+    target_mse: f64,
+    max_mse: Option<f64>,
+    gamma: f64,
+    hist: &mut HistogramInternal,
+) -> Result<Option<(PalF, Option<f64>)>, Error> {
+    // matched instead of `?`, which in debug builds takes stack for more copies of the palettes
+    if attr.grayscale {
+        match optimal::gray_palette(hist, attr.max_colors, target_mse) {
+            Ok(Some((palette, palette_error))) => {
+                attr.verbose_print("  selecting grays...");
+                // fixed colors replace some of the optimal ones
+                let palette_error = hist.fixed_colors.is_empty().then_some(palette_error);
+                return finish_optimal_palette(palette, palette_error, attr, hist, max_mse, gamma)
+                    .map(Some);
+            }
+            Ok(None) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    if attr.palette_algorithm == PaletteAlgorithm::Optimal {
+        match optimal::principal_axis_palette(hist, attr.max_colors, target_mse) {
+            Ok(Some(palette)) => {
+                attr.verbose_print("  selecting colors along the principal axis...");
+                return finish_optimal_palette(palette, None, attr, hist, max_mse, gamma).map(Some);
+            }
+            Ok(None) => attr.verbose_print("  colors are not on a line, using median cut"),
+            Err(e) => return Err(e),
+        }
+    }
+
+    // training is too slow to repeat in the feedback loop, k-means refines it instead
+    if attr.palette_algorithm == PaletteAlgorithm::NeuQuant {
+        attr.verbose_print("  training neural network...");
+        return match neuquant(hist, attr.max_colors, attr.speed()) {
+            Ok(palette) => {
+                finish_optimal_palette(palette, None, attr, hist, max_mse, gamma).map(Some)
+            }
+            Err(e) => Err(e),
+        };
+    }
+    Ok(None)
+}
+
+/// Generates palettes repeatedly, adjusting the number of colors and the target error to find the best one
+fn feedback_loop_palette(
+    attr: &Attributes,
+    target_mse: f64,
+    max_mse: Option<f64>,
+    gamma: f64,
+    mut hist: HistogramInternal,
+) -> Result<(PalF, Option<f64>), Error> {
     let mut max_colors = attr.max_colors;
     let total_trials = attr.feedback_loop_trials(hist.items.len()) as i16;
     let mut trials_left = total_trials;
@@ -517,13 +971,14 @@ pub(crate) fn find_best_palette(
             .max(palette_error.unwrap_or(quality_to_mse(1)))
             .max(quality_to_mse(51))
             * 1.2;
-        let mut new_palette = mediancut(
+        let mut new_palette = generate_palette(
+            attr,
             &mut hist,
             max_colors,
             target_mse * target_mse_overshoot,
             max_mse_per_color,
-        )?
-        .with_fixed_colors(attr.max_colors, &hist.fixed_colors);
+            gamma,
+        )?;
 
         let stage_done = (f32::from(trials_left.max(0)) / f32::from(total_trials + 1)).mul_add(
             -(f32::from(trials_left.max(0)) / f32::from(total_trials + 1)),
@@ -572,16 +1027,75 @@ pub(crate) fn find_best_palette(
     }
     .ok_or(ValueOutOfRange)?;
 
-    refine_palette(&mut palette, attr, &mut hist, max_mse, &mut palette_error)?;
+    refine_palette(
+        &mut palette,
+        attr,
+        &mut hist,
+        max_mse,
+        gamma,
+        &mut palette_error,
+    )?;
 
     Ok((palette, palette_error))
 }
 
+/// One palette from the algorithm selected in `attr`, with the fixed colors
+fn generate_palette(
+    attr: &Attributes,
+    hist: &mut HistogramInternal,
+    max_colors: PalLen,
+    target_mse: f64,
+    max_mse_per_color: f64,
+    gamma: f64,
+) -> Result<PalF, Error> {
+    // matched once instead of `?` per algorithm, which in debug builds takes stack for a copy of the palette each
+    let palette = match attr.palette_algorithm {
+        PaletteAlgorithm::Wu => wu(hist, max_colors, target_mse),
+        PaletteAlgorithm::Agglomerative => agglomerative(hist, max_colors, target_mse),
+        PaletteAlgorithm::Octree => {
+            Octree::from_histogram(hist, gamma).and_then(|mut tree| tree.reduce(max_colors))
+        }
+        PaletteAlgorithm::MedianCut | PaletteAlgorithm::Optimal | PaletteAlgorithm::NeuQuant => {
+            mediancut(hist, max_colors, target_mse, max_mse_per_color)
+        }
+    };
+    let mut palette = match palette {
+        Ok(palette) => palette.with_fixed_colors(attr.max_colors, &hist.fixed_colors),
+        Err(e) => return Err(e),
+    };
+    if let Some(levels) = &attr.channel_levels {
+        levels.snap_palette(&mut palette, gamma);
+    }
+    Ok(palette)
+}
+
+/// Adds fixed colors, and measures the error if it's not known exactly
+fn finish_optimal_palette(
+    palette: PalF,
+    palette_error: Option<f64>,
+    attr: &Attributes,
+    hist: &mut HistogramInternal,
+    max_mse: Option<f64>,
+    gamma: f64,
+) -> Result<(PalF, Option<f64>), Error> {
+    let mut palette = palette.with_fixed_colors(attr.max_colors, &hist.fixed_colors);
+    if let Some(levels) = &attr.channel_levels {
+        levels.snap_palette(&mut palette, gamma);
+    }
+    let mut palette_error = Some(match palette_error {
+        Some(e) => e,
+        None => Kmeans::iteration(hist, &mut palette, false)?,
+    });
+    refine_palette(&mut palette, attr, hist, max_mse, gamma, &mut palette_error)?;
+    Ok((palette, palette_error))
+}
+
 fn refine_palette(
     palette: &mut PalF,
     attr: &Attributes,
     hist: &mut HistogramInternal,
     max_mse: Option<f64>,
+    gamma: f64,
     palette_error: &mut Option<f64>,
 ) -> Result<(), Error> {
     let (iterations, iteration_limit) =
@@ -599,8 +1113,15 @@ fn refine_palette(
                 break;
             }
 
+            // the error is measured for colors that can be output
+            if let Some(levels) = &attr.channel_levels {
+                levels.snap_palette(palette, gamma);
+            }
             let pal_err = Kmeans::iteration(hist, palette, false)?;
             debug_assert!(pal_err < 1e20);
+            if attr.medoids {
+                snap_to_medoids(palette, hist)?;
+            }
             let previous_palette_error = *palette_error;
             *palette_error = Some(pal_err);
 
@@ -616,6 +1137,12 @@ fn refine_palette(
             };
         }
     }
+    if attr.medoids {
+        snap_to_medoids(palette, hist)?;
+    }
+    if let Some(levels) = &attr.channel_levels {
+        levels.snap_palette(palette, gamma);
+    }
     Ok(())
 }
 
@@ -632,6 +1159,11 @@ fn palette_from_histogram(hist: &HistogramInternal, max_colors: PalLen) -> (PalF
     )
 }
 
+/// Dithered pixels that stray further than this from the palette get their error diffusion reduced
+pub(crate) fn max_dither_error(palette_error: Option<f64>) -> f32 {
+    (palette_error.unwrap_or(quality_to_mse(80)) * 2.4).max(quality_to_mse(35)) as f32
+}
+
 pub(crate) fn quality_to_mse(quality: u8) -> f64 {
     if quality == 0 {
         return 1e20; // + epsilon for floating point errors
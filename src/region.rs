@@ -0,0 +1,153 @@
+//! Weighted areas of an image, which make pixels in them more (or less) important than the rest of the image.
+//!
+//! They're combined with the importance map (usually made by `contrast_maps`) instead of replacing it.
+use crate::error::Error;
+use crate::rows::temp_buf;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+#[derive(Clone)]
+enum Shape {
+    Rect {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+    /// Vertices in pixel coordinates
+    Polygon(Box<[(f32, f32)]>),
+    /// Coverage of every pixel of the image, 255 = fully inside
+    Mask(Box<[u8]>),
+}
+
+#[derive(Clone)]
+pub(crate) struct Region {
+    shape: Shape,
+    weight: f32,
+}
+
+impl Region {
+    pub(crate) fn rect(x: usize, y: usize, width: usize, height: usize, weight: f32) -> Self {
+        Self {
+            shape: Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            weight,
+        }
+    }
+
+    pub(crate) fn polygon(points: Box<[(f32, f32)]>, weight: f32) -> Self {
+        Self {
+            shape: Shape::Polygon(points),
+            weight,
+        }
+    }
+
+    pub(crate) fn mask(mask: Box<[u8]>, weight: f32) -> Self {
+        Self {
+            shape: Shape::Mask(mask),
+            weight,
+        }
+    }
+
+    /// Multiplies weights of pixels in the `row` covered by this region. Overlapping regions multiply their weights.
+    fn apply_to_row(&self, row: usize, weights: &mut [f32], crossings: &mut Vec<f32>) {
+        let width = weights.len();
+        match &self.shape {
+            Shape::Rect {
+                x,
+                y,
+                width: w,
+                height: h,
+            } => {
+                if (*y..y.saturating_add(*h)).contains(&row) {
+                    let start = (*x).min(width);
+                    let end = x.saturating_add(*w).min(width);
+                    weights[start..end]
+                        .iter_mut()
+                        .for_each(|v| *v *= self.weight);
+                }
+            }
+            Shape::Polygon(points) => {
+                // even-odd rule, sampled at pixel centers
+                let y = row as f32 + 0.5;
+                crossings.clear();
+                for (a, b) in points.iter().zip(points.iter().cycle().skip(1)) {
+                    if (a.1 <= y) != (b.1 <= y) {
+                        crossings.push((y - a.1).mul_add((b.0 - a.0) / (b.1 - a.1), a.0));
+                    }
+                }
+                crossings.sort_unstable_by(f32::total_cmp);
+                for span in crossings.chunks_exact(2) {
+                    let start = first_pixel_after(span[0]).min(width);
+                    let end = first_pixel_after(span[1]).min(width);
+                    weights[start..end]
+                        .iter_mut()
+                        .for_each(|v| *v *= self.weight);
+                }
+            }
+            Shape::Mask(mask) => {
+                let Some(mask) = mask.get(row * width..row * width + width) else {
+                    return;
+                };
+                for (v, &m) in weights.iter_mut().zip(mask) {
+                    *v *= (self.weight - 1.).mul_add(f32::from(m) / 255., 1.);
+                }
+            }
+        }
+    }
+}
+
+/// Index of the first pixel whose center is at or after `x`
+fn first_pixel_after(x: f32) -> usize {
+    let x = (x - 0.5).max(0.);
+    let i = x as usize;
+    if (i as f32) < x {
+        i + 1
+    } else {
+        i
+    }
+}
+
+/// Multiplies the importance map by weights of the regions.
+///
+/// The map is 8-bit, so if any weight is above 1, the whole map is scaled down to make room for it.
+pub(crate) fn apply_regions(
+    regions: &[Region],
+    map: &mut [u8],
+    width: usize,
+    height: usize,
+) -> Result<(), Error> {
+    let mut weights = temp_buf(width)?;
+    let mut crossings = Vec::new();
+    let mut row_weights = |row: usize, weights: &mut [f32]| {
+        weights.fill(1.);
+        for region in regions {
+            region.apply_to_row(row, weights, &mut crossings);
+        }
+    };
+
+    let mut max_weight = 1f32;
+    for row in 0..height {
+        row_weights(row, &mut weights);
+        max_weight = weights.iter().copied().fold(max_weight, f32::max);
+    }
+
+    let scale = 1. / max_weight;
+    for (row, map_row) in map.chunks_exact_mut(width).take(height).enumerate() {
+        row_weights(row, &mut weights);
+        for (m, &w) in map_row.iter_mut().zip(weights.iter()) {
+            if w <= 0. || *m == 0 {
+                *m = 0;
+            } else {
+                // pixels with any weight must stay in the histogram
+                *m = (f32::from(*m) * w).mul_add(scale, 0.5).clamp(1., 255.) as u8;
+            }
+        }
+    }
+    Ok(())
+}
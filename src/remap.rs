@@ -1,7 +1,9 @@
+use crate::attr::ContentMode;
 use crate::error::Error;
 use crate::image::Image;
 use crate::kmeans::Kmeans;
 use crate::nearest::Nearest;
+use crate::octree::Octree;
 use crate::pal::{f_pixel, PalF, PalIndexRemap, Palette, ARGBF};
 use crate::quant::QuantizationResult;
 use crate::rayoff::*;
@@ -32,9 +34,18 @@ pub(crate) fn remap_to_palette<'x, 'b: 'x>(
     px: &mut DynamicRows,
     background: Option<&mut Image<'_>>,
     importance_map: Option<&[u8]>,
+    first_row: usize,
     output_pixels: &'x mut RowBitmapMut<'b, PalIndexRemap>,
     palette: &mut PalF,
+    octree: Option<&Octree>,
 ) -> Result<(f64, RowBitmap<'x, PalIndexRemap>), Error> {
+    if let (Some(tree), None, false) = (octree, &background, px.grayscale) {
+        // the tree needs 8-bit rows, which are gone if the image has been freed
+        if px.rgba_rows_iter().is_ok() {
+            return tree.remap(px, first_row, output_pixels, palette);
+        }
+    }
+
     let n = Nearest::new(palette)?;
     let colors = palette.as_slice();
     let palette_len = colors.len();
@@ -85,6 +96,7 @@ pub(crate) fn remap_to_palette<'x, 'b: 'x>(
         .enumerate()
         .par_bridge()
         .map(|(row, output_pixels_row)| {
+            let row = first_row + row;
             let mut remapping_error = 0.;
             #[allow(irrefutable_let_patterns)]
             let Ok(tls_res) = tls.get_or_try(per_thread_buffers) else {
@@ -185,12 +197,25 @@ fn get_dithered_pixel(
     })
 }
 
+pub(crate) fn base_dithering_level(dither_level: f32, has_dither_map: bool) -> f32 {
+    // response to this value is non-linear and without it any value < 0.8 would give almost no dithering
+    let mut base_dithering_level =
+        (1. - dither_level).mul_add(-(1. - dither_level), 1.) * (15. / 16.); // prevent small errors from accumulating
+    if has_dither_map {
+        base_dithering_level *= 1. / 255.; // dither_map is in 0-255 scale
+    }
+    base_dithering_level
+}
+
 /// Uses edge/noise map to apply dithering only to flat areas. Dithering on edges creates jagged lines, and noisy areas are "naturally" dithered.
 ///
 ///  If `output_image_is_remapped` is true, only pixels noticeably changed by error diffusion will be written to output image.
+///
+/// `output_pixels` may cover only some rows of the image, starting at `first_row`.
 #[inline(never)]
 pub(crate) fn remap_to_palette_floyd(
     input_image: &mut Image,
+    first_row: usize,
     mut output_pixels: RowBitmapMut<'_, PalIndexRemap>,
     palette: &PalF,
     quant: &QuantizationResult,
@@ -204,19 +229,21 @@ pub(crate) fn remap_to_palette_floyd(
     };
 
     let width = input_image.width();
-    let height = input_image.height();
+    let image_height = input_image.height();
+    let height = output_pixels.len();
 
     let mut temp_row = temp_buf(width)?;
 
-    let dither_map = if quant.use_dither_map != DitherMapMode::None {
-        input_image
-            .dither_map
-            .as_deref()
-            .or(input_image.edges.as_deref())
-            .unwrap_or(&[])
-    } else {
-        &[]
-    };
+    let dither_map =
+        if quant.use_dither_map != DitherMapMode::None || quant.content_mode == ContentMode::Text {
+            input_image
+                .dither_map
+                .as_deref()
+                .or(input_image.edges.as_deref())
+                .unwrap_or(&[])
+        } else {
+            &[]
+        };
 
     let n = Nearest::new(palette)?;
     let palette = palette.as_slice();
@@ -238,12 +265,7 @@ pub(crate) fn remap_to_palette_floyd(
     if background.is_some() && !palette[transparent_index as usize].is_fully_transparent() {
         background = None;
     }
-    // response to this value is non-linear and without it any value < 0.8 would give almost no dithering
-    let mut base_dithering_level =
-        (1. - quant.dither_level).mul_add(-(1. - quant.dither_level), 1.) * (15. / 16.); // prevent small errors from accumulating
-    if !dither_map.is_empty() {
-        base_dithering_level *= 1. / 255.; // dither_map is in 0-255 scale
-    }
+    let base_dithering_level = base_dithering_level(quant.dither_level, !dither_map.is_empty());
 
     // when using remapping on top of a background, lots of pixels may be transparent, making poor guesses
     // (guesses are only for speed, don't affect visuals)
@@ -266,7 +288,7 @@ pub(crate) fn remap_to_palette_floyd(
         .chunks((height + num_chunks - 1) / num_chunks)
         .map(CacheLineAlign);
     scope(move |s| {
-        let mut chunk_start_row = 0;
+        let mut chunk_start_row = first_row;
         for mut chunk in chunks {
             let chunk_len = chunk.0.len();
             let mut temp_row = temp_buf(width)?;
@@ -311,7 +333,8 @@ pub(crate) fn remap_to_palette_floyd(
             // parallel remap makes progress not very useful
             if quant.remap_progress(
                 progress_stage1 as f32
-                    + chunk_start_row as f32 * (100. - progress_stage1 as f32) / height as f32,
+                    + chunk_start_row as f32 * (100. - progress_stage1 as f32)
+                        / image_height as f32,
             ) {
                 return Err(Error::Aborted);
             }
@@ -351,7 +374,7 @@ pub(crate) fn remap_to_palette_floyd(
 }
 
 #[inline(never)]
-fn dither_row(
+pub(crate) fn dither_row(
     row_pixels: &[f_pixel],
     output_pixels_row: &mut [PalIndexRemap],
     width: u32,
@@ -1,5 +1,8 @@
 use crate::error::Error;
-use crate::pal::{f_pixel, gamma_lut, RGBA};
+use crate::pal::{
+    f32_to_rgba16, f_pixel, gamma_exponent, gamma_lut, to_gray, to_gray16, to_gray_f32, RGBA,
+    RGBA16,
+};
 #[cfg(feature = "_internal_c_ffi")]
 use crate::seacow::Pointer;
 use crate::seacow::SeaCow;
@@ -7,6 +10,7 @@ use crate::LIQ_HIGH_MEMORY_LIMIT;
 use core::mem::size_of;
 #[cfg(feature = "_internal_c_ffi")]
 use core::slice;
+use rgb::prelude::*;
 
 #[cfg(all(not(feature = "std"), feature = "no_std"))]
 use std::{boxed::Box, vec, vec::Vec};
@@ -31,9 +35,31 @@ pub(crate) enum PixelsSource<'pixels, 'rows> {
         pixels: Option<SeaCow<'pixels, RGBA>>,
     },
     Callback(Box<RowCallback<'rows>>),
+    /// 16 bits per channel, contiguous rows without padding
+    Rgba16(SeaCow<'pixels, RGBA16>),
+    /// `[r, g, b, a]` in 0..1 range, contiguous rows without padding
+    Float(SeaCow<'pixels, [f32; 4]>),
 }
 
 impl<'pixels> PixelsSource<'pixels, '_> {
+    /// For high-bit-depth pixels, which don't support stride
+    pub(crate) fn check_area<T>(
+        pixels: &SeaCow<'pixels, T>,
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        if height == 0 || width == 0 {
+            return Err(Error::ValueOutOfRange);
+        }
+        let area = (width as usize)
+            .checked_mul(height as usize)
+            .ok_or(Error::ValueOutOfRange)?;
+        if pixels.as_slice().len() < area {
+            return Err(Error::BufferTooSmall);
+        }
+        Ok(())
+    }
+
     pub(crate) fn for_pixels(
         pixels: SeaCow<'pixels, RGBA>,
         width: u32,
@@ -76,6 +102,10 @@ pub(crate) struct DynamicRows<'pixels, 'rows> {
     f_pixels: Option<Box<[f_pixel]>>,
     pixels: PixelsSource<'pixels, 'rows>,
     pub(crate) gamma: f64,
+    /// RGBA pixels have color channels already multiplied by alpha
+    pub(crate) premultiplied: bool,
+    /// Colors are converted to luma, see `Attributes::set_grayscale`
+    pub(crate) grayscale: bool,
 }
 
 impl Clone for DynamicRows<'_, '_> {
@@ -109,8 +139,12 @@ impl Clone for DynamicRows<'_, '_> {
                     let pixels = SeaCow::boxed(out.into_boxed_slice());
                     PixelsSource::for_pixels(pixels, self.width, self.height, self.width).unwrap()
                 }
+                PixelsSource::Rgba16(pixels) => PixelsSource::Rgba16(pixels.clone()),
+                PixelsSource::Float(pixels) => PixelsSource::Float(pixels.clone()),
             },
             gamma: self.gamma,
+            premultiplied: self.premultiplied,
+            grayscale: self.grayscale,
         }
     }
 }
@@ -128,11 +162,10 @@ impl DynamicRowsIter<'_, '_, '_> {
             let start = self.px.width as usize * row;
             &pixels[start..start + self.px.width as usize]
         } else {
-            let lut = gamma_lut(self.px.gamma);
-            let row_pixels = self.px.row_rgba(temp_row, row);
-
             match self.temp_f_row.as_mut() {
-                Some(t) => DynamicRows::convert_row_to_f(t, row_pixels, &lut),
+                Some(t) => self
+                    .px
+                    .row_f_uncached(temp_row, t, row, &gamma_lut(self.px.gamma)),
                 None => &mut [], // this can't happen
             }
         }
@@ -148,10 +181,8 @@ impl DynamicRowsIter<'_, '_, '_> {
         if let Some(pixels) = self.px.f_pixels.as_ref() {
             &pixels[self.px.width as usize * row..]
         } else {
-            let lut = gamma_lut(self.px.gamma);
-            let row_pixels = self.px.row_rgba(temp_row, row);
-
-            DynamicRows::convert_row_to_f(temp_row_f, row_pixels, &lut)
+            self.px
+                .row_f_uncached(temp_row, temp_row_f, row, &gamma_lut(self.px.gamma))
         }
     }
 
@@ -176,6 +207,8 @@ impl<'pixels, 'rows> DynamicRows<'pixels, 'rows> {
             f_pixels: None,
             pixels,
             gamma,
+            premultiplied: false,
+            grayscale: false,
         }
     }
 
@@ -205,21 +238,148 @@ impl<'pixels, 'rows> DynamicRows<'pixels, 'rows> {
                 cb(temp_row, row);
                 temp_row
             }
+            // 8-bit rounding only for the APIs that need RGBA, f_pixels are converted directly
+            PixelsSource::Rgba16(pixels) => {
+                let start = row * self.width();
+                for (dst, src) in temp_row
+                    .iter_mut()
+                    .zip(&pixels.as_slice()[start..start + self.width()])
+                {
+                    *dst = src.map(|c| ((u32::from(c) * 255 + 32895) >> 16) as u8);
+                }
+                temp_row
+            }
+            PixelsSource::Float(pixels) => {
+                let start = row * self.width();
+                for (dst, src) in temp_row
+                    .iter_mut()
+                    .zip(&pixels.as_slice()[start..start + self.width()])
+                {
+                    *dst = f32_to_rgba16(*src).map(|c| ((u32::from(c) * 255 + 32895) >> 16) as u8);
+                }
+                temp_row
+            }
+        }
+    }
+
+    /// Whether pixels have more precision than RGBA, and should use [`DynamicRows::row_rgba16`]
+    #[inline]
+    pub(crate) fn is_high_depth(&self) -> bool {
+        matches!(
+            self.pixels,
+            PixelsSource::Rgba16(_) | PixelsSource::Float(_)
+        )
+    }
+
+    /// Original pixels at 16-bit precision. Alpha is premultiplied if the image is.
+    ///
+    /// Only for images that [have high bit depth](Self::is_high_depth).
+    pub(crate) fn row_rgba16<'px>(
+        &'px self,
+        temp_row: &'px mut [RGBA16],
+        row: usize,
+    ) -> &'px [RGBA16] {
+        let width = self.width();
+        match &self.pixels {
+            PixelsSource::Rgba16(pixels) => &pixels.as_slice()[row * width..][..width],
+            PixelsSource::Float(pixels) => {
+                for (dst, src) in temp_row
+                    .iter_mut()
+                    .zip(&pixels.as_slice()[row * width..][..width])
+                {
+                    *dst = f32_to_rgba16(*src);
+                }
+                temp_row
+            }
+            _ => {
+                debug_assert!(false, "8-bit rows are read with row_rgba");
+                &[]
+            }
+        }
+    }
+
+    /// Converts the row directly from the source, so that high-bit-depth pixels don't get rounded to RGBA
+    fn row_f_uncached<'f>(
+        &self,
+        temp_row: &mut [RGBA],
+        row_f_pixels: &'f mut [f_pixel],
+        row: usize,
+        gamma_lut: &[f32; 256],
+    ) -> &'f mut [f_pixel] {
+        let width = self.width();
+        match &self.pixels {
+            PixelsSource::Rgba16(pixels) => {
+                let exp = gamma_exponent(self.gamma);
+                for (dst, src) in row_f_pixels
+                    .iter_mut()
+                    .zip(&pixels.as_slice()[row * width..][..width])
+                {
+                    let src = if self.grayscale {
+                        to_gray16(*src)
+                    } else {
+                        *src
+                    };
+                    *dst = f_pixel::from_rgba16(exp, src, self.premultiplied);
+                }
+                row_f_pixels
+            }
+            PixelsSource::Float(pixels) => {
+                let exp = gamma_exponent(self.gamma);
+                for (dst, src) in row_f_pixels
+                    .iter_mut()
+                    .zip(&pixels.as_slice()[row * width..][..width])
+                {
+                    let src = if self.grayscale {
+                        to_gray_f32(*src)
+                    } else {
+                        *src
+                    };
+                    *dst = f_pixel::from_rgba_f32(exp, src, self.premultiplied);
+                }
+                row_f_pixels
+            }
+            _ => {
+                let row_pixels = self.row_rgba(temp_row, row);
+                self.convert_row_to_f(row_f_pixels, row_pixels, gamma_lut)
+            }
         }
     }
 
     fn convert_row_to_f<'f>(
+        &self,
         row_f_pixels: &'f mut [f_pixel],
         row_pixels: &[RGBA],
         gamma_lut: &[f32; 256],
     ) -> &'f mut [f_pixel] {
         assert_eq!(row_f_pixels.len(), row_pixels.len());
-        for (dst, src) in row_f_pixels.iter_mut().zip(row_pixels) {
-            *dst = f_pixel::from_rgba(gamma_lut, *src);
+        let grayscale = self.grayscale;
+        if self.premultiplied {
+            for (dst, src) in row_f_pixels.iter_mut().zip(row_pixels) {
+                let src = if grayscale { to_gray(*src) } else { *src };
+                *dst = f_pixel::from_premultiplied_rgba(gamma_lut, self.gamma, src);
+            }
+        } else {
+            for (dst, src) in row_f_pixels.iter_mut().zip(row_pixels) {
+                let src = if grayscale { to_gray(*src) } else { *src };
+                *dst = f_pixel::from_rgba(gamma_lut, src);
+            }
         }
         row_f_pixels
     }
 
+    /// Changing it invalidates converted pixels, so it fails if the RGBA source has already been freed
+    pub(crate) fn set_premultiplied(&mut self, premultiplied: bool) -> Result<(), Error> {
+        if self.premultiplied == premultiplied {
+            return Ok(());
+        }
+        if self.f_pixels.is_some() {
+            self.rgba_rows_iter()?;
+            self.f_pixels = None;
+        }
+        self.premultiplied = premultiplied;
+        Ok(())
+    }
+
     #[must_use]
     fn should_use_low_memory(&self) -> bool {
         self.width() * self.height() > LIQ_HIGH_MEMORY_LIMIT / size_of::<f_pixel>()
@@ -248,8 +408,7 @@ impl<'pixels, 'rows> DynamicRows<'pixels, 'rows> {
         let lut = gamma_lut(self.gamma);
         let mut f_pixels = temp_buf(width * self.height())?;
         for (row, f_row) in f_pixels.chunks_exact_mut(width).enumerate() {
-            let row_pixels = self.row_rgba(temp_row, row);
-            Self::convert_row_to_f(f_row, row_pixels, &lut);
+            self.row_f_uncached(temp_row, f_row, row, &lut);
         }
         self.f_pixels = Some(f_pixels);
         Ok(())
@@ -297,10 +456,9 @@ impl<'pixels, 'rows> DynamicRows<'pixels, 'rows> {
 
     #[inline]
     pub fn all_rows_f(&mut self) -> Result<&[f_pixel], Error> {
-        if self.f_pixels.is_some() {
-            return Ok(self.f_pixels.as_ref().unwrap()); // borrow-checker :(
+        if self.f_pixels.is_none() {
+            self.prepare_iter(&mut temp_buf(self.width())?, false)?;
         }
-        self.prepare_iter(&mut temp_buf(self.width())?, false)?;
         self.f_pixels.as_deref().ok_or(Error::Unsupported)
     }
 
@@ -0,0 +1,166 @@
+//! Estimates which parts of the image stand out, so that the subject gets more palette precision than the background.
+//!
+//! It works on a small copy of the image, combining frequency-tuned saliency (distance of the smoothed color
+//! from the average color of the image) with center-surround contrast at a few scales, and a mild center bias.
+use crate::error::Error;
+use crate::pal::ARGBF;
+use crate::rows::{temp_buf, DynamicRows};
+use core::ops::{Add, Mul};
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Longer side of the downscaled copy
+const SIZE: usize = 64;
+/// Importance of the least salient areas, relative to the most salient ones
+const BACKGROUND_WEIGHT: f32 = 0.25;
+
+/// Multiplies the importance `map` by saliency of the pixels
+pub(crate) fn apply_saliency(px: &mut DynamicRows, map: &mut [u8]) -> Result<(), Error> {
+    let width = px.width();
+    let height = px.height();
+    let small_width = (width * SIZE / width.max(height)).max(1);
+    let small_height = (height * SIZE / width.max(height)).max(1);
+
+    let small = downscale(px, small_width, small_height)?;
+    let saliency = saliency(&small, small_width, small_height);
+
+    for (y, map_row) in map.chunks_exact_mut(width).take(height).enumerate() {
+        let (y0, y1, fy) = sample_position(y, height, small_height);
+        for (x, m) in map_row.iter_mut().enumerate() {
+            if *m == 0 {
+                continue;
+            }
+            let (x0, x1, fx) = sample_position(x, width, small_width);
+            let top = lerp(
+                saliency[y0 * small_width + x0],
+                saliency[y0 * small_width + x1],
+                fx,
+            );
+            let bottom = lerp(
+                saliency[y1 * small_width + x0],
+                saliency[y1 * small_width + x1],
+                fx,
+            );
+            let s = lerp(top, bottom, fy);
+            let weight = (1. - BACKGROUND_WEIGHT).mul_add(s, BACKGROUND_WEIGHT);
+            *m = (f32::from(*m) * weight).max(1.) as u8;
+        }
+    }
+    Ok(())
+}
+
+/// Averages blocks of pixels
+fn downscale(
+    px: &mut DynamicRows,
+    small_width: usize,
+    small_height: usize,
+) -> Result<Vec<ARGBF>, Error> {
+    let width = px.width();
+    let height = px.height();
+    let mut sums = Vec::new();
+    sums.try_reserve_exact(small_width * small_height)?;
+    sums.resize(small_width * small_height, (ARGBF::default(), 0u32));
+
+    let mut temp_row = temp_buf(width)?;
+    let mut rows = px.rows_iter(&mut temp_row)?;
+    for y in 0..height {
+        let sums_row = &mut sums[y * small_height / height * small_width..][..small_width];
+        for (x, p) in rows.row_f(&mut temp_row, y).iter().enumerate() {
+            let (sum, count) = &mut sums_row[x * small_width / width];
+            *sum += p.0;
+            *count += 1;
+        }
+    }
+    Ok(sums
+        .into_iter()
+        .map(|(sum, count)| sum * (1. / count.max(1) as f32))
+        .collect())
+}
+
+/// Saliency of each pixel of the small image, normalized to 0-1
+fn saliency(small: &[ARGBF], width: usize, height: usize) -> Vec<f32> {
+    let mean = small.iter().fold(ARGBF::default(), |a, &b| a + b) * (1. / small.len() as f32);
+    let fine = blur(small, width, height, 1);
+    let mut saliency: Vec<f32> = fine.iter().map(|&c| distance(c, mean)).collect();
+    for radius in [SIZE / 32, SIZE / 16, SIZE / 8] {
+        let surround = blur(small, width, height, radius);
+        for ((s, &c), &surround) in saliency.iter_mut().zip(&fine).zip(&surround) {
+            *s += distance(c, surround) * (1. / 3.);
+        }
+    }
+
+    let saliency = blur(&saliency, width, height, 1);
+    let max = saliency.iter().copied().fold(0., f32::max);
+    let scale = if max > 0. { 1. / max } else { 0. };
+    saliency
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| {
+            // subjects of photos tend to be in the middle
+            let dx = (i % width) as f32 / width as f32 - 0.5;
+            let dy = (i / width) as f32 / height as f32 - 0.5;
+            let center_bias = 1. - 0.5 * dy.mul_add(dy, dx * dx);
+            s * scale * center_bias
+        })
+        .collect()
+}
+
+#[inline]
+fn distance(a: ARGBF, b: ARGBF) -> f32 {
+    let d = a - b;
+    (d.a * d.a + d.r * d.r + d.g * d.g + d.b * d.b).sqrt()
+}
+
+/// Two passes of a separable box blur, which is close enough to gaussian
+fn blur<T>(src: &[T], width: usize, height: usize, radius: usize) -> Vec<T>
+where
+    T: Copy + Default + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let mut out = src.to_vec();
+    let mut tmp = src.to_vec();
+    for _ in 0..2 {
+        box_blur_pass(&out, &mut tmp, width, height, radius, 1, width);
+        box_blur_pass(&tmp, &mut out, height, width, radius, width, 1);
+    }
+    out
+}
+
+/// Blurs `lines` lines of `len` samples, where samples are `step` apart and lines are `line_step` apart
+fn box_blur_pass<T>(
+    src: &[T],
+    dst: &mut [T],
+    len: usize,
+    lines: usize,
+    radius: usize,
+    step: usize,
+    line_step: usize,
+) where
+    T: Copy + Default + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let scale = 1. / (radius * 2 + 1) as f32;
+    for line in 0..lines {
+        let at = |i: usize| src[line * line_step + i.min(len - 1) * step];
+        let mut sum =
+            (0..=radius * 2).fold(T::default(), |sum, i| sum + at(i.saturating_sub(radius)));
+        for i in 0..len {
+            dst[line * line_step + i * step] = sum * scale;
+            // edge pixels are repeated
+            sum = sum + at(i + radius + 1) + at(i.saturating_sub(radius)) * -1.;
+        }
+    }
+}
+
+/// Nearest two samples of the small image and interpolation between them
+#[inline]
+fn sample_position(pos: usize, size: usize, small_size: usize) -> (usize, usize, f32) {
+    let p = ((pos as f32 + 0.5) * small_size as f32 / size as f32 - 0.5).max(0.);
+    let p0 = (p as usize).min(small_size - 1);
+    let p1 = (p0 + 1).min(small_size - 1);
+    (p0, p1, p - p0 as f32)
+}
+
+#[inline]
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    (b - a).mul_add(t, a)
+}
@@ -236,9 +236,9 @@ impl<'a, T, I: Iterator<Item = &'a [T]>> Iterator for RowBitmapIter<'a, T, I> {
 }
 
 #[cfg(feature = "_internal_c_ffi")]
-enum MutCow<'a, T: ?Sized> {
-    Owned(Box<T>),
+pub(crate) enum MutCow<'a, T: ?Sized> {
     #[allow(dead_code)]
+    Owned(Box<T>),
     /// This is optional, for FFI only
     Borrowed(&'a mut T),
 }
@@ -0,0 +1,303 @@
+//! Quantization of images that are too large to be held in memory at once.
+//!
+//! Rows are read from a [`RowSource`] in bands, with a few extra rows around each band, so that contrast maps
+//! and dithering work almost as if the whole image was available. Memory use is proportional to the width of the image.
+use crate::attr::ContentMode;
+use crate::error::Error;
+use crate::hist::Histogram;
+use crate::image::Image;
+use crate::nearest::Nearest;
+use crate::pal::{f_pixel, PalIndexRemap, Palette, MAX_COLORS, RGBA};
+use crate::quant::{max_dither_error, QuantizationResult};
+use crate::remap::{base_dithering_level, dither_row, remap_to_palette, DitherMapMode, Remapped};
+use crate::rows::temp_buf;
+use crate::seacow::RowBitmapMut;
+use crate::Attributes;
+use core::ops::Range;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Rows processed at a time
+const BAND_ROWS: usize = 64;
+/// Contrast maps are blurred and eroded, so they need this many rows of context above and below the band
+const CONTRAST_HALO: usize = 16;
+
+/// Supplies pixels of an image in bands of rows, for [`Histogram::add_rows`] and [`QuantizationResult::remap_rows`].
+///
+/// Rows are requested from top to bottom, each row exactly once per pass, so the source can be a decoder or a renderer that can't seek.
+/// Quantization needs two passes over the image: one to build the histogram, and one to remap it.
+///
+/// It's implemented for closures `FnMut(first_row, rows) -> Result<(), Error>`.
+pub trait RowSource {
+    /// Fill `rows` with consecutive rows of pixels, starting at row `first_row` (0-indexed).
+    ///
+    /// The slice's length is a multiple of the image width.
+    fn read_rows(&mut self, first_row: usize, rows: &mut [RGBA]) -> Result<(), Error>;
+}
+
+impl<F: FnMut(usize, &mut [RGBA]) -> Result<(), Error>> RowSource for F {
+    #[inline(always)]
+    fn read_rows(&mut self, first_row: usize, rows: &mut [RGBA]) -> Result<(), Error> {
+        self(first_row, rows)
+    }
+}
+
+/// Receives remapped rows of palette indices, for [`QuantizationResult::remap_rows`].
+///
+/// Rows are written in order from top to bottom, each row exactly once.
+///
+/// It's implemented for closures `FnMut(first_row, indices) -> Result<(), Error>`.
+pub trait RowSink {
+    /// Consume consecutive rows of palette indices, starting at row `first_row` (0-indexed).
+    ///
+    /// The slice's length is a multiple of the image width.
+    fn write_rows(&mut self, first_row: usize, indices: &[PalIndexRemap]) -> Result<(), Error>;
+}
+
+impl<F: FnMut(usize, &[PalIndexRemap]) -> Result<(), Error>> RowSink for F {
+    #[inline(always)]
+    fn write_rows(&mut self, first_row: usize, indices: &[PalIndexRemap]) -> Result<(), Error> {
+        self(first_row, indices)
+    }
+}
+
+/// Sliding window over the source. Rows of the halo are kept between bands, so that they're not read twice.
+struct Bands<'s> {
+    source: &'s mut dyn RowSource,
+    width: usize,
+    height: usize,
+    halo: usize,
+    window: Vec<RGBA>,
+    window_start: usize,
+    next_band: usize,
+}
+
+impl<'s> Bands<'s> {
+    fn new(
+        source: &'s mut dyn RowSource,
+        width: usize,
+        height: usize,
+        halo: usize,
+    ) -> Result<Self, Error> {
+        let mut window = Vec::new();
+        window.try_reserve_exact(width * (BAND_ROWS + 2 * halo).min(height))?;
+        Ok(Self {
+            source,
+            width,
+            height,
+            halo,
+            window,
+            window_start: 0,
+            next_band: 0,
+        })
+    }
+
+    /// Returns rows of the next band, and the window of pixels around it, which starts at `window_start` row.
+    fn next(&mut self) -> Result<Option<(Range<usize>, &[RGBA])>, Error> {
+        if self.next_band >= self.height {
+            return Ok(None);
+        }
+        let band = self.next_band..(self.next_band + BAND_ROWS).min(self.height);
+        let window_start = band.start.saturating_sub(self.halo);
+        let window_end = (band.end + self.halo).min(self.height);
+
+        let loaded_end = self.window_start + self.window.len() / self.width;
+        let unused = ((window_start - self.window_start) * self.width).min(self.window.len());
+        self.window.drain(..unused);
+        self.window_start = window_start;
+
+        let loaded = self.window.len();
+        self.window
+            .resize((window_end - window_start) * self.width, RGBA::default());
+        if loaded < self.window.len() {
+            self.source
+                .read_rows(loaded_end.max(window_start), &mut self.window[loaded..])?;
+        }
+        self.next_band = band.end;
+        Ok(Some((band, &self.window)))
+    }
+}
+
+fn check_size(width: usize, height: usize, gamma: f64) -> Result<(), Error> {
+    if width == 0 || height == 0 || !(0. ..=1.).contains(&gamma) {
+        return Err(Error::ValueOutOfRange);
+    }
+    Ok(())
+}
+
+pub(crate) fn add_rows(
+    hist: &mut Histogram,
+    attr: &Attributes,
+    source: &mut dyn RowSource,
+    width: usize,
+    height: usize,
+    gamma: f64,
+) -> Result<(), Error> {
+    check_size(width, height, gamma)?;
+    let halo = if attr.use_contrast_maps {
+        CONTRAST_HALO
+    } else {
+        0
+    };
+    let mut bands = Bands::new(source, width, height, halo)?;
+    while let Some((band, window)) = bands.next()? {
+        let band_offset = (band.start - band.start.saturating_sub(halo)) * width;
+        let band_len = band.len() * width;
+        let mut image = Image::new_band(
+            &window[band_offset..band_offset + band_len],
+            width,
+            gamma,
+            attr.grayscale,
+        )?;
+        if attr.use_contrast_maps {
+            let mut window_image = Image::new_band(window, width, gamma, attr.grayscale)?;
+            window_image.contrast_maps()?;
+            if attr.content_mode == ContentMode::Text {
+                window_image.raise_importance_of_edges();
+            }
+            if let Some(map) = window_image.importance_map.take() {
+                let mut map = map.into_vec();
+                map.drain(..band_offset);
+                map.truncate(band_len);
+                image.importance_map = Some(map.into_boxed_slice());
+            }
+        }
+        // saliency is skipped, because it would be estimated for every band separately
+        hist.add_image_with_importance_map(attr, &mut image)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn remap_rows(
+    quant: &QuantizationResult,
+    source: &mut dyn RowSource,
+    width: usize,
+    height: usize,
+    gamma: f64,
+    sink: &mut dyn RowSink,
+) -> Result<Box<Remapped>, Error> {
+    check_size(width, height, gamma)?;
+
+    // The palette can't be refined after remapping, because the first rows are already gone by then
+    let mut palette = quant.palette.clone();
+    let mut remapped = Box::new(Remapped {
+        int_palette: Palette {
+            count: 0,
+            entries: [RGBA::default(); MAX_COLORS],
+        },
+        palette_error: quant.palette_error,
+    });
+    palette.init_int_palette(
+        &mut remapped.int_palette,
+        quant.gamma,
+        quant.min_posterization_output,
+        quant.channel_levels.as_ref(),
+        quant.output_premultiplied,
+        quant.grayscale,
+    );
+
+    let dithering = quant.dither_level > 0.;
+    let use_edges = dithering
+        && (quant.use_dither_map != DitherMapMode::None || quant.content_mode == ContentMode::Text);
+    let generate_dither_map = dithering
+        && (quant.use_dither_map == DitherMapMode::Always
+            || (quant.use_dither_map != DitherMapMode::None && width * height <= 2000 * 2000));
+    let halo = if use_edges { CONTRAST_HALO } else { 0 };
+
+    let n = Nearest::new(&palette)?;
+    let max_dither_error = max_dither_error(quant.palette_error);
+    let mut temp_row = temp_buf(width)?;
+    let mut output = temp_buf(width * (BAND_ROWS + 2 * halo).min(height))?;
+    // carried over from band to band, so that there are no seams between them
+    let mut diffusion = temp_buf::<f_pixel>((width + 2) * 2)?;
+    let mut remapping_error = 0.;
+
+    let mut bands = Bands::new(source, width, height, halo)?;
+    while let Some((band, window)) = bands.next()? {
+        if quant.remap_progress(band.start as f32 * 100. / height as f32) {
+            return Err(Error::Aborted);
+        }
+        let window_start = band.start.saturating_sub(halo);
+        let band_offset = (band.start - window_start) * width;
+        let band_len = band.len() * width;
+        let mut image = Image::new_band(window, width, gamma, quant.grayscale)?;
+        let window_output = &mut output[..window.len()];
+
+        if !dithering {
+            let mut output_pixels = RowBitmapMut::new_contiguous(window_output, width);
+            let (error, _) = remap_to_palette(
+                &mut image.px,
+                None,
+                None,
+                0,
+                &mut output_pixels,
+                &mut palette.clone(),
+                quant.octree.as_deref(),
+            )?;
+            remapping_error += error * band_len as f64;
+        } else {
+            if use_edges {
+                image.contrast_maps()?;
+            }
+            if generate_dither_map {
+                let mut output_pixels = RowBitmapMut::new_contiguous(&mut *window_output, width);
+                let (_, remapped_rows) = remap_to_palette(
+                    &mut image.px,
+                    None,
+                    None,
+                    0,
+                    &mut output_pixels,
+                    &mut palette.clone(),
+                    None,
+                )?;
+                image.update_dither_map(&remapped_rows, &palette, false)?;
+            }
+            if quant.content_mode == ContentMode::Text {
+                image.suppress_dithering_on_edges()?;
+            }
+            image.px.prepare_iter(&mut temp_row, true)?;
+            let dither_map = if use_edges {
+                image
+                    .dither_map
+                    .as_deref()
+                    .or(image.edges.as_deref())
+                    .unwrap_or(&[])
+            } else {
+                &[]
+            };
+            let base_dithering_level =
+                base_dithering_level(quant.dither_level, !dither_map.is_empty());
+            let mut rows = image.px.rows_iter_prepared()?;
+            let band_output =
+                window_output[band_offset..band_offset + band_len].chunks_exact_mut(width);
+            for (row, output_row) in band.clone().zip(band_output) {
+                let window_row = row - window_start;
+                let dither_map = dither_map
+                    .get(window_row * width..window_row * width + width)
+                    .unwrap_or(&[]);
+                dither_row(
+                    rows.row_f(&mut temp_row, window_row),
+                    output_row,
+                    width as u32,
+                    dither_map,
+                    base_dithering_level,
+                    max_dither_error,
+                    &n,
+                    palette.as_slice(),
+                    0,
+                    &[],
+                    generate_dither_map,
+                    &mut diffusion,
+                    row & 1 == 0,
+                );
+            }
+        }
+        sink.write_rows(band.start, &output[band_offset..band_offset + band_len])?;
+    }
+
+    if !dithering {
+        remapped.palette_error = Some(remapping_error / (width * height) as f64);
+    }
+    Ok(remapped)
+}
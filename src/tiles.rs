@@ -0,0 +1,334 @@
+//! Quantization for tiled graphics, where every tile can use only one of a few small sub-palettes (like console tilemaps).
+//!
+//! Tiles are clustered by how well they fit each sub-palette, and sub-palettes are made from histograms of their tiles,
+//! alternating the two steps like k-means.
+use crate::attr::Attributes;
+use crate::error::Error;
+use crate::hist::{Histogram, HistogramEntry};
+use crate::image::Image;
+use crate::pal::{f_pixel, gamma_lut, unpremultiply, PalIndexRemap, RGBA};
+use crate::quant::QuantizationResult;
+use crate::rows::temp_buf;
+use crate::OrdFloat;
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Rounds of reassigning tiles and rebuilding sub-palettes
+const ITERATIONS: usize = 6;
+
+/// Result of [`Attributes::quantize_tiles`]: sub-palettes, and which of them each tile uses
+pub struct TileQuantizationResult {
+    palettes: Vec<QuantizationResult>,
+    tile_palettes: Vec<usize>,
+    tile_width: usize,
+    tile_height: usize,
+    colors_per_palette: usize,
+}
+
+/// Pixels of the image split into tiles
+struct Tiles {
+    width: usize,
+    height: usize,
+    tile_width: usize,
+    tile_height: usize,
+    columns: usize,
+    rows: usize,
+    gamma: f64,
+    pixels: Vec<RGBA>,
+    f_pixels: Vec<f_pixel>,
+}
+
+impl Tiles {
+    fn new(image: &Image<'_>, tile_width: usize, tile_height: usize) -> Result<Self, Error> {
+        let width = image.width();
+        let height = image.height();
+        let gamma = image.gamma().unwrap_or(0.);
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(width * height)?;
+        let rows = image.px.rgba_rows_iter()?;
+        let mut temp_row = temp_buf(width)?;
+        for row in 0..height {
+            pixels.extend(rows.row_rgba(&mut temp_row, row).iter().map(|&px| {
+                if image.px.premultiplied {
+                    unpremultiply(px)
+                } else {
+                    px
+                }
+            }));
+        }
+        let lut = gamma_lut(if gamma > 0. { gamma } else { 0.45455 });
+        let mut f_pixels = Vec::new();
+        f_pixels.try_reserve_exact(pixels.len())?;
+        f_pixels.extend(pixels.iter().map(|&px| f_pixel::from_rgba(&lut, px)));
+        Ok(Self {
+            width,
+            height,
+            tile_width,
+            tile_height,
+            columns: (width + tile_width - 1) / tile_width,
+            rows: (height + tile_height - 1) / tile_height,
+            gamma,
+            pixels,
+            f_pixels,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.columns * self.rows
+    }
+
+    /// Offsets of pixels of each row of the tile. Tiles at the right and bottom edges may be smaller.
+    fn tile_rows(&self, tile: usize) -> impl Iterator<Item = core::ops::Range<usize>> + '_ {
+        let x = (tile % self.columns) * self.tile_width;
+        let y = (tile / self.columns) * self.tile_height;
+        let w = self.tile_width.min(self.width - x);
+        (y..(y + self.tile_height).min(self.height))
+            .map(move |y| y * self.width + x..y * self.width + x + w)
+    }
+
+    fn tile_f_pixels(&self, tile: usize) -> impl Iterator<Item = &f_pixel> + '_ {
+        self.tile_rows(tile).flat_map(|r| &self.f_pixels[r])
+    }
+
+    fn tile_image(&self, attr: &Attributes, tile: usize) -> Result<Image<'static>, Error> {
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(self.tile_width * self.tile_height)?;
+        let mut rows = 0;
+        for r in self.tile_rows(tile) {
+            pixels.extend_from_slice(&self.pixels[r]);
+            rows += 1;
+        }
+        let width = pixels.len() / rows;
+        Image::new(attr, pixels, width, rows, self.gamma)
+    }
+
+    fn mean(&self, tile: usize) -> f_pixel {
+        let mut sum = f_pixel::default().0;
+        let mut count = 0;
+        for px in self.tile_f_pixels(tile) {
+            sum += px.0;
+            count += 1;
+        }
+        f_pixel(sum * (1. / count.max(1) as f32))
+    }
+
+    /// Sum of differences between pixels of the tile and the closest colors of the palette
+    fn error(&self, tile: usize, palette: &[f_pixel]) -> f32 {
+        self.tile_f_pixels(tile)
+            .map(|px| palette.iter().map(|c| c.diff(px)).fold(f32::MAX, f32::min))
+            .sum()
+    }
+}
+
+impl Attributes {
+    /// Quantizes tiled graphics, where each tile of `tile_width`×`tile_height` pixels must use only one of `num_palettes` sub-palettes
+    /// of up to `colors_per_palette` colors (e.g. 8×8 tiles with 8 palettes of 16 colors).
+    ///
+    /// Fixed colors of the image are in every sub-palette, so [`Image::add_fixed_color_at`] can reserve e.g. index 0 for transparency.
+    /// Other settings like speed and quality apply to each sub-palette. Max colors setting is ignored.
+    pub fn quantize_tiles(
+        &self,
+        image: &mut Image<'_>,
+        tile_width: usize,
+        tile_height: usize,
+        num_palettes: usize,
+        colors_per_palette: u32,
+    ) -> Result<TileQuantizationResult, Error> {
+        if tile_width == 0 || tile_height == 0 || num_palettes == 0 {
+            return Err(Error::ValueOutOfRange);
+        }
+        let mut attr = self.clone();
+        attr.set_max_colors(colors_per_palette)?;
+        let tiles = Tiles::new(image, tile_width, tile_height)?;
+        let num_palettes = num_palettes.min(tiles.len());
+
+        let mut tile_palettes = initial_clusters(&tiles, num_palettes)?;
+        let mut errors = Vec::new();
+        errors.try_reserve_exact(tiles.len())?;
+        errors.resize(tiles.len(), 0f32);
+        let mut palettes = Vec::new();
+        for iteration in 0.. {
+            if self.progress(iteration as f32 * 100. / ITERATIONS as f32) {
+                return Err(Error::Aborted);
+            }
+            palettes.clear();
+            for p in 0..num_palettes {
+                // sub-palette without tiles gets the tile that fits worst elsewhere
+                if !tile_palettes.contains(&p) {
+                    if let Some((worst, _)) = errors
+                        .iter()
+                        .enumerate()
+                        .max_by_key(|&(_, &e)| OrdFloat::new(e))
+                    {
+                        tile_palettes[worst] = p;
+                        errors[worst] = 0.;
+                    }
+                }
+                palettes.push(sub_palette(&attr, image, &tiles, &tile_palettes, p)?);
+            }
+
+            let colors: Vec<Vec<f_pixel>> = palettes
+                .iter_mut()
+                .map(|res| {
+                    // rounds the colors to their final values
+                    let _ = res.palette();
+                    res.palette.as_slice().to_vec()
+                })
+                .collect();
+            let mut changed = false;
+            for (tile, (assigned, error)) in tile_palettes.iter_mut().zip(&mut errors).enumerate() {
+                let (best, best_error) = colors
+                    .iter()
+                    .map(|c| tiles.error(tile, c))
+                    .enumerate()
+                    .min_by_key(|&(_, e)| OrdFloat::new(e))
+                    .unwrap_or((0, 0.));
+                changed |= *assigned != best;
+                *assigned = best;
+                *error = best_error;
+            }
+            if !changed || iteration + 1 >= ITERATIONS {
+                break;
+            }
+        }
+
+        Ok(TileQuantizationResult {
+            palettes,
+            tile_palettes,
+            tile_width,
+            tile_height,
+            colors_per_palette: colors_per_palette as usize,
+        })
+    }
+}
+
+/// Farthest-first seeds by average color of tiles, and tiles assigned to the closest seed
+fn initial_clusters(tiles: &Tiles, num_palettes: usize) -> Result<Vec<usize>, Error> {
+    let mut means = Vec::new();
+    means.try_reserve_exact(tiles.len())?;
+    means.extend((0..tiles.len()).map(|t| tiles.mean(t)));
+
+    let mut seeds = Vec::with_capacity(num_palettes);
+    let mut distances: Vec<f32> = means.iter().map(|m| m.diff(&means[0])).collect();
+    seeds.push(0);
+    while seeds.len() < num_palettes {
+        let Some((farthest, _)) = distances
+            .iter()
+            .enumerate()
+            .max_by_key(|&(_, &d)| OrdFloat::new(d))
+        else {
+            break;
+        };
+        seeds.push(farthest);
+        for (d, m) in distances.iter_mut().zip(&means) {
+            *d = d.min(m.diff(&means[farthest]));
+        }
+    }
+    Ok(means
+        .iter()
+        .map(|m| {
+            seeds
+                .iter()
+                .enumerate()
+                .min_by_key(|&(_, &s)| OrdFloat::new(m.diff(&means[s])))
+                .map_or(0, |(p, _)| p)
+        })
+        .collect())
+}
+
+/// Palette made with mediancut from a histogram of all tiles assigned to the sub-palette `p`
+fn sub_palette(
+    attr: &Attributes,
+    image: &Image<'_>,
+    tiles: &Tiles,
+    tile_palettes: &[usize],
+    p: usize,
+) -> Result<QuantizationResult, Error> {
+    let mut hist = Histogram::new(attr);
+    for &(color, pinned) in &image.fixed_colors {
+        match pinned {
+            Some(index) => hist.add_fixed_color_at(color, tiles.gamma, index.into())?,
+            None => hist.add_fixed_color(color, tiles.gamma)?,
+        }
+    }
+    let mut entries = Vec::new();
+    for (tile, _) in tile_palettes.iter().enumerate().filter(|&(_, &tp)| tp == p) {
+        entries.clear();
+        entries.try_reserve(tiles.tile_width * tiles.tile_height)?;
+        for r in tiles.tile_rows(tile) {
+            entries.extend(
+                tiles.pixels[r]
+                    .iter()
+                    .map(|&color| HistogramEntry { color, count: 1 }),
+            );
+        }
+        hist.add_colors(&entries, tiles.gamma)?;
+    }
+    hist.quantize(attr)
+}
+
+impl TileQuantizationResult {
+    /// Set to 1.0 to get nice smooth image, for all sub-palettes. Dithering doesn't cross edges of tiles.
+    pub fn set_dithering_level(&mut self, value: f32) -> Result<(), Error> {
+        for res in &mut self.palettes {
+            res.set_dithering_level(value)?;
+        }
+        Ok(())
+    }
+
+    /// Colors of each sub-palette. They may have fewer colors than requested.
+    #[must_use]
+    pub fn palettes(&mut self) -> Vec<Vec<RGBA>> {
+        self.palettes
+            .iter_mut()
+            .map(|res| res.palette_vec())
+            .collect()
+    }
+
+    /// All sub-palettes concatenated, each padded with transparent black to `colors_per_palette` entries,
+    /// so that index in the global palette is `sub-palette * colors_per_palette + index in the sub-palette`.
+    #[must_use]
+    pub fn global_palette(&mut self) -> Vec<RGBA> {
+        let colors_per_palette = self.colors_per_palette;
+        let mut out = Vec::with_capacity(self.palettes.len() * colors_per_palette);
+        for res in &mut self.palettes {
+            let pal = res.palette();
+            out.extend_from_slice(pal);
+            out.resize(
+                out.len() + colors_per_palette - pal.len(),
+                RGBA::new(0, 0, 0, 0),
+            );
+        }
+        out
+    }
+
+    /// Index of the sub-palette used by each tile, row by row
+    #[must_use]
+    pub fn tile_palettes(&self) -> &[usize] {
+        &self.tile_palettes
+    }
+
+    /// Remaps each tile of the image with its sub-palette.
+    ///
+    /// Returns the 1-byte-per-pixel bitmap, where indices are into the tile's sub-palette.
+    pub fn remapped(&mut self, image: &mut Image<'_>) -> Result<Vec<PalIndexRemap>, Error> {
+        let tiles = Tiles::new(image, self.tile_width, self.tile_height)?;
+        if tiles.len() != self.tile_palettes.len() {
+            return Err(Error::ValueOutOfRange);
+        }
+        let attr = Attributes::new();
+        let mut out = Vec::new();
+        out.try_reserve_exact(tiles.width * tiles.height)?;
+        out.resize(tiles.width * tiles.height, 0);
+        for (tile, &p) in self.tile_palettes.iter().enumerate() {
+            let mut tile_image = tiles.tile_image(&attr, tile)?;
+            let (_, indices) = self.palettes[p].remapped(&mut tile_image)?;
+            let mut indices = indices.chunks_exact(tile_image.width());
+            for (r, row) in tiles.tile_rows(tile).zip(&mut indices) {
+                out[r].copy_from_slice(row);
+            }
+        }
+        Ok(out)
+    }
+}
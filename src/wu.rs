@@ -0,0 +1,390 @@
+//! Xiaolin Wu's quantizer: splits boxes of a grid of color moments where it maximizes variance between the halves.
+//!
+//! It's an alternative to mediancut for the initial palette. It works in the same premultiplied color space as the rest
+//! of the library, but on a coarse grid instead of individual histogram entries, so its speed hardly depends on the number of colors.
+use crate::hist::{HistItem, HistogramInternal};
+use crate::nearest::Nearest;
+use crate::pal::{f_pixel, PalF, PalLen, PalPop, ARGBF};
+use crate::{Error, OrdFloat};
+use core::ops::{Add, Sub};
+
+#[cfg(all(not(feature = "std"), feature = "no_std"))]
+use crate::no_std_compat::*;
+
+/// Weighted sums of colors in a part of the grid
+#[derive(Copy, Clone, Default)]
+struct Moments {
+    weight: f64,
+    /// ARGB
+    color: [f64; 4],
+    /// Sum of squared lengths of colors
+    squares: f64,
+}
+
+impl Moments {
+    fn new(item: &HistItem) -> Self {
+        let w = f64::from(item.adjusted_weight);
+        let c = channels(item.color);
+        Self {
+            weight: w,
+            color: c.map(|c| c * w),
+            squares: w * c.iter().map(|c| c * c).sum::<f64>(),
+        }
+    }
+
+    /// `|Σwc|²/Σw`, the part of the sum of squares explained by the average color
+    fn explained(&self) -> f64 {
+        if self.weight <= 0. {
+            return 0.;
+        }
+        self.color.iter().map(|c| c * c).sum::<f64>() / self.weight
+    }
+
+    fn variance(&self) -> f64 {
+        (self.squares - self.explained()).max(0.)
+    }
+}
+
+impl Add for Moments {
+    type Output = Self;
+
+    fn add(mut self, o: Self) -> Self {
+        self.weight += o.weight;
+        self.color
+            .iter_mut()
+            .zip(o.color)
+            .for_each(|(a, b)| *a += b);
+        self.squares += o.squares;
+        self
+    }
+}
+
+impl Sub for Moments {
+    type Output = Self;
+
+    fn sub(mut self, o: Self) -> Self {
+        self.weight -= o.weight;
+        self.color
+            .iter_mut()
+            .zip(o.color)
+            .for_each(|(a, b)| *a -= b);
+        self.squares -= o.squares;
+        self
+    }
+}
+
+#[inline(always)]
+fn channels(c: f_pixel) -> [f64; 4] {
+    [c.a, c.r, c.g, c.b].map(f64::from)
+}
+
+/// Range of cells `lo` (exclusive) to `hi` (inclusive) in each dimension
+#[derive(Copy, Clone)]
+struct WuBox {
+    lo: [usize; 4],
+    hi: [usize; 4],
+    variance: f64,
+}
+
+/// Cumulative moments, so that moments of any box can be computed from its corners
+struct Grid {
+    /// Number of cells in each dimension, including a leading zero cell
+    size: [usize; 4],
+    stride: [usize; 4],
+    cumulative: Vec<Moments>,
+    min: [f32; 4],
+    scale: [f32; 4],
+}
+
+impl Grid {
+    fn new(items: &[HistItem]) -> Result<Self, Error> {
+        let mut min = [f32::MAX; 4];
+        let mut max = [f32::MIN; 4];
+        for item in items {
+            let c: [f32; 4] = [item.color.a, item.color.r, item.color.g, item.color.b];
+            for ((min, max), c) in min.iter_mut().zip(&mut max).zip(c) {
+                *min = min.min(c);
+                *max = max.max(c);
+            }
+        }
+        // alpha is usually uniform, and then colors can use finer grid in the same memory
+        let alpha_cells = if max[0] > min[0] { 8 } else { 1 };
+        let color_cells = if alpha_cells > 1 { 16 } else { 32 };
+        let cells = [alpha_cells, color_cells, color_cells, color_cells];
+        let size = cells.map(|c| c + 1);
+        let stride = [size[1] * size[2] * size[3], size[2] * size[3], size[3], 1];
+        let scale = [0, 1, 2, 3].map(|i| {
+            let range = max[i] - min[i];
+            if range > 0. {
+                cells[i] as f32 / range
+            } else {
+                0.
+            }
+        });
+
+        let mut cumulative = Vec::new();
+        cumulative.try_reserve_exact(size.iter().product())?;
+        cumulative.resize(size.iter().product(), Moments::default());
+        let mut grid = Self {
+            size,
+            stride,
+            cumulative,
+            min,
+            scale,
+        };
+        for item in items {
+            let idx = grid.cell_index(item.color);
+            grid.cumulative[idx] = grid.cumulative[idx] + Moments::new(item);
+        }
+        grid.accumulate();
+        Ok(grid)
+    }
+
+    #[inline]
+    fn cell_index(&self, color: f_pixel) -> usize {
+        let c = [color.a, color.r, color.g, color.b];
+        (0..4)
+            .map(|i| {
+                let cell = (((c[i] - self.min[i]) * self.scale[i]) as usize).min(self.size[i] - 2);
+                (cell + 1) * self.stride[i]
+            })
+            .sum()
+    }
+
+    /// Turns moments of cells into sums of moments of all cells before them
+    fn accumulate(&mut self) {
+        for dim in 0..4 {
+            let stride = self.stride[dim];
+            for idx in 0..self.cumulative.len() {
+                if (idx / stride) % self.size[dim] > 0 {
+                    self.cumulative[idx] = self.cumulative[idx] + self.cumulative[idx - stride];
+                }
+            }
+        }
+    }
+
+    /// Inclusion-exclusion over the 16 corners of the box
+    fn volume(&self, lo: &[usize; 4], hi: &[usize; 4]) -> Moments {
+        let mut plus = Moments::default();
+        let mut minus = Moments::default();
+        for corner in 0..16u32 {
+            let idx: usize = (0..4)
+                .map(|d| if corner & (1 << d) != 0 { lo[d] } else { hi[d] } * self.stride[d])
+                .sum();
+            if corner.count_ones() % 2 == 0 {
+                plus = plus + self.cumulative[idx];
+            } else {
+                minus = minus + self.cumulative[idx];
+            }
+        }
+        plus - minus
+    }
+
+    fn new_box(&self, lo: [usize; 4], hi: [usize; 4]) -> WuBox {
+        let splittable = (0..4).any(|d| hi[d] - lo[d] > 1);
+        WuBox {
+            lo,
+            hi,
+            variance: if splittable {
+                self.volume(&lo, &hi).variance()
+            } else {
+                0.
+            },
+        }
+    }
+
+    /// Finds the cut maximizing variance between halves. `None` if the box can't be split.
+    fn cut(&self, b: &WuBox) -> Option<[WuBox; 2]> {
+        let whole = self.volume(&b.lo, &b.hi);
+        let mut best = None;
+        let mut best_score = whole.explained();
+        for dim in 0..4 {
+            for pos in b.lo[dim] + 1..b.hi[dim] {
+                let mut hi = b.hi;
+                hi[dim] = pos;
+                let first = self.volume(&b.lo, &hi);
+                let second = whole - first;
+                if first.weight <= 0. || second.weight <= 0. {
+                    continue;
+                }
+                let score = first.explained() + second.explained();
+                if score > best_score {
+                    best_score = score;
+                    best = Some((dim, pos));
+                }
+            }
+        }
+        let (dim, pos) = best?;
+        let mut first_hi = b.hi;
+        first_hi[dim] = pos;
+        let mut second_lo = b.lo;
+        second_lo[dim] = pos;
+        Some([self.new_box(b.lo, first_hi), self.new_box(second_lo, b.hi)])
+    }
+}
+
+/// Makes a palette of up to `target_colors`, or fewer if their total variance is under `target_mse`.
+///
+/// Like mediancut, uses `adjusted_weight` and sets palette indices in the items' `tmp`.
+#[inline(never)]
+pub(crate) fn wu(
+    hist: &mut HistogramInternal,
+    target_colors: PalLen,
+    target_mse: f64,
+) -> Result<PalF, Error> {
+    if hist.items.is_empty() {
+        return Ok(PalF::new());
+    }
+    let grid = Grid::new(&hist.items)?;
+    let whole = grid.new_box([0; 4], grid.size.map(|s| s - 1));
+    let total_weight = grid.volume(&whole.lo, &whole.hi).weight;
+
+    let mut boxes = Vec::new();
+    boxes.try_reserve_exact(target_colors as usize)?;
+    boxes.push(whole);
+    let mut total_variance = whole.variance;
+    while boxes.len() < target_colors as usize && total_variance > target_mse * total_weight {
+        let Some((i, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.variance > 0.)
+            .max_by_key(|(_, b)| OrdFloat::new64(b.variance))
+        else {
+            break;
+        };
+        match grid.cut(&boxes[i]) {
+            Some([first, second]) => {
+                total_variance += first.variance + second.variance - boxes[i].variance;
+                boxes[i] = first;
+                boxes.push(second);
+            }
+            None => boxes[i].variance = 0.,
+        }
+    }
+
+    // which box each cell belongs to
+    let mut tags = Vec::new();
+    tags.try_reserve_exact(grid.cumulative.len())?;
+    tags.resize(grid.cumulative.len(), 0u32);
+    for (i, b) in boxes.iter().enumerate() {
+        for a in b.lo[0] + 1..=b.hi[0] {
+            for r in b.lo[1] + 1..=b.hi[1] {
+                for g in b.lo[2] + 1..=b.hi[2] {
+                    let row = a * grid.stride[0] + r * grid.stride[1] + g * grid.stride[2];
+                    tags[row + b.lo[3] + 1..=row + b.hi[3]].fill(i as u32);
+                }
+            }
+        }
+    }
+
+    let mut sums = Vec::new();
+    sums.try_reserve_exact(boxes.len())?;
+    sums.resize(boxes.len(), (ARGBF::default(), 0f32, 0f64));
+    for item in hist.items.iter_mut() {
+        let b = tags[grid.cell_index(item.color)];
+        item.tmp = b;
+        let (color, weight, pop) = &mut sums[b as usize];
+        *color += item.color.0 * item.adjusted_weight;
+        *weight += item.adjusted_weight;
+        *pop += f64::from(item.perceptual_weight);
+    }
+
+    // boxes can be empty only if weights were zero, and then they need to be skipped without changing indices of the others
+    let mut palette = PalF::new();
+    let mut remap = Vec::new();
+    remap.try_reserve_exact(sums.len())?;
+    for &(color, weight, pop) in &sums {
+        if weight > 0. {
+            remap.push(Some(palette.len() as u32));
+            palette.push(f_pixel(color * (1. / weight)), PalPop::new(pop as f32));
+        } else {
+            remap.push(None);
+        }
+    }
+    if palette.len() != sums.len() && palette.len() > 0 {
+        // items of the skipped boxes go to the closest of the remaining colors
+        let n = Nearest::new(&palette)?;
+        for item in hist.items.iter_mut() {
+            item.tmp = match remap[item.tmp as usize] {
+                Some(index) => index,
+                None => n.search(&item.color, 0).0.into(),
+            };
+        }
+    }
+    Ok(palette)
+}
+
+#[test]
+fn cut_splits_axis_with_most_variance() {
+    use crate::RGBA;
+    // red spans the whole range, green and blue only a few levels
+    let colors: Vec<_> = (0..64u8)
+        .map(|i| (RGBA::new(i * 4, 100 + i % 4, 50 + i % 2, 255), 1))
+        .collect();
+    let hist = HistogramInternal::from_colors(&colors);
+    let grid = Grid::new(&hist.items).unwrap();
+    let whole = grid.new_box([0; 4], grid.size.map(|s| s - 1));
+    let [first, second] = grid.cut(&whole).unwrap();
+    assert!(first.hi[1] > whole.lo[1] && first.hi[1] < whole.hi[1]);
+    assert_eq!(second.lo[1], first.hi[1]);
+    for dim in [0, 2, 3] {
+        assert_eq!(
+            (first.lo[dim], first.hi[dim]),
+            (whole.lo[dim], whole.hi[dim])
+        );
+        assert_eq!(
+            (second.lo[dim], second.hi[dim]),
+            (whole.lo[dim], whole.hi[dim])
+        );
+    }
+    assert!(first.variance < whole.variance && second.variance < whole.variance);
+}
+
+#[test]
+fn stops_when_variance_is_explained() {
+    use crate::RGBA;
+    let centers = [
+        RGBA::new(20, 20, 200, 255),
+        RGBA::new(200, 30, 30, 255),
+        RGBA::new(40, 220, 60, 255),
+    ];
+    let colors: Vec<_> = centers
+        .iter()
+        .flat_map(|c| (0..4u8).map(move |i| (RGBA::new(c.r + i, c.g, c.b + i % 2, 255), 10)))
+        .collect();
+    let mut hist = HistogramInternal::from_colors(&colors);
+    // clusters are tight, so 3 colors are enough for this error, even though 16 are allowed
+    let palette = wu(&mut hist, 16, 1e-4).unwrap();
+    assert_eq!(palette.len(), 3);
+
+    // every color is assigned to the palette entry of its cluster
+    for item in hist.items.iter() {
+        let entry = palette.as_slice()[item.tmp as usize];
+        let closest = palette
+            .as_slice()
+            .iter()
+            .map(|c| c.diff(&item.color))
+            .fold(f32::MAX, f32::min);
+        assert_eq!(entry.diff(&item.color), closest);
+        assert!(closest < 1e-3, "{closest}");
+    }
+}
+
+#[test]
+#[cfg(feature = "large_palettes")]
+fn more_than_256_boxes() {
+    use crate::RGBA;
+    let colors: Vec<_> = (0..32 * 32 * 32u32)
+        .map(|i| {
+            let c = |shift: u32| ((i >> shift) % 32 * 8) as u8;
+            (RGBA::new(c(0), c(5), c(10), 255), 1)
+        })
+        .collect();
+    let mut hist = HistogramInternal::from_colors(&colors);
+    let palette = wu(&mut hist, 1000, 0.).unwrap();
+    assert!(palette.len() > 256, "{}", palette.len());
+    assert!(hist
+        .items
+        .iter()
+        .all(|item| (item.tmp as usize) < palette.len()));
+}